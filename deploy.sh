@@ -56,12 +56,14 @@ dfx deploy --specified-id mm444-5iaaa-aaaar-qaabq-cai ckbtc_index --argument '(o
 
 dfx deploy --specified-id 6fwhw-fyaaa-aaaap-qb7ua-cai schnorr_canister
 
-dfx deploy etcher_backend --specified-id dyb47-nqaaa-aaaag-qjvba-cai --argument '(record{
-        network = variant { regtest };
-        ckbtc_ledger = principal "mc6ru-gyaaa-aaaar-qaaaq-cai";
-        ckbtc_minter = principal "ml52i-qqaaa-aaaar-qaaba-cai";
-        schnorr_canister = principal "6fwhw-fyaaa-aaaap-qb7ua-cai";
-        timer_for_reveal_txn = 1;
+dfx deploy etcher_backend --specified-id dyb47-nqaaa-aaaag-qjvba-cai --argument '(variant{
+        Init = record{
+                network = variant { regtest };
+                ckbtc_ledger = principal "mc6ru-gyaaa-aaaar-qaaaq-cai";
+                ckbtc_minter = principal "ml52i-qqaaa-aaaar-qaaba-cai";
+                schnorr_canister = principal "6fwhw-fyaaa-aaaap-qb7ua-cai";
+                timer_for_reveal_txn = 1;
+}
 })'
 
 dfx deploy --specified-id kho2y-sqaaa-aaaag-qjuta-cai etcher_frontend
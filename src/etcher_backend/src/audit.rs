@@ -0,0 +1,253 @@
+use std::{borrow::Cow, cell::RefCell};
+
+use candid::{CandidType, Principal};
+use ic_stable_structures::{storable::Bound, Log, Storable};
+use serde::{Deserialize, Serialize};
+
+use crate::{get_audit_log_data_memory, get_audit_log_index_memory, Memory};
+
+pub const MAX_AUDIT_LOG_PAGE: u64 = 100;
+/// Entries looked at per query, whether they match the filters or not.
+pub const MAX_AUDIT_LOG_SCAN: u64 = 1_000;
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEventKind {
+    EtchingRequested,
+    TransactionSigned,
+    TransactionBroadcasted,
+    CkbtcTransferred,
+    BtcRetrieved,
+    ConfigChanged,
+    ChainReorganized,
+    Failure,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub enum AuditEvent {
+    EtchingRequested {
+        runes: Vec<String>,
+    },
+    TransactionSigned {
+        txid: String,
+    },
+    TransactionBroadcasted {
+        txid: String,
+    },
+    CkbtcTransferred {
+        block_index: u64,
+        amount: u64,
+    },
+    BtcRetrieved {
+        block_index: u64,
+        address: String,
+        amount: u64,
+    },
+    ConfigChanged {
+        change: String,
+    },
+    ChainReorganized {
+        fork_height: u32,
+        depth: u32,
+    },
+    Failure {
+        reason: String,
+    },
+}
+
+impl AuditEvent {
+    pub fn kind(&self) -> AuditEventKind {
+        match self {
+            Self::EtchingRequested { .. } => AuditEventKind::EtchingRequested,
+            Self::TransactionSigned { .. } => AuditEventKind::TransactionSigned,
+            Self::TransactionBroadcasted { .. } => AuditEventKind::TransactionBroadcasted,
+            Self::CkbtcTransferred { .. } => AuditEventKind::CkbtcTransferred,
+            Self::BtcRetrieved { .. } => AuditEventKind::BtcRetrieved,
+            Self::ConfigChanged { .. } => AuditEventKind::ConfigChanged,
+            Self::ChainReorganized { .. } => AuditEventKind::ChainReorganized,
+            Self::Failure { .. } => AuditEventKind::Failure,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub event: AuditEvent,
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        ciborium::de::from_reader(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static AUDIT_LOG: RefCell<Log<AuditEntry, Memory, Memory>> = RefCell::new(
+        Log::init(get_audit_log_index_memory(), get_audit_log_data_memory())
+            .expect("failed to initialize the audit log"),
+    );
+}
+
+/// Appends `event` on behalf of the current caller.
+pub fn record(event: AuditEvent) {
+    record_as(ic_cdk::caller(), event)
+}
+
+pub fn record_as(caller: Principal, event: AuditEvent) {
+    let entry = AuditEntry {
+        timestamp: ic_cdk::api::time(),
+        caller,
+        event,
+    };
+    AUDIT_LOG.with_borrow(|log| {
+        log.append(&entry)
+            .expect("failed to append to the audit log")
+    });
+}
+
+/// Records a [`AuditEvent::Failure`] of `operation` unless
+/// [`FailureAudit::completed`] is called. Like the
+/// [`FailureGuard`](crate::circuit_breaker::FailureGuard), it is dropped
+/// during the cleanup of a call that trapped after an await, so the record
+/// outlives the trap that rolls back the rest of the call. A trap before
+/// the first await leaves nothing behind to record.
+#[derive(Debug)]
+#[must_use]
+pub struct FailureAudit {
+    // captured up front, the caller isn't available during the cleanup
+    caller: Principal,
+    operation: &'static str,
+    completed: bool,
+}
+
+impl FailureAudit {
+    pub fn new(operation: &'static str) -> Self {
+        Self {
+            caller: ic_cdk::caller(),
+            operation,
+            completed: false,
+        }
+    }
+
+    pub fn completed(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for FailureAudit {
+    fn drop(&mut self) {
+        if !self.completed {
+            record_as(
+                self.caller,
+                AuditEvent::Failure {
+                    reason: format!("{} failed", self.operation),
+                },
+            );
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct AuditLogQuery {
+    pub start: Option<u64>,
+    pub limit: Option<u64>,
+    pub caller: Option<Principal>,
+    pub kind: Option<AuditEventKind>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct AuditLogPage {
+    pub entries: Vec<(u64, AuditEntry)>,
+    // index to pass as `start` to continue, `None` once the end is reached
+    pub next: Option<u64>,
+}
+
+/// The entries from `start` on that match the filters, up to `limit` of
+/// them. At most [`MAX_AUDIT_LOG_SCAN`] entries are looked at, so a page
+/// can come back short or even empty before the end of the log is reached.
+pub fn query(args: AuditLogQuery) -> AuditLogPage {
+    let limit = args
+        .limit
+        .unwrap_or(MAX_AUDIT_LOG_PAGE)
+        .min(MAX_AUDIT_LOG_PAGE) as usize;
+    AUDIT_LOG.with_borrow(|log| {
+        let mut entries = vec![];
+        let mut index = args.start.unwrap_or(0);
+        let scan_end = log.len().min(index.saturating_add(MAX_AUDIT_LOG_SCAN));
+        while index < scan_end && entries.len() < limit {
+            let entry = log.get(index).unwrap();
+            if args.caller.is_none_or(|caller| entry.caller == caller)
+                && args.kind.is_none_or(|kind| entry.event.kind() == kind)
+            {
+                entries.push((index, entry));
+            }
+            index += 1;
+        }
+        AuditLogPage {
+            entries,
+            next: (index < log.len()).then_some(index),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn append(event: AuditEvent) {
+        AUDIT_LOG.with_borrow(|log| {
+            log.append(&AuditEntry {
+                timestamp: 0,
+                caller: Principal::anonymous(),
+                event,
+            })
+            .unwrap()
+        });
+    }
+
+    #[test]
+    fn scans_a_bounded_number_of_entries_per_query() {
+        for _ in 0..2 * MAX_AUDIT_LOG_SCAN {
+            append(AuditEvent::TransactionSigned {
+                txid: String::new(),
+            });
+        }
+        append(AuditEvent::Failure {
+            reason: "sign_with_ecdsa failed".to_string(),
+        });
+        let failures = |start| {
+            query(AuditLogQuery {
+                start,
+                limit: None,
+                caller: None,
+                kind: Some(AuditEventKind::Failure),
+            })
+        };
+        let page = failures(None);
+        assert!(page.entries.is_empty());
+        assert_eq!(page.next, Some(MAX_AUDIT_LOG_SCAN));
+        let page = failures(page.next);
+        assert!(page.entries.is_empty());
+        let page = failures(page.next);
+        assert_eq!(page.entries[0].0, 2 * MAX_AUDIT_LOG_SCAN);
+        assert_eq!(page.next, None);
+
+        let page = query(AuditLogQuery {
+            start: None,
+            limit: Some(10),
+            caller: None,
+            kind: None,
+        });
+        assert_eq!(page.entries.len(), 10);
+        assert_eq!(page.next, Some(10));
+    }
+}
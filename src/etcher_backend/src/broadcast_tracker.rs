@@ -0,0 +1,447 @@
+//! Keeps every transaction the canister broadcasts until it confirms.
+//!
+//! A transaction counts as confirmed once one of its outputs shows up in
+//! `bitcoin_get_utxos`, or once a tracked transaction spending it does.
+//! Unconfirmed transactions are sent again every rebroadcast interval and
+//! flagged as possibly dropped once they get too old. Confirmed ones are
+//! kept until they are deeper than any reorg the chain tracker follows.
+
+use std::collections::BTreeMap;
+
+use bitcoin::{consensus, hashes::Hash, Address, Transaction, TxOut};
+use candid::CandidType;
+use hex::ToHex;
+use ic_cdk::api::management_canister::bitcoin::Utxo;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    btc_api::{get_network, BitcoinApi},
+    chain_tracker::{self, KEPT_HEADERS},
+    storage::TRACKED_TRANSACTIONS,
+    STATE,
+};
+
+/// How long possibly dropped transactions are still checked for having
+/// confirmed after all.
+pub const DROPPED_CHECKS_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BroadcastStatus {
+    Pending,
+    Confirmed {
+        height: u32,
+    },
+    /// Still unconfirmed after the configured age, it isn't sent again.
+    PossiblyDropped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackedTransaction {
+    /// Consensus encoded transaction, as it was broadcast.
+    pub raw_tx: Vec<u8>,
+    // the outputs it spends, to check it again before every rebroadcast
+    pub prevouts: Vec<TxOut>,
+    pub first_broadcast_at: u64,
+    pub last_broadcast_at: u64,
+    pub broadcasts: u32,
+    pub status: BroadcastStatus,
+}
+
+impl TrackedTransaction {
+    pub fn transaction(&self) -> Transaction {
+        consensus::deserialize(&self.raw_tx).unwrap()
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct BroadcastInfo {
+    pub txid: String,
+    pub status: BroadcastStatus,
+    pub broadcasts: u32,
+    pub first_broadcast_at: u64,
+    pub last_broadcast_at: u64,
+}
+
+fn info(txid: String, tracked: TrackedTransaction) -> BroadcastInfo {
+    BroadcastInfo {
+        txid,
+        status: tracked.status,
+        broadcasts: tracked.broadcasts,
+        first_broadcast_at: tracked.first_broadcast_at,
+        last_broadcast_at: tracked.last_broadcast_at,
+    }
+}
+
+/// Starts tracking `txn` after its first broadcast. Broadcasting a tracked
+/// transaction again doesn't change it.
+pub fn track(txn: &Transaction, prevouts: &[TxOut], now: u64) {
+    let txid: String = txn.txid().encode_hex();
+    TRACKED_TRANSACTIONS.with_borrow_mut(|tracked| {
+        if !tracked.contains_key(&txid) {
+            tracked.insert(
+                txid,
+                TrackedTransaction {
+                    raw_tx: consensus::serialize(txn),
+                    prevouts: prevouts.to_vec(),
+                    first_broadcast_at: now,
+                    last_broadcast_at: now,
+                    broadcasts: 1,
+                    status: BroadcastStatus::Pending,
+                },
+            );
+        }
+    });
+}
+
+pub fn get(txid: &str) -> Option<TrackedTransaction> {
+    TRACKED_TRANSACTIONS.with_borrow(|tracked| tracked.get(&txid.to_string()))
+}
+
+pub fn get_info(txid: String) -> Option<BroadcastInfo> {
+    get(&txid).map(|tracked| info(txid, tracked))
+}
+
+/// Every tracked transaction that hasn't confirmed yet.
+pub fn unconfirmed() -> Vec<BroadcastInfo> {
+    TRACKED_TRANSACTIONS.with_borrow(|tracked| {
+        tracked
+            .iter()
+            .filter(|(_, tracked)| !matches!(tracked.status, BroadcastStatus::Confirmed { .. }))
+            .map(|(txid, tracked)| info(txid, tracked))
+            .collect()
+    })
+}
+
+fn set_status(txid: &str, status: BroadcastStatus) {
+    TRACKED_TRANSACTIONS.with_borrow_mut(|tracked| {
+        if let Some(mut entry) = tracked.get(&txid.to_string()) {
+            entry.status = status;
+            tracked.insert(txid.to_string(), entry);
+        }
+    });
+}
+
+/// Marks `txid` and every unconfirmed tracked transaction it spends from as
+/// confirmed at `height`, as a transaction can only confirm after its
+/// parents. The parents might have confirmed earlier than that.
+fn confirm(txid: &str, height: u32) {
+    let mut confirmed = vec![txid.to_string()];
+    while let Some(txid) = confirmed.pop() {
+        let Some(tracked) = get(&txid) else {
+            continue;
+        };
+        if !matches!(tracked.status, BroadcastStatus::Confirmed { .. }) {
+            set_status(&txid, BroadcastStatus::Confirmed { height });
+        }
+        confirmed.extend(
+            tracked
+                .transaction()
+                .input
+                .iter()
+                .map(|input| input.previous_output.txid.encode_hex::<String>())
+                .filter(|parent| {
+                    get(parent).is_some_and(|parent| {
+                        !matches!(parent.status, BroadcastStatus::Confirmed { .. })
+                    })
+                }),
+        );
+    }
+}
+
+/// Sends the tracked transaction `txid` again, which is pending again
+/// afterwards even if it had confirmed before.
+pub async fn rebroadcast(
+    bitcoin_api: &impl BitcoinApi,
+    txid: &str,
+    now: u64,
+) -> Result<(), String> {
+    let tracked = get(txid).ok_or_else(|| format!("{} isn't tracked", txid))?;
+    bitcoin_api
+        .send_transaction(tracked.transaction(), &tracked.prevouts)
+        .await?;
+    TRACKED_TRANSACTIONS.with_borrow_mut(|tracked| {
+        let mut entry = tracked.get(&txid.to_string()).unwrap();
+        entry.last_broadcast_at = now;
+        entry.broadcasts += 1;
+        entry.status = BroadcastStatus::Pending;
+        tracked.insert(txid.to_string(), entry);
+    });
+    Ok(())
+}
+
+/// Marks the transactions confirmed above `fork_height` as pending again,
+/// as the blocks they confirmed in were reorged out.
+pub fn unconfirm_above(fork_height: u32) {
+    let reorged: Vec<String> = TRACKED_TRANSACTIONS.with_borrow(|tracked| {
+        tracked
+            .iter()
+            .filter(|(_, tracked)| {
+                matches!(tracked.status, BroadcastStatus::Confirmed { height } if height > fork_height)
+            })
+            .map(|(txid, _)| txid)
+            .collect()
+    });
+    for txid in reorged {
+        set_status(&txid, BroadcastStatus::Pending);
+    }
+}
+
+/// Stops tracking the transactions that confirmed more than
+/// [`KEPT_HEADERS`] blocks below `tip_height`, too deep to be reorged out.
+pub fn prune_confirmed(tip_height: u32) {
+    TRACKED_TRANSACTIONS.with_borrow_mut(|tracked| {
+        let settled: Vec<String> = tracked
+            .iter()
+            .filter(|(_, tracked)| {
+                matches!(tracked.status, BroadcastStatus::Confirmed { height } if height + KEPT_HEADERS <= tip_height)
+            })
+            .map(|(txid, _)| txid)
+            .collect();
+        for txid in settled {
+            tracked.remove(&txid);
+        }
+    });
+}
+
+/// Height of the block `txn` was confirmed in, if one of its outputs shows
+/// up among the utxos of its address. The utxos of every address are only
+/// fetched once per check and kept in `utxos_by_address`.
+async fn confirmation_height(
+    bitcoin_api: &impl BitcoinApi,
+    txn: &Transaction,
+    utxos_by_address: &mut BTreeMap<String, Vec<Utxo>>,
+) -> Option<u32> {
+    let txid = txn.txid().to_byte_array().to_vec();
+    let network = get_network();
+    for (vout, output) in txn.output.iter().enumerate() {
+        let Ok(address) = Address::from_script(&output.script_pubkey, network) else {
+            continue;
+        };
+        let address = address.to_string();
+        if !utxos_by_address.contains_key(&address) {
+            let utxos = bitcoin_api.get_utxos(address.clone()).await.utxos;
+            utxos_by_address.insert(address.clone(), utxos);
+        }
+        if let Some(utxo) = utxos_by_address[&address]
+            .iter()
+            .find(|utxo| utxo.outpoint.txid == txid && utxo.outpoint.vout == vout as u32)
+        {
+            return Some(utxo.height);
+        }
+    }
+    None
+}
+
+/// Checks every unconfirmed transaction: confirmed ones are marked as such,
+/// the ones older than the configured age are flagged as possibly dropped
+/// and the others are sent again once the rebroadcast interval has passed.
+/// Possibly dropped ones are left alone after [`DROPPED_CHECKS_NANOS`].
+pub async fn check_broadcasts(bitcoin_api: &impl BitcoinApi, now: u64) {
+    if let Some(tip_height) = chain_tracker::tip_height() {
+        prune_confirmed(tip_height);
+    }
+    let (rebroadcast_interval, possibly_dropped_after) = STATE.with_borrow(|state| {
+        (
+            state.rebroadcast_interval as u64 * 60 * 1_000_000_000,
+            state.possibly_dropped_after as u64 * 60 * 1_000_000_000,
+        )
+    });
+    let mut utxos_by_address = BTreeMap::new();
+    for BroadcastInfo { txid, .. } in unconfirmed() {
+        // might have been confirmed along with a child in the meantime
+        let Some(tracked) = get(&txid)
+            .filter(|tracked| !matches!(tracked.status, BroadcastStatus::Confirmed { .. }))
+        else {
+            continue;
+        };
+        if tracked.status == BroadcastStatus::PossiblyDropped
+            && now.saturating_sub(tracked.first_broadcast_at)
+                >= possibly_dropped_after + DROPPED_CHECKS_NANOS
+        {
+            continue;
+        }
+        if let Some(height) =
+            confirmation_height(bitcoin_api, &tracked.transaction(), &mut utxos_by_address).await
+        {
+            confirm(&txid, height);
+            continue;
+        }
+        if tracked.status != BroadcastStatus::Pending {
+            continue;
+        }
+        if now.saturating_sub(tracked.first_broadcast_at) >= possibly_dropped_after {
+            set_status(&txid, BroadcastStatus::PossiblyDropped);
+        } else if now.saturating_sub(tracked.last_broadcast_at) >= rebroadcast_interval {
+            // failures are retried after the next interval
+            let _ = rebroadcast(bitcoin_api, &txid, now).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        absolute::LockTime, Network, OutPoint, PublicKey, ScriptBuf, Sequence, TxIn, Txid, Witness,
+    };
+    use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+
+    use super::*;
+    use crate::{
+        config::{DEFAULT_POSSIBLY_DROPPED_AFTER, DEFAULT_REBROADCAST_INTERVAL},
+        mock::{block_on, MockBitcoinApi},
+    };
+
+    const TIP_HEIGHT: u32 = 100;
+    const MINUTE: u64 = 60 * 1_000_000_000;
+
+    fn setup() -> (MockBitcoinApi, Address) {
+        STATE.with_borrow_mut(|state| {
+            state.network = Some(BitcoinNetwork::Regtest);
+            state.rebroadcast_interval = DEFAULT_REBROADCAST_INTERVAL;
+            state.possibly_dropped_after = DEFAULT_POSSIBLY_DROPPED_AFTER;
+        });
+        let public_key = PublicKey::from_slice(&[2; 33]).unwrap();
+        let address = Address::p2wpkh(&public_key, Network::Regtest).unwrap();
+        let bitcoin_api = MockBitcoinApi::new(Network::Regtest, TIP_HEIGHT);
+        bitcoin_api.fund(&address.to_string(), 100_000);
+        (bitcoin_api, address)
+    }
+
+    /// Spends `outpoint` of `address` worth `value` back to `address`.
+    fn spend(outpoint: OutPoint, value: u64, address: &Address) -> (Transaction, Vec<TxOut>) {
+        let txn = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: value - 1_000,
+                script_pubkey: address.script_pubkey(),
+            }],
+        };
+        let prevouts = vec![TxOut {
+            value,
+            script_pubkey: address.script_pubkey(),
+        }];
+        (txn, prevouts)
+    }
+
+    fn broadcast(bitcoin_api: &MockBitcoinApi, address: &Address, now: u64) -> Transaction {
+        let utxo = block_on(bitcoin_api.get_utxos(address.to_string())).utxos[0].clone();
+        let outpoint = OutPoint::new(
+            Txid::from_slice(&utxo.outpoint.txid).unwrap(),
+            utxo.outpoint.vout,
+        );
+        let (txn, prevouts) = spend(outpoint, utxo.value, address);
+        block_on(bitcoin_api.send_transaction(txn.clone(), &prevouts)).unwrap();
+        track(&txn, &prevouts, now);
+        txn
+    }
+
+    fn status(txn: &Transaction) -> BroadcastStatus {
+        get(&txn.txid().encode_hex::<String>()).unwrap().status
+    }
+
+    #[test]
+    fn rebroadcasts_until_confirmed() {
+        let (bitcoin_api, address) = setup();
+        let txn = broadcast(&bitcoin_api, &address, 0);
+        let txid: String = txn.txid().encode_hex();
+
+        block_on(check_broadcasts(&bitcoin_api, 10 * MINUTE));
+        assert_eq!(get(&txid).unwrap().broadcasts, 1);
+
+        // nodes forget about it, so it is sent again
+        bitcoin_api.evict();
+        block_on(check_broadcasts(&bitcoin_api, 30 * MINUTE));
+        assert_eq!(bitcoin_api.mempool(), vec![txn.clone()]);
+        let tracked = get(&txid).unwrap();
+        assert_eq!(tracked.broadcasts, 2);
+        assert_eq!(tracked.last_broadcast_at, 30 * MINUTE);
+        assert_eq!(tracked.raw_tx, consensus::serialize(&txn));
+        assert_eq!(unconfirmed().len(), 1);
+
+        bitcoin_api.mine(1);
+        block_on(check_broadcasts(&bitcoin_api, 31 * MINUTE));
+        assert_eq!(
+            status(&txn),
+            BroadcastStatus::Confirmed {
+                height: TIP_HEIGHT + 1
+            }
+        );
+        assert!(unconfirmed().is_empty());
+    }
+
+    #[test]
+    fn flags_old_transactions_and_confirms_their_parents() {
+        let (bitcoin_api, address) = setup();
+        let parent = broadcast(&bitcoin_api, &address, 0);
+        bitcoin_api.mine(1);
+        // the child spends the only output of the parent, which can't be
+        // seen confirming on its own anymore
+        let child = broadcast(&bitcoin_api, &address, 0);
+        assert_eq!(child.input[0].previous_output.txid, parent.txid());
+
+        let dropped_after = DEFAULT_POSSIBLY_DROPPED_AFTER as u64 * MINUTE;
+        block_on(check_broadcasts(&bitcoin_api, dropped_after));
+        assert_eq!(status(&parent), BroadcastStatus::PossiblyDropped);
+        assert_eq!(status(&child), BroadcastStatus::PossiblyDropped);
+
+        // flagged transactions aren't sent again
+        block_on(check_broadcasts(&bitcoin_api, dropped_after + 60 * MINUTE));
+        assert_eq!(
+            get(&child.txid().encode_hex::<String>())
+                .unwrap()
+                .broadcasts,
+            1
+        );
+
+        bitcoin_api.mine(1);
+        block_on(check_broadcasts(&bitcoin_api, dropped_after + 90 * MINUTE));
+        let confirmed = BroadcastStatus::Confirmed {
+            height: TIP_HEIGHT + 2,
+        };
+        assert_eq!(status(&child), confirmed);
+        assert_eq!(status(&parent), confirmed);
+    }
+
+    #[test]
+    fn looks_up_addresses_once_and_forgets_settled_transactions() {
+        let (bitcoin_api, address) = setup();
+        bitcoin_api.fund(&address.to_string(), 100_000);
+        let first = broadcast(&bitcoin_api, &address, 0);
+        let second = broadcast(&bitcoin_api, &address, 0);
+        let requests = bitcoin_api.utxo_requests();
+        block_on(check_broadcasts(&bitcoin_api, MINUTE));
+        // both pay to the same address
+        assert_eq!(bitcoin_api.utxo_requests(), requests + 1);
+
+        bitcoin_api.mine(1);
+        block_on(check_broadcasts(&bitcoin_api, 2 * MINUTE));
+        let height = TIP_HEIGHT + 1;
+        assert_eq!(status(&second), BroadcastStatus::Confirmed { height });
+        prune_confirmed(height + KEPT_HEADERS - 1);
+        assert_eq!(status(&first), BroadcastStatus::Confirmed { height });
+        prune_confirmed(height + KEPT_HEADERS);
+        assert!(get(&first.txid().encode_hex::<String>()).is_none());
+        assert!(get(&second.txid().encode_hex::<String>()).is_none());
+
+        let dropped = broadcast(&bitcoin_api, &address, 0);
+        let dropped_after = DEFAULT_POSSIBLY_DROPPED_AFTER as u64 * MINUTE;
+        block_on(check_broadcasts(&bitcoin_api, dropped_after));
+        assert_eq!(status(&dropped), BroadcastStatus::PossiblyDropped);
+        // it isn't looked for anymore once it is that old
+        bitcoin_api.mine(1);
+        let requests = bitcoin_api.utxo_requests();
+        block_on(check_broadcasts(
+            &bitcoin_api,
+            dropped_after + DROPPED_CHECKS_NANOS,
+        ));
+        assert_eq!(bitcoin_api.utxo_requests(), requests);
+        assert_eq!(status(&dropped), BroadcastStatus::PossiblyDropped);
+    }
+}
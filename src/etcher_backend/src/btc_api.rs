@@ -1,27 +1,29 @@
 use std::str::FromStr;
 
 use crate::{
-    ecdsa_api::ecdsa_sign, schnorr_api, tags::Tag, utils::sec1_to_der, EtchingArgs, STATE,
+    audit::{self, AuditEvent},
+    broadcast_tracker,
+    circuit_breaker::FailureGuard,
+    ecdsa_api::EcdsaSigner,
+    metrics,
+    schnorr_api::SchnorrSigner,
+    utxo_lock, EtchingArgs, QueuedRevealTxn, STATE,
 };
 use bitcoin::{
-    absolute::LockTime,
-    consensus,
-    hashes::{sha256, Hash},
-    opcodes,
-    script::{Builder, PushBytes},
-    secp256k1::{
-        constants::SCHNORR_SIGNATURE_SIZE, schnorr, Message, PublicKey, Secp256k1, XOnlyPublicKey,
-    },
-    sighash::{EcdsaSighashType, Prevouts, SighashCache, TapSighashType},
-    taproot::{ControlBlock, LeafVersion, Signature, TapLeafHash, TaprootBuilder},
-    Address, Amount, FeeRate, Network, OutPoint, Script, ScriptBuf, Sequence, Transaction, TxIn,
-    TxOut, Txid, Witness,
+    block::Header, consensus, hashes::Hash, psbt::PartiallySignedTransaction, Address, BlockHash,
+    Network, OutPoint, Transaction, TxOut, Txid,
+};
+use candid::{CandidType, Deserialize, Principal};
+use etcher_core::{
+    build_commit_transaction, build_reveal_transaction, check_standardness,
+    finalize_commit_transaction, finalize_reveal_transaction, fund_commit_transaction,
+    funding_psbt, with_placeholder_signatures, RevealPlan,
 };
 use hex::ToHex;
-use ic_cdk::api::management_canister::bitcoin::{BitcoinNetwork, GetUtxosResponse, Utxo};
-use ordinals::{Artifact, Etching, Rune, Runestone, SpacedRune, Terms};
-
-pub const SIG_HASH_TYPE: EcdsaSighashType = EcdsaSighashType::All;
+use ic_cdk::api::management_canister::bitcoin::{
+    BitcoinNetwork, GetUtxosResponse, Utxo, UtxoFilter,
+};
+use ordinals::Runestone;
 
 pub async fn get_balance_of(address: String) -> u64 {
     let network = STATE.with_borrow(|state| *state.network.as_ref().unwrap());
@@ -38,22 +40,134 @@ pub async fn get_balance_of(address: String) -> u64 {
 }
 
 pub async fn get_utxos_of(address: String) -> GetUtxosResponse {
+    let response = fetch_utxos(address.clone(), None).await;
+    if response.next_page.is_none() {
+        utxo_lock::release_spent(&address, &response.utxos);
+    }
+    response
+}
+
+/// Utxos of `address` with at least `min_confirmations` confirmations.
+pub async fn get_confirmed_utxos_of(address: String, min_confirmations: u32) -> GetUtxosResponse {
+    fetch_utxos(
+        address,
+        Some(UtxoFilter::MinConfirmations(min_confirmations)),
+    )
+    .await
+}
+
+async fn fetch_utxos(address: String, filter: Option<UtxoFilter>) -> GetUtxosResponse {
     let network = STATE.with_borrow(|state| *state.network.as_ref().unwrap());
-    ic_cdk::api::management_canister::bitcoin::bitcoin_get_utxos(
+    let response = ic_cdk::api::management_canister::bitcoin::bitcoin_get_utxos(
         ic_cdk::api::management_canister::bitcoin::GetUtxosRequest {
             address,
             network,
-            filter: None,
+            filter,
         },
     )
     .await
     .unwrap()
-    .0
+    .0;
+    metrics::observe_tip_height(response.tip_height);
+    response
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct GetBlockHeadersRequest {
+    pub start_height: u32,
+    pub end_height: Option<u32>,
+    pub network: BitcoinNetwork,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Default)]
+pub struct GetBlockHeadersResponse {
+    pub tip_height: u32,
+    /// Consensus encoded 80 byte headers, starting at `start_height`.
+    pub block_headers: Vec<Vec<u8>>,
+}
+
+// the management canister charges as much as for `bitcoin_get_utxos`
+const GET_BLOCK_HEADERS_MAINNET: u128 = 10_000_000_000;
+const GET_BLOCK_HEADERS_TESTNET: u128 = 4_000_000_000;
+
+pub async fn get_block_headers_of(
+    start_height: u32,
+    end_height: Option<u32>,
+) -> GetBlockHeadersResponse {
+    let network = STATE.with_borrow(|state| *state.network.as_ref().unwrap());
+    let cycles = match network {
+        BitcoinNetwork::Mainnet => GET_BLOCK_HEADERS_MAINNET,
+        BitcoinNetwork::Testnet => GET_BLOCK_HEADERS_TESTNET,
+        BitcoinNetwork::Regtest => 0,
+    };
+    let response = ic_cdk::api::call::call_with_payment128::<
+        (GetBlockHeadersRequest,),
+        (GetBlockHeadersResponse,),
+    >(
+        Principal::management_canister(),
+        "bitcoin_get_block_headers",
+        (GetBlockHeadersRequest {
+            start_height,
+            end_height,
+            network,
+        },),
+        cycles,
+    )
+    .await
+    .unwrap()
+    .0;
+    metrics::observe_tip_height(response.tip_height);
+    response
+}
+
+/// Checks `txn`, spending `prevouts`, against relay policy before it gets
+/// signed or broadcast.
+pub fn preflight(txn: &Transaction, prevouts: &[TxOut]) -> Result<(), String> {
+    check_standardness(txn, prevouts).map_err(|violations| {
+        let violations: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+        format!("Transaction isn't standard: {}", violations.join("; "))
+    })
 }
 
-pub async fn send_bitcoin_transaction(txn: Transaction) -> String {
+/// Checks the commit transaction `commit_tx` spending `prevouts` of
+/// `funding_address` and the reveal of every plan with placeholder
+/// signatures, so that nothing is signed that wouldn't be relayed. Returns
+/// the plans again.
+pub fn preflight_etching(
+    commit_tx: &Transaction,
+    funding_address: &Address,
+    prevouts: &[TxOut],
+    plans: Vec<RevealPlan>,
+) -> Result<Vec<RevealPlan>, String> {
+    preflight(
+        &with_placeholder_signatures(commit_tx, funding_address)?,
+        prevouts,
+    )
+    .map_err(|e| format!("Commit: {}", e))?;
+    let mut checked = Vec::with_capacity(plans.len());
+    for (vout, plan) in plans.into_iter().enumerate() {
+        let rune = plan.rune;
+        let unsigned_reveal =
+            build_reveal_transaction(commit_tx, vout as u32, funding_address, plan)?;
+        preflight(
+            &unsigned_reveal.with_placeholder_signature(),
+            std::slice::from_ref(&unsigned_reveal.commit_output),
+        )
+        .map_err(|e| format!("Reveal of {}: {}", rune, e))?;
+        checked.push(unsigned_reveal.into_plan());
+    }
+    Ok(checked)
+}
+
+/// Broadcasts `txn` after checking it against relay policy.
+pub async fn send_bitcoin_transaction(
+    txn: Transaction,
+    prevouts: &[TxOut],
+) -> Result<String, String> {
+    preflight(&txn, prevouts)?;
     let transaction = bitcoin::consensus::serialize(&txn);
     let network = STATE.with_borrow(|state| *state.network.as_ref().unwrap());
+    let failure_guard = FailureGuard::new("bitcoin_send_transaction");
     ic_cdk::api::management_canister::bitcoin::bitcoin_send_transaction(
         ic_cdk::api::management_canister::bitcoin::SendTransactionRequest {
             transaction,
@@ -61,345 +175,903 @@ pub async fn send_bitcoin_transaction(txn: Transaction) -> String {
         },
     )
     .await
-    .unwrap();
-    txn.txid().encode_hex()
+    .map_err(|(code, msg)| format!("Failed to send transaction: {:?} {}", code, msg))?;
+    failure_guard.succeeded();
+    let txid: String = txn.txid().encode_hex();
+    audit::record(AuditEvent::TransactionBroadcasted { txid: txid.clone() });
+    broadcast_tracker::track(&txn, prevouts, ic_cdk::api::time());
+    Ok(txid)
 }
 
-pub fn build_reveal_transaction(
-    commit_input_index: usize,
-    control_block: &ControlBlock,
-    fee_rate: FeeRate,
-    output: Vec<TxOut>,
-    input: Vec<OutPoint>,
-    script: &Script,
-) -> (Transaction, Amount) {
-    let reveal_txn = Transaction {
-        input: input
-            .into_iter()
-            .map(|previous_output| TxIn {
-                previous_output,
-                script_sig: Script::builder().into_script(),
-                witness: Witness::new(),
-                sequence: Sequence::from_height(Runestone::COMMIT_CONFIRMATIONS - 1),
-            })
-            .collect(),
-        output,
-        lock_time: LockTime::ZERO,
-        version: 2,
-    };
-    let fee = {
-        let mut reveal_txn_clone = reveal_txn.clone();
-        for (current_index, txin) in reveal_txn_clone.input.iter_mut().enumerate() {
-            if current_index == commit_input_index {
-                txin.witness.push(
-                    Signature::from_slice(&[0; SCHNORR_SIGNATURE_SIZE])
-                        .unwrap()
-                        .to_vec(),
-                );
-                txin.witness.push(script);
-                txin.witness.push(control_block.serialize());
-            } else {
-                txin.witness = Witness::from_slice(&[&[0; SCHNORR_SIGNATURE_SIZE]]);
-            }
-        }
-        Amount::from_sat(
-            (fee_rate.to_sat_per_kwu() as f64 * reveal_txn_clone.vsize() as f64).round() as u64,
-        )
-    };
-    (reveal_txn, fee)
+#[allow(async_fn_in_trait)]
+pub trait BitcoinApi {
+    async fn get_balance(&self, address: String) -> u64;
+
+    async fn get_utxos(&self, address: String) -> GetUtxosResponse;
+
+    /// Utxos of `address` with at least `min_confirmations` confirmations.
+    async fn get_confirmed_utxos(
+        &self,
+        address: String,
+        min_confirmations: u32,
+    ) -> GetUtxosResponse;
+
+    /// Headers from `start_height` up to `end_height`, or the tip if `None`.
+    async fn get_block_headers(
+        &self,
+        start_height: u32,
+        end_height: Option<u32>,
+    ) -> GetBlockHeadersResponse;
+
+    /// Broadcasts `txn`, which spends `prevouts`, and returns its txid.
+    async fn send_transaction(
+        &self,
+        txn: Transaction,
+        prevouts: &[TxOut],
+    ) -> Result<String, String>;
 }
 
-pub fn check_etching(height: u32, arg: &EtchingArgs) {
-    if arg.height.is_none() && arg.offset.is_none() {
-        ic_cdk::trap("No mint term selected")
+/// Talks to the bitcoin api of the management canister.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IcBitcoinApi;
+
+impl BitcoinApi for IcBitcoinApi {
+    async fn get_balance(&self, address: String) -> u64 {
+        get_balance_of(address).await
     }
-    let network = STATE.with_borrow(|state| match state.network.as_ref().unwrap() {
-        BitcoinNetwork::Mainnet => Network::Bitcoin,
-        BitcoinNetwork::Testnet => Network::Testnet,
-        BitcoinNetwork::Regtest => Network::Regtest,
-    });
-    let minimum = Rune::minimum_at_height(network, ordinals::Height(height));
-    let SpacedRune { rune, spacers: _ } = SpacedRune::from_str(&arg.rune).unwrap();
-    if rune < minimum {
-        ic_cdk::trap("Rune is less than Minimum")
+
+    async fn get_utxos(&self, address: String) -> GetUtxosResponse {
+        get_utxos_of(address).await
     }
-    if rune.is_reserved() {
-        ic_cdk::trap("Rune is reserved")
+
+    async fn get_confirmed_utxos(
+        &self,
+        address: String,
+        min_confirmations: u32,
+    ) -> GetUtxosResponse {
+        get_confirmed_utxos_of(address, min_confirmations).await
     }
-    if char::from_u32(arg.symbol).is_none() {
-        ic_cdk::trap("Failed to validate symbol")
+
+    async fn get_block_headers(
+        &self,
+        start_height: u32,
+        end_height: Option<u32>,
+    ) -> GetBlockHeadersResponse {
+        get_block_headers_of(start_height, end_height).await
     }
-    if arg.amount == 0 || arg.cap == 0 {
-        ic_cdk::trap("Can't be Zero")
+
+    async fn send_transaction(
+        &self,
+        txn: Transaction,
+        prevouts: &[TxOut],
+    ) -> Result<String, String> {
+        send_bitcoin_transaction(txn, prevouts).await
     }
-    if arg.divisibility > 38 {
-        ic_cdk::trap("Exceeds max allowed divisibility")
+}
+
+/// Hash of the block at `height` of the current chain, if it is that long.
+pub async fn block_hash_at(bitcoin_api: &impl BitcoinApi, height: u32) -> Option<BlockHash> {
+    let response = bitcoin_api.get_block_headers(height, Some(height)).await;
+    response.block_headers.first().map(|header| {
+        consensus::deserialize::<Header>(header)
+            .unwrap()
+            .block_hash()
+    })
+}
+
+/// Returned by [`send_reveal_if_confirmed`] once the reveal can't be sent
+/// anymore.
+pub const COMMIT_SPENT: &str = "Commit output was spent by another transaction";
+
+/// Sends the reveal of `queued` once the exact commit output it spends has
+/// enough confirmations for the etching to be valid.
+///
+/// The block the commit was first seen in is remembered. Once the commit
+/// output disappears while that block isn't part of the chain anymore, the
+/// commit was reorged out: it is broadcast again and the confirmations are
+/// counted from the block it lands in next.
+pub async fn send_reveal_if_confirmed(
+    bitcoin_api: &impl BitcoinApi,
+    queued: &mut QueuedRevealTxn,
+    now: u64,
+) -> Result<String, String> {
+    let commit_outpoint = queued.reveal_txn.input[0].previous_output;
+    let is_commit = |utxo: &&Utxo| {
+        utxo.outpoint.txid == commit_outpoint.txid.to_byte_array()
+            && utxo.outpoint.vout == commit_outpoint.vout
+    };
+    let confirmed = bitcoin_api
+        .get_confirmed_utxos(
+            queued.commit_tx_address.clone(),
+            Runestone::COMMIT_CONFIRMATIONS as u32,
+        )
+        .await;
+    if let Some(utxo) = confirmed.utxos.iter().find(is_commit) {
+        let commit_output = TxOut {
+            value: utxo.value,
+            script_pubkey: Address::from_str(&queued.commit_tx_address)
+                .unwrap()
+                .assume_checked()
+                .script_pubkey(),
+        };
+        return bitcoin_api
+            .send_transaction(queued.reveal_txn.clone(), &[commit_output])
+            .await;
     }
-    if let Some((start, stop)) = arg.height {
-        if start >= stop {
-            ic_cdk::trap("Height Start must be lower than Height Stop")
+    let utxos = bitcoin_api
+        .get_utxos(queued.commit_tx_address.clone())
+        .await;
+    if let Some(utxo) = utxos.utxos.iter().find(is_commit) {
+        if queued
+            .commit_block
+            .is_none_or(|(height, _)| height != utxo.height)
+        {
+            if let Some(hash) = block_hash_at(bitcoin_api, utxo.height).await {
+                queued.commit_block = Some((utxo.height, hash));
+            }
         }
+        return Err("Not enough commit confirmation".into());
     }
-    if let Some((start, stop)) = arg.offset {
-        if start >= stop {
-            ic_cdk::trap("Offset Start must be lower than Offset Stop")
-        }
+    let Some((height, hash)) = queued.commit_block else {
+        return Err("No UTXOs Found".into());
+    };
+    if block_hash_at(bitcoin_api, height).await == Some(hash) {
+        return Err(COMMIT_SPENT.into());
     }
+    queued.commit_block = None;
+    broadcast_tracker::rebroadcast(
+        bitcoin_api,
+        &commit_outpoint.txid.encode_hex::<String>(),
+        now,
+    )
+    .await?;
+    Err("Commit was reorged out and broadcast again".into())
+}
+
+pub fn get_network() -> Network {
+    STATE.with_borrow(|state| match state.network.as_ref().unwrap() {
+        BitcoinNetwork::Mainnet => Network::Bitcoin,
+        BitcoinNetwork::Testnet => Network::Testnet,
+        BitcoinNetwork::Regtest => Network::Regtest,
+    })
+}
+
+pub fn check_etching(height: u32, arg: &EtchingArgs) -> Result<(), String> {
+    etcher_core::check_etching(get_network(), height, arg)
+}
+
+/// [`etcher_core::prepare_reveal`] with the configured network, postage and
+/// default fee rate.
+pub fn prepare_reveal(
+    schnorr_public_key: &[u8],
+    caller_address: &Address,
+    etching_args: &EtchingArgs,
+) -> Result<RevealPlan, String> {
+    let (postage, default_fee_rate) =
+        STATE.with_borrow(|state| (state.postage, state.default_fee_rate));
+    etcher_core::prepare_reveal(
+        schnorr_public_key,
+        caller_address,
+        etching_args,
+        get_network(),
+        postage,
+        default_fee_rate,
+    )
+}
+
+fn to_core_utxo(utxo: &Utxo) -> etcher_core::Utxo {
+    etcher_core::Utxo {
+        outpoint: OutPoint::new(
+            Txid::from_raw_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
+            utxo.outpoint.vout,
+        ),
+        value: utxo.value,
+    }
+}
+
+/// The outputs `utxos` of `address` that a transaction spends.
+pub fn prevouts_of(utxos: &[Utxo], address: &Address) -> Vec<TxOut> {
+    utxos
+        .iter()
+        .map(|utxo| TxOut {
+            value: utxo.value,
+            script_pubkey: address.script_pubkey(),
+        })
+        .collect()
+}
+
+/// Selects the `owned_utxos` that fund the commit of `plans`, see
+/// [`etcher_core::select_utxos`].
+pub fn select_utxos(
+    owned_utxos: &[Utxo],
+    funding_address: &Address,
+    plans: &[RevealPlan],
+) -> Result<Vec<Utxo>, String> {
+    let utxos: Vec<_> = owned_utxos.iter().map(to_core_utxo).collect();
+    let selected = etcher_core::select_utxos(&utxos, funding_address, plans)?;
+    Ok(selected
+        .iter()
+        .map(|selected| {
+            let index = utxos
+                .iter()
+                .position(|utxo| utxo.outpoint == selected.outpoint)
+                .unwrap();
+            owned_utxos[index].clone()
+        })
+        .collect())
+}
+
+/// The `utxos` that `transaction` spends.
+pub fn spent_by(utxos: Vec<Utxo>, transaction: &Transaction) -> Vec<Utxo> {
+    utxos
+        .into_iter()
+        .filter(|utxo| {
+            let outpoint = to_core_utxo(utxo).outpoint;
+            transaction
+                .input
+                .iter()
+                .any(|input| input.previous_output == outpoint)
+        })
+        .collect()
 }
 
 pub async fn build_and_sign_etching_transaction(
-    derivation_path: &Vec<Vec<u8>>,
+    ecdsa_signer: &impl EcdsaSigner,
+    schnorr_signer: &impl SchnorrSigner,
+    derivation_path: &[Vec<u8>],
+    owned_utxos: &[Utxo],
+    ecdsa_public_key: &[u8],
+    caller_address: &Address,
+    plan: RevealPlan,
+) -> Result<(Address, Transaction, Transaction), String> {
+    let (commit_tx, mut reveals) = build_and_sign_etching_transactions(
+        ecdsa_signer,
+        schnorr_signer,
+        derivation_path,
+        owned_utxos,
+        ecdsa_public_key,
+        caller_address,
+        vec![plan],
+    )
+    .await?;
+    let (commit_tx_address, reveal_tx) = reveals.remove(0);
+    Ok((commit_tx_address, commit_tx, reveal_tx))
+}
+
+/// Builds the etching transactions with [`etcher_core`] and signs them with
+/// the given signers. See [`build_commit_transaction`] for how the commit
+/// outputs are funded.
+pub async fn build_and_sign_etching_transactions(
+    ecdsa_signer: &impl EcdsaSigner,
+    schnorr_signer: &impl SchnorrSigner,
+    derivation_path: &[Vec<u8>],
     owned_utxos: &[Utxo],
     ecdsa_public_key: &[u8],
+    caller_address: &Address,
+    plans: Vec<RevealPlan>,
+) -> Result<(Transaction, Vec<(Address, Transaction)>), String> {
+    let utxos: Vec<_> = owned_utxos.iter().map(to_core_utxo).collect();
+    let unsigned_commit = build_commit_transaction(&utxos, caller_address, &plans)?;
+    let plans = preflight_etching(
+        &unsigned_commit.transaction,
+        caller_address,
+        &prevouts_of(owned_utxos, caller_address),
+        plans,
+    )?;
+    let mut signatures = Vec::with_capacity(unsigned_commit.sighashes.len());
+    for sighash in unsigned_commit.sighashes.iter() {
+        signatures.push(
+            ecdsa_signer
+                .sign(sighash.to_vec(), derivation_path.to_vec())
+                .await,
+        );
+    }
+    let commit_tx = finalize_commit_transaction(unsigned_commit, &signatures, ecdsa_public_key)
+        .map_err(|e| e.to_string())?;
+    let reveals = sign_reveal_transactions(
+        schnorr_signer,
+        derivation_path,
+        &commit_tx,
+        caller_address,
+        plans,
+    )
+    .await?;
+    Ok((commit_tx, reveals))
+}
+
+/// Builds and signs the reveal of every plan, spending the commit output at
+/// the same index as the plan.
+pub async fn sign_reveal_transactions(
+    schnorr_signer: &impl SchnorrSigner,
+    derivation_path: &[Vec<u8>],
+    commit_tx: &Transaction,
+    caller_address: &Address,
+    plans: Vec<RevealPlan>,
+) -> Result<Vec<(Address, Transaction)>, String> {
+    let mut reveals = Vec::with_capacity(plans.len());
+    for (vout, plan) in plans.into_iter().enumerate() {
+        let commit_tx_address = plan.commit_tx_address.clone();
+        let unsigned_reveal =
+            build_reveal_transaction(commit_tx, vout as u32, caller_address, plan)?;
+        let signature = schnorr_signer
+            .sign(
+                unsigned_reveal.signing_data.clone(),
+                derivation_path.to_vec(),
+            )
+            .await;
+        let reveal_tx = finalize_reveal_transaction(unsigned_reveal, &signature)?;
+        reveals.push((commit_tx_address, reveal_tx));
+    }
+    Ok(reveals)
+}
+
+/// Builds the commit transaction of an etching funded by some of the `funding_utxos` of
+/// the caller's own segwit `funding_address`, as a PSBT for the caller to
+/// sign. The reveal is signed later on, once the signed commit comes back.
+pub fn build_etching_psbt(
     schnorr_public_key: &[u8],
-    caller_p2pkh_address: String,
-    etching_args: EtchingArgs,
-) -> (Address, Transaction, Transaction) {
-    let SpacedRune { rune, spacers } = SpacedRune::from_str(&etching_args.rune).unwrap();
-    let symbol = char::from_u32(etching_args.symbol).unwrap();
-    // building the reveal script
-    let secp256k1 = Secp256k1::new();
-    let schnorr_public_key: XOnlyPublicKey =
-        PublicKey::from_slice(schnorr_public_key).unwrap().into();
-    const PROTOCOL_ID: [u8; 3] = *b"ord";
-    let mut reveal_script = Builder::new()
-        .push_slice(schnorr_public_key.serialize())
-        .push_opcode(opcodes::all::OP_CHECKSIG)
-        .push_opcode(opcodes::OP_FALSE)
-        .push_opcode(opcodes::all::OP_IF)
-        .push_slice(PROTOCOL_ID);
-
-    Tag::Rune.encode(&mut reveal_script, &Some(rune.commitment()));
-
-    let reveal_script = reveal_script
-        .push_opcode(opcodes::all::OP_ENDIF)
-        .into_script();
-
-    let taproot_send_info = TaprootBuilder::new()
-        .add_leaf(0, reveal_script.clone())
-        .unwrap()
-        .finalize(&secp256k1, schnorr_public_key)
-        .unwrap();
+    funding_utxos: &[Utxo],
+    funding_address: &Address,
+    etching_args: &EtchingArgs,
+) -> Result<(Address, PartiallySignedTransaction), String> {
+    let plan = prepare_reveal(schnorr_public_key, funding_address, etching_args)?;
+    let commit_tx_address = plan.commit_tx_address.clone();
+    let funding_utxos = select_utxos(funding_utxos, funding_address, std::slice::from_ref(&plan))?;
+    let utxos: Vec<_> = funding_utxos.iter().map(to_core_utxo).collect();
+    let commit_tx = fund_commit_transaction(&utxos, funding_address, std::slice::from_ref(&plan))?;
+    preflight_etching(
+        &commit_tx,
+        funding_address,
+        &prevouts_of(&funding_utxos, funding_address),
+        vec![plan],
+    )?;
+    let psbt = funding_psbt(&commit_tx, &utxos, funding_address)?;
+    Ok((commit_tx_address, psbt))
+}
 
-    let control_block = taproot_send_info
-        .control_block(&(reveal_script.clone(), LeafVersion::TapScript))
-        .unwrap();
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        blockdata::script::Instruction,
+        ecdsa,
+        secp256k1::{Message, PublicKey as Secp256k1PublicKey, Secp256k1, SecretKey},
+        sighash::SighashCache,
+        ScriptBuf,
+    };
+    use candid::Principal;
+    use etcher_core::transaction::SIG_HASH_TYPE;
+    use ordinals::{Artifact, Rune};
 
-    let network = STATE.with_borrow(|state| {
-        let network = state.network.as_ref().unwrap();
-        match network {
-            BitcoinNetwork::Mainnet => Network::Bitcoin,
-            BitcoinNetwork::Testnet => Network::Testnet,
-            BitcoinNetwork::Regtest => Network::Regtest,
-        }
-    });
-    let caller_address = Address::from_str(&caller_p2pkh_address)
-        .unwrap()
-        .assume_checked();
-    let commit_tx_address = Address::p2tr_tweaked(taproot_send_info.output_key(), network);
+    use super::*;
+    use crate::{
+        config::{DEFAULT_FEE_RATE, DEFAULT_POSTAGE},
+        ecdsa_api::EcdsaSigner,
+        mock::{block_on, MockBitcoinApi, MockEcdsaSigner, MockSchnorrSigner},
+        schnorr_api::SchnorrBackend,
+        utils::{generate_derivation_path, public_key_to_p2pkh_address},
+    };
 
-    let mut reveal_input = vec![OutPoint::null()];
-    let mut reveal_output = vec![];
+    const TIP_HEIGHT: u32 = 100;
 
-    let mut pointer = None;
-    if etching_args.premine > 0 {
-        reveal_output.push(TxOut {
-            script_pubkey: caller_address.script_pubkey(),
-            value: 10_000,
-        });
-        pointer = Some(reveal_output.len() as u32 - 1u32);
+    struct Etcher {
+        bitcoin_api: MockBitcoinApi,
+        ecdsa_signer: MockEcdsaSigner,
+        schnorr_signer: MockSchnorrSigner,
+        derivation_path: Vec<Vec<u8>>,
+        ecdsa_public_key: Vec<u8>,
+        schnorr_public_key: Vec<u8>,
+        address: String,
     }
-    let (height, offset) = match (etching_args.height, etching_args.offset) {
-        (Some((start, stop)), None) => {
-            let height = (Some(start), Some(stop));
-            (height, (None, None))
+
+    impl Etcher {
+        fn caller_address(&self) -> Address {
+            Address::from_str(&self.address).unwrap().assume_checked()
         }
-        (None, Some((start, stop))) => {
-            let offset = (Some(start), Some(stop));
-            ((None, None), offset)
+
+        fn plan(&self, args: &EtchingArgs) -> RevealPlan {
+            prepare_reveal(&self.schnorr_public_key, &self.caller_address(), args).unwrap()
         }
-        (Some((h_start, h_stop)), Some((o_start, o_stop))) => {
-            let height = (Some(h_start), Some(h_stop));
-            let offset = (Some(o_start), Some(o_stop));
-            (height, offset)
+    }
+
+    fn queue(commit_tx_address: &Address, reveal_tx: &Transaction) -> QueuedRevealTxn {
+        QueuedRevealTxn {
+            reveal_txn: reveal_tx.clone(),
+            commit_tx_address: commit_tx_address.to_string(),
+            commit_block: None,
+            next_check_height: 0,
         }
-        (None, None) => ic_cdk::trap("No Term Set"),
-    };
-    let runestone = Runestone {
-        etching: Some(Etching {
-            rune: Some(rune),
-            symbol: Some(symbol),
-            divisibility: Some(etching_args.divisibility),
-            premine: Some(etching_args.premine),
-            spacers: Some(spacers),
-            turbo: etching_args.turbo,
-            terms: Some(Terms {
-                cap: Some(etching_args.cap),
-                amount: Some(etching_args.amount),
-                height,
-                offset,
-            }),
-        }),
-        edicts: vec![],
-        mint: None,
-        pointer,
-    };
+    }
 
-    let script_pubkey = runestone.encipher();
-    if script_pubkey.len() > 82 {
-        ic_cdk::trap("Exceeds OP_RETURN size of 82")
+    fn setup(balance: u64) -> Etcher {
+        STATE.with_borrow_mut(|state| {
+            state.network = Some(BitcoinNetwork::Regtest);
+            state.postage = DEFAULT_POSTAGE;
+            state.default_fee_rate = DEFAULT_FEE_RATE;
+        });
+        let ecdsa_signer = MockEcdsaSigner::new([1; 32]);
+        let schnorr_signer = MockSchnorrSigner::new([2; 32]);
+        let derivation_path = generate_derivation_path(&Principal::anonymous());
+        let ecdsa_public_key = block_on(EcdsaSigner::public_key(
+            &ecdsa_signer,
+            derivation_path.clone(),
+        ));
+        let schnorr_public_key = block_on(SchnorrSigner::public_key(
+            &schnorr_signer,
+            derivation_path.clone(),
+        ));
+        let address = public_key_to_p2pkh_address(&ecdsa_public_key);
+        let bitcoin_api = MockBitcoinApi::new(Network::Regtest, TIP_HEIGHT);
+        bitcoin_api.fund(&address, balance);
+        Etcher {
+            bitcoin_api,
+            ecdsa_signer,
+            schnorr_signer,
+            derivation_path,
+            ecdsa_public_key,
+            schnorr_public_key,
+            address,
+        }
     }
-    reveal_output.push(TxOut {
-        script_pubkey,
-        value: 0,
-    });
-    let fee_rate = FeeRate::from_sat_per_vb(etching_args.fee_rate.unwrap_or(10)).unwrap();
-    let (_, reveal_fee) = build_reveal_transaction(
-        0,
-        &control_block,
-        fee_rate,
-        reveal_output.clone(),
-        reveal_input.clone(),
-        &reveal_script,
-    );
-
-    let mut utxos_to_spend = vec![];
-    let mut total_spent = 0;
-    owned_utxos.iter().for_each(|utxo| {
-        total_spent += utxo.value;
-        utxos_to_spend.push(utxo);
-    });
-
-    let input = utxos_to_spend
-        .into_iter()
-        .map(|utxo| TxIn {
-            previous_output: OutPoint::new(
-                Txid::from_raw_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
-                utxo.outpoint.vout,
-            ),
-            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
-            witness: Witness::new(),
-            script_sig: ScriptBuf::new(),
-        })
-        .collect::<Vec<TxIn>>();
-
-    let mut commit_tx = Transaction {
-        input,
-        output: vec![TxOut {
-            script_pubkey: commit_tx_address.script_pubkey(),
-            value: total_spent,
-        }],
-        lock_time: LockTime::ZERO,
-        version: 2,
-    };
 
-    let sig_bytes = 73;
-    let commit_fee =
-        FeeRate::from_sat_per_vb(fee_rate.to_sat_per_kwu() * commit_tx.vsize() as u64 + sig_bytes)
-            .unwrap();
-    ic_cdk::println!("commit fee: {}\nreveal fee: {}", commit_fee, reveal_fee);
-    commit_tx.output[0].value = total_spent - commit_fee.to_sat_per_kwu();
-
-    // signing the commit_tx
-    let commit_tx_cache = SighashCache::new(commit_tx.clone());
-    for (index, input) in commit_tx.input.iter_mut().enumerate() {
-        let sighash = commit_tx_cache
-            .legacy_signature_hash(
-                index,
-                &caller_address.script_pubkey(),
-                SIG_HASH_TYPE.to_u32(),
+    fn etching_args(rune: &str) -> EtchingArgs {
+        EtchingArgs {
+            divisibility: 2,
+            symbol: '$' as u32,
+            rune: rune.to_string(),
+            amount: 1_000,
+            cap: 100,
+            turbo: true,
+            premine: 500,
+            height: None,
+            offset: Some((0, 1_000)),
+            fee_rate: None,
+        }
+    }
+
+    fn assert_commit_signed_by(commit_tx: &Transaction, address: &str, public_key: &[u8]) {
+        let secp = Secp256k1::verification_only();
+        let script_pubkey = Address::from_str(address)
+            .unwrap()
+            .assume_checked()
+            .script_pubkey();
+        let cache = SighashCache::new(commit_tx);
+        for (index, input) in commit_tx.input.iter().enumerate() {
+            let pushes: Vec<_> = input
+                .script_sig
+                .instructions()
+                .map(|instruction| match instruction.unwrap() {
+                    Instruction::PushBytes(bytes) => bytes.as_bytes().to_vec(),
+                    Instruction::Op(op) => panic!("unexpected {}", op),
+                })
+                .collect();
+            assert_eq!(pushes[1], public_key);
+            let signature = ecdsa::Signature::from_slice(&pushes[0]).unwrap();
+            let sighash = cache
+                .legacy_signature_hash(index, &script_pubkey, SIG_HASH_TYPE.to_u32())
+                .unwrap();
+            secp.verify_ecdsa(
+                &Message::from_slice(&sighash.to_byte_array()).unwrap(),
+                &signature.sig,
+                &Secp256k1PublicKey::from_slice(public_key).unwrap(),
             )
             .unwrap();
-        let signature = ecdsa_sign(sighash.to_byte_array().to_vec(), derivation_path.clone()).await;
-        let der_signature = sec1_to_der(signature);
-        let mut sig_with_hashtype = der_signature;
-        sig_with_hashtype.push(SIG_HASH_TYPE.to_u32() as u8);
-        input.script_sig = ScriptBuf::builder()
-            .push_slice::<&PushBytes>(sig_with_hashtype.as_slice().try_into().unwrap())
-            .push_slice::<&PushBytes>(ecdsa_public_key.try_into().unwrap())
-            .into_script();
-        input.witness.clear();
+        }
     }
-    let (vout, _) = commit_tx
-        .output
-        .iter()
-        .enumerate()
-        .find(|(_vout, output)| output.script_pubkey == commit_tx_address.script_pubkey())
+
+    #[test]
+    fn etches_and_reveals_a_rune() {
+        let etcher = setup(1_000_000);
+        let args = etching_args("ETCHERTESTRUNE");
+        let utxos = block_on(etcher.bitcoin_api.get_utxos(etcher.address.clone()));
+        check_etching(utxos.tip_height, &args).unwrap();
+        let (commit_tx_address, commit_tx, reveal_tx) =
+            block_on(build_and_sign_etching_transaction(
+                &etcher.ecdsa_signer,
+                &etcher.schnorr_signer,
+                &etcher.derivation_path,
+                &utxos.utxos,
+                &etcher.ecdsa_public_key,
+                &etcher.caller_address(),
+                etcher.plan(&args),
+            ))
+            .unwrap();
+        assert_commit_signed_by(&commit_tx, &etcher.address, &etcher.ecdsa_public_key);
+        assert_eq!(
+            commit_tx.output[0].script_pubkey,
+            commit_tx_address.script_pubkey()
+        );
+        assert_eq!(reveal_tx.input[0].previous_output.txid, commit_tx.txid());
+        // everything that isn't paid as fees ends up with the caller again
+        let returned: u64 = reveal_tx.output.iter().map(|output| output.value).sum();
+        let fees = 1_000_000 - returned;
+        assert!(fees > 0 && fees < 20_000, "fees: {}", fees);
+
+        let prevouts = prevouts_of(
+            &utxos.utxos,
+            &Address::from_str(&etcher.address).unwrap().assume_checked(),
+        );
+        let commit_txid = block_on(etcher.bitcoin_api.send_transaction(commit_tx, &prevouts));
+        assert_eq!(
+            commit_txid,
+            Ok(reveal_tx.input[0].previous_output.txid.to_string())
+        );
+        let mut queued = queue(&commit_tx_address, &reveal_tx);
+        let mut send_reveal = || {
+            block_on(send_reveal_if_confirmed(
+                &etcher.bitcoin_api,
+                &mut queued,
+                0,
+            ))
+        };
+        assert_eq!(send_reveal(), Err("No UTXOs Found".to_string()));
+        etcher.bitcoin_api.mine(1);
+        assert_eq!(
+            send_reveal(),
+            Err("Not enough commit confirmation".to_string())
+        );
+        etcher
+            .bitcoin_api
+            .mine(Runestone::COMMIT_CONFIRMATIONS as u32 - 1);
+        assert_eq!(send_reveal(), Ok(reveal_tx.txid().to_string()));
+
+        let mempool = etcher.bitcoin_api.mempool();
+        let Some(Artifact::Runestone(runestone)) = Runestone::decipher(&mempool[0]) else {
+            panic!("reveal carries no runestone")
+        };
+        let etching = runestone.etching.unwrap();
+        assert_eq!(
+            etching.rune,
+            Some(Rune::from_str("ETCHERTESTRUNE").unwrap())
+        );
+        assert_eq!(etching.premine, Some(500));
+
+        // the premine sits in the first output, which the co-signer won't spend
+        crate::cosigner::remember_rune_outpoints(&reveal_tx);
+        assert!(crate::cosigner::is_rune_outpoint(&OutPoint::new(
+            reveal_tx.txid(),
+            0
+        )));
+        assert!(!crate::cosigner::is_rune_outpoint(&OutPoint::new(
+            reveal_tx.txid(),
+            2
+        )));
+    }
+
+    #[test]
+    fn etches_a_rune_funded_by_an_external_psbt() {
+        let etcher = setup(0);
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[7; 32]).unwrap();
+        let public_key = bitcoin::PublicKey::new(secret_key.public_key(&secp));
+        let funding_address = Address::p2wpkh(&public_key, Network::Regtest).unwrap();
+        etcher
+            .bitcoin_api
+            .fund(&funding_address.to_string(), 300_000);
+        etcher.bitcoin_api.fund(&funding_address.to_string(), 5_000);
+        let utxos = block_on(etcher.bitcoin_api.get_utxos(funding_address.to_string())).utxos;
+        let args = etching_args("ETCHERTESTRUNE");
+        let (commit_tx_address, psbt) =
+            build_etching_psbt(&etcher.schnorr_public_key, &utxos, &funding_address, &args)
+                .unwrap();
+
+        // the user signs the PSBT with their own wallet
+        let mut psbt = PartiallySignedTransaction::from_str(&psbt.to_string()).unwrap();
+        let mut cache = SighashCache::new(&psbt.unsigned_tx);
+        let signatures: Vec<_> = psbt
+            .inputs
+            .iter()
+            .enumerate()
+            .map(|(index, input)| {
+                let sighash = cache
+                    .segwit_signature_hash(
+                        index,
+                        &ScriptBuf::new_p2pkh(&public_key.pubkey_hash()),
+                        input.witness_utxo.as_ref().unwrap().value,
+                        SIG_HASH_TYPE,
+                    )
+                    .unwrap();
+                ecdsa::Signature::sighash_all(
+                    secp.sign_ecdsa(&Message::from_slice(sighash.as_ref()).unwrap(), &secret_key),
+                )
+            })
+            .collect();
+        for (input, signature) in psbt.inputs.iter_mut().zip(signatures) {
+            input.partial_sigs.insert(public_key, signature);
+        }
+
+        let commit_tx = etcher_core::finalize_funding_psbt(psbt, &funding_address).unwrap();
+        let (reveal_address, reveal_tx) = block_on(sign_reveal_transactions(
+            &etcher.schnorr_signer,
+            &etcher.derivation_path,
+            &commit_tx,
+            &funding_address,
+            vec![prepare_reveal(&etcher.schnorr_public_key, &funding_address, &args).unwrap()],
+        ))
+        .unwrap()
+        .remove(0);
+        assert_eq!(reveal_address, commit_tx_address);
+        // the larger utxo covers the etching, the other one stays free
+        let spent = spent_by(utxos, &commit_tx);
+        assert_eq!(spent.len(), 1);
+        assert_eq!(spent[0].value, 300_000);
+        block_on(
+            etcher
+                .bitcoin_api
+                .send_transaction(commit_tx, &prevouts_of(&spent, &funding_address)),
+        )
         .unwrap();
-    reveal_input[0] = OutPoint {
-        txid: commit_tx.txid(),
-        vout: vout as u32,
-    };
-    reveal_output.push(TxOut {
-        script_pubkey: caller_address.script_pubkey(),
-        value: total_spent - commit_fee.to_sat_per_kwu() - reveal_fee.to_sat(),
-    });
-    // building the reveal txn
-    let mut reveal_tx = Transaction {
-        version: 2,
-        lock_time: LockTime::ZERO,
-        input: reveal_input
+        etcher
+            .bitcoin_api
+            .mine(Runestone::COMMIT_CONFIRMATIONS as u32);
+        assert_eq!(
+            block_on(send_reveal_if_confirmed(
+                &etcher.bitcoin_api,
+                &mut queue(&commit_tx_address, &reveal_tx),
+                0,
+            )),
+            Ok(reveal_tx.txid().to_string())
+        );
+        let returned: u64 = reveal_tx
+            .output
             .iter()
-            .map(|outpoint| TxIn {
-                previous_output: *outpoint,
-                witness: Witness::new(),
-                script_sig: ScriptBuf::new(),
-                sequence: Sequence::from_height(Runestone::COMMIT_CONFIRMATIONS - 1),
+            .filter(|output| output.script_pubkey == funding_address.script_pubkey())
+            .map(|output| output.value)
+            .sum();
+        assert!(300_000 - returned < 20_000);
+    }
+
+    #[test]
+    fn refuses_to_build_etchings_nodes_would_not_relay() {
+        let etcher = setup(1_000_000);
+        let caller_address = Address::from_str(&etcher.address).unwrap().assume_checked();
+        let utxos = block_on(etcher.bitcoin_api.get_utxos(etcher.address.clone())).utxos;
+        let mut args = etching_args("ETCHERTESTRUNE");
+        args.fee_rate = Some(0);
+        let err = build_etching_psbt(&etcher.schnorr_public_key, &utxos, &caller_address, &args)
+            .unwrap_err();
+        assert!(
+            err.starts_with("Commit: Transaction isn't standard: Fee of 0 is below"),
+            "{}",
+            err
+        );
+
+        // a signed transaction is checked again right before it is sent
+        let (_, commit_tx, _) = block_on(build_and_sign_etching_transaction(
+            &etcher.ecdsa_signer,
+            &etcher.schnorr_signer,
+            &etcher.derivation_path,
+            &utxos,
+            &etcher.ecdsa_public_key,
+            &caller_address,
+            etcher.plan(&etching_args("ETCHERTESTRUNE")),
+        ))
+        .unwrap();
+        let mut prevouts = prevouts_of(&utxos, &caller_address);
+        prevouts[0].value = 0;
+        assert!(block_on(etcher.bitcoin_api.send_transaction(commit_tx, &prevouts)).is_err());
+        assert!(etcher.bitcoin_api.mempool().is_empty());
+    }
+
+    #[test]
+    fn etches_several_runes_from_one_commit() {
+        let etcher = setup(1_000_000);
+        let caller_address = Address::from_str(&etcher.address).unwrap().assume_checked();
+        let plans: Vec<_> = ["ETCHERTESTRUNEA", "ETCHERTESTRUNEB", "ETCHERTESTRUNEC"]
+            .into_iter()
+            .map(|rune| {
+                prepare_reveal(
+                    &etcher.schnorr_public_key,
+                    &caller_address,
+                    &etching_args(rune),
+                )
+                .unwrap()
             })
-            .collect(),
-        output: reveal_output,
-    };
-    for output in reveal_tx.output.iter() {
-        if output.value < output.script_pubkey.dust_value().to_sat() {
-            ic_cdk::trap("commit txn output would be dust")
+            .collect();
+        let utxos = block_on(etcher.bitcoin_api.get_utxos(etcher.address.clone()));
+        let (commit_tx, reveals) = block_on(build_and_sign_etching_transactions(
+            &etcher.ecdsa_signer,
+            &etcher.schnorr_signer,
+            &etcher.derivation_path,
+            &utxos.utxos,
+            &etcher.ecdsa_public_key,
+            &caller_address,
+            plans,
+        ))
+        .unwrap();
+        assert_commit_signed_by(&commit_tx, &etcher.address, &etcher.ecdsa_public_key);
+        assert_eq!(commit_tx.output.len(), 3);
+        block_on(etcher.bitcoin_api.send_transaction(
+            commit_tx.clone(),
+            &prevouts_of(&utxos.utxos, &caller_address),
+        ))
+        .unwrap();
+        etcher
+            .bitcoin_api
+            .mine(Runestone::COMMIT_CONFIRMATIONS as u32);
+        for (vout, (commit_tx_address, reveal_tx)) in reveals.into_iter().enumerate() {
+            assert_eq!(
+                reveal_tx.input[0].previous_output,
+                OutPoint::new(commit_tx.txid(), vout as u32)
+            );
+            block_on(send_reveal_if_confirmed(
+                &etcher.bitcoin_api,
+                &mut queue(&commit_tx_address, &reveal_tx),
+                0,
+            ))
+            .unwrap();
         }
+        assert_eq!(etcher.bitcoin_api.mempool().len(), 3);
     }
-    let mut sighash_cache = SighashCache::new(&mut reveal_tx);
-    let mut signing_data = vec![];
-    let leaf_hash = TapLeafHash::from_script(&reveal_script, LeafVersion::TapScript);
-    sighash_cache
-        .taproot_encode_signing_data_to(
-            &mut signing_data,
-            0,
-            &Prevouts::All(&[commit_tx.output[vout].clone()]),
-            None,
-            Some((leaf_hash, 0xFFFFFFFF)),
-            TapSighashType::Default,
+
+    #[test]
+    fn rebroadcasts_commits_that_were_reorged_out() {
+        let etcher = setup(1_000_000);
+        let utxos = block_on(etcher.bitcoin_api.get_utxos(etcher.address.clone()));
+        let (commit_tx_address, commit_tx, reveal_tx) =
+            block_on(build_and_sign_etching_transaction(
+                &etcher.ecdsa_signer,
+                &etcher.schnorr_signer,
+                &etcher.derivation_path,
+                &utxos.utxos,
+                &etcher.ecdsa_public_key,
+                &etcher.caller_address(),
+                etcher.plan(&etching_args("ETCHERREORGRUNE")),
+            ))
+            .unwrap();
+        let prevouts = prevouts_of(
+            &utxos.utxos,
+            &Address::from_str(&etcher.address).unwrap().assume_checked(),
+        );
+        block_on(
+            etcher
+                .bitcoin_api
+                .send_transaction(commit_tx.clone(), &prevouts),
         )
         .unwrap();
-    let mut hashed_tag = sha256::Hash::hash(b"TapSighash").to_byte_array().to_vec();
-    let mut prefix = hashed_tag.clone();
-    prefix.append(&mut hashed_tag);
-    let signing_data: Vec<_> = prefix.iter().chain(signing_data.iter()).cloned().collect();
-    let schnorr_signature =
-        schnorr_api::schnorr_sign(signing_data.clone(), derivation_path.clone()).await;
-    ic_cdk::println!("sig size: {}", schnorr_signature.len());
-    // Verify the signature to be sure that signing works
-    let secp = bitcoin::secp256k1::Secp256k1::verification_only();
-
-    let sig_ = schnorr::Signature::from_slice(&schnorr_signature).unwrap();
-    let digest = sha256::Hash::hash(&signing_data).to_byte_array();
-    let msg = Message::from_slice(&digest).unwrap();
-    assert!(secp
-        .verify_schnorr(&sig_, &msg, &schnorr_public_key)
-        .is_ok());
-
-    let witness = sighash_cache.witness_mut(0).unwrap();
-    witness.push(
-        Signature {
-            sig: schnorr::Signature::from_slice(&schnorr_signature).unwrap(),
-            hash_ty: TapSighashType::Default,
-        }
-        .to_vec(),
-    );
-    witness.push(reveal_script);
-    witness.push(&control_block.serialize());
-    if Runestone::decipher(&reveal_tx).unwrap() != Artifact::Runestone(runestone) {
-        ic_cdk::trap("Runestone mismatched")
+        broadcast_tracker::track(&commit_tx, &prevouts, 0);
+        let mut queued = queue(&commit_tx_address, &reveal_tx);
+        let mut send_reveal = || {
+            block_on(send_reveal_if_confirmed(
+                &etcher.bitcoin_api,
+                &mut queued,
+                0,
+            ))
+        };
+
+        etcher.bitcoin_api.mine(1);
+        assert_eq!(
+            send_reveal(),
+            Err("Not enough commit confirmation".to_string())
+        );
+        // the block with the commit is replaced by one without it
+        etcher.bitcoin_api.reorg(1);
+        assert!(etcher.bitcoin_api.mempool().is_empty());
+        assert_eq!(
+            send_reveal(),
+            Err("Commit was reorged out and broadcast again".to_string())
+        );
+        assert_eq!(etcher.bitcoin_api.mempool(), vec![commit_tx.clone()]);
+        let info = broadcast_tracker::get_info(commit_tx.txid().encode_hex()).unwrap();
+        assert_eq!(info.broadcasts, 2);
+
+        // confirmations count from the block the commit lands in now
+        etcher.bitcoin_api.mine(1);
+        assert_eq!(
+            send_reveal(),
+            Err("Not enough commit confirmation".to_string())
+        );
+        etcher
+            .bitcoin_api
+            .mine(Runestone::COMMIT_CONFIRMATIONS as u32 - 1);
+        assert_eq!(send_reveal(), Ok(reveal_tx.txid().to_string()));
+    }
+
+    #[test]
+    fn reports_commits_spent_by_another_transaction() {
+        let etcher = setup(1_000_000);
+        let utxos = block_on(etcher.bitcoin_api.get_utxos(etcher.address.clone()));
+        let (commit_tx_address, commit_tx, reveal_tx) =
+            block_on(build_and_sign_etching_transaction(
+                &etcher.ecdsa_signer,
+                &etcher.schnorr_signer,
+                &etcher.derivation_path,
+                &utxos.utxos,
+                &etcher.ecdsa_public_key,
+                &etcher.caller_address(),
+                etcher.plan(&etching_args("ETCHERSPENTRUNE")),
+            ))
+            .unwrap();
+        let prevouts = prevouts_of(&utxos.utxos, &etcher.caller_address());
+        block_on(
+            etcher
+                .bitcoin_api
+                .send_transaction(commit_tx.clone(), &prevouts),
+        )
+        .unwrap();
+        let mut queued = queue(&commit_tx_address, &reveal_tx);
+        etcher.bitcoin_api.mine(1);
+        assert_eq!(
+            block_on(send_reveal_if_confirmed(
+                &etcher.bitcoin_api,
+                &mut queued,
+                0
+            )),
+            Err("Not enough commit confirmation".to_string())
+        );
+
+        // the same input spent with another fee
+        let mut other = reveal_tx.clone();
+        other.output.last_mut().unwrap().value -= 1_000;
+        block_on(
+            etcher
+                .bitcoin_api
+                .send_transaction(other, &commit_tx.output[..1]),
+        )
+        .unwrap();
+        etcher.bitcoin_api.mine(1);
+        assert_eq!(
+            block_on(send_reveal_if_confirmed(
+                &etcher.bitcoin_api,
+                &mut queued,
+                0
+            )),
+            Err(COMMIT_SPENT.to_string())
+        );
+    }
+
+    #[test]
+    fn returns_signing_and_building_failures() {
+        let etcher = setup(1_000_000);
+        let utxos = block_on(etcher.bitcoin_api.get_utxos(etcher.address.clone())).utxos;
+        let build_and_sign = |ecdsa_signer: &MockEcdsaSigner, utxos: &[Utxo]| {
+            block_on(build_and_sign_etching_transaction(
+                ecdsa_signer,
+                &etcher.schnorr_signer,
+                &etcher.derivation_path,
+                utxos,
+                &etcher.ecdsa_public_key,
+                &etcher.caller_address(),
+                etcher.plan(&etching_args("ETCHERFAILINGRUNE")),
+            ))
+        };
+        // signs with another key than the one the commit inputs belong to
+        assert_eq!(
+            build_and_sign(&MockEcdsaSigner::new([9; 32]), &utxos).unwrap_err(),
+            "Signature of input 0 doesn't match its sighash"
+        );
+        assert_eq!(
+            build_and_sign(&etcher.ecdsa_signer, &[]).unwrap_err(),
+            "Not enough balance"
+        );
+        assert!(build_and_sign(&etcher.ecdsa_signer, &utxos).is_ok());
+    }
+
+    #[test]
+    fn signs_reveals_through_the_management_canister() {
+        let etcher = setup(1_000_000);
+        let utxos = block_on(etcher.bitcoin_api.get_utxos(etcher.address.clone())).utxos;
+        let args = etching_args("ETCHERMANAGEDRUNE");
+        let (_, commit_tx, _) = block_on(build_and_sign_etching_transaction(
+            &etcher.ecdsa_signer,
+            &etcher.schnorr_signer,
+            &etcher.derivation_path,
+            &utxos,
+            &etcher.ecdsa_public_key,
+            &etcher.caller_address(),
+            etcher.plan(&args),
+        ))
+        .unwrap();
+        // the same key, signing messages as given rather than hashing them
+        let schnorr_signer =
+            MockSchnorrSigner::with_backend([2; 32], SchnorrBackend::ManagementCanister);
+        let unsigned_reveal =
+            build_reveal_transaction(&commit_tx, 0, &etcher.caller_address(), etcher.plan(&args))
+                .unwrap();
+        let signature = block_on(schnorr_signer.sign(
+            unsigned_reveal.signing_data.clone(),
+            etcher.derivation_path.clone(),
+        ));
+        let reveal_tx = finalize_reveal_transaction(unsigned_reveal, &signature).unwrap();
+        assert_eq!(reveal_tx.input[0].previous_output.txid, commit_tx.txid());
     }
-    let commit_tx_bytes = consensus::serialize(&commit_tx);
-    let reveal_tx_bytes = consensus::serialize(&reveal_tx);
-    ic_cdk::println!("Commit tx bytes: {}", hex::encode(commit_tx_bytes));
-    ic_cdk::println!("Reveal tx bytes: {}", hex::encode(reveal_tx_bytes));
-    (commit_tx_address, commit_tx, reveal_tx)
 }
@@ -0,0 +1,167 @@
+//! Follows the tip of the bitcoin chain.
+//!
+//! The headers of the last [`KEPT_HEADERS`] blocks are kept in stable memory
+//! and synced from `bitcoin_get_block_headers`. A synced header that doesn't
+//! match the kept one at its height means the blocks from there on were
+//! reorged out, which [`sync`] reports for the other subsystems to react to.
+
+use std::time::Duration;
+
+use bitcoin::{block::Header, consensus};
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::{btc_api::BitcoinApi, storage::CHAIN_HEADERS};
+
+/// A day of blocks, far deeper than any reorg seen in practice.
+pub const KEPT_HEADERS: u32 = 144;
+/// Blocks come every ten minutes on average.
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// the median time past of a block is taken over it and its ten parents
+const MEDIAN_TIME_SPAN: usize = 11;
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChainTip {
+    pub height: u32,
+    pub hash: String,
+    pub median_time_past: u32,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reorg {
+    /// Height of the last block both chains share.
+    pub fork_height: u32,
+    /// Number of kept blocks that were replaced or dropped.
+    pub depth: u32,
+}
+
+fn header_at(height: u32) -> Option<Header> {
+    CHAIN_HEADERS.with_borrow(|headers| {
+        headers
+            .get(&height)
+            .map(|header| consensus::deserialize(&header).unwrap())
+    })
+}
+
+pub fn tip_height() -> Option<u32> {
+    CHAIN_HEADERS.with_borrow(|headers| headers.last_key_value().map(|(height, _)| height))
+}
+
+pub fn tip() -> Option<ChainTip> {
+    let height = tip_height()?;
+    let hash = header_at(height).unwrap().block_hash();
+    Some(ChainTip {
+        height,
+        hash: hash.to_string(),
+        median_time_past: median_time_past(),
+    })
+}
+
+/// Median of the timestamps of the tip and its parents, which the next
+/// block's timestamp and time locks are checked against.
+pub fn median_time_past() -> u32 {
+    let Some(tip_height) = tip_height() else {
+        return 0;
+    };
+    let first_height = tip_height.saturating_sub(MEDIAN_TIME_SPAN as u32 - 1);
+    let mut times: Vec<u32> = CHAIN_HEADERS.with_borrow(|headers| {
+        headers
+            .range(first_height..)
+            .map(|(_, header)| consensus::deserialize::<Header>(&header).unwrap().time)
+            .collect()
+    });
+    times.sort_unstable();
+    times[times.len() / 2]
+}
+
+/// Fetches the headers from the lowest kept height up to the tip and keeps
+/// the last [`KEPT_HEADERS`] of them.
+pub async fn sync(bitcoin_api: &impl BitcoinApi) -> Option<Reorg> {
+    let kept_tip = tip_height();
+    let start_height = match CHAIN_HEADERS.with_borrow(|headers| headers.first_key_value()) {
+        Some((height, _)) => height,
+        None => {
+            let tip_height = bitcoin_api.get_block_headers(0, Some(0)).await.tip_height;
+            tip_height.saturating_sub(KEPT_HEADERS - 1)
+        }
+    };
+    let response = bitcoin_api.get_block_headers(start_height, None).await;
+    let fetched: Vec<(u32, Header)> = (start_height..)
+        .zip(
+            response
+                .block_headers
+                .iter()
+                .map(|header| consensus::deserialize(header).unwrap()),
+        )
+        .collect();
+
+    let mut first_replaced = fetched
+        .iter()
+        .find(|(height, header)| header_at(*height).is_some_and(|kept| kept != *header))
+        .map(|(height, _)| *height);
+    if kept_tip.is_some_and(|kept_tip| kept_tip > response.tip_height) {
+        first_replaced = first_replaced.min(Some(response.tip_height + 1));
+    }
+    let reorg = first_replaced.map(|height| Reorg {
+        fork_height: height - 1,
+        depth: kept_tip.unwrap() - height + 1,
+    });
+
+    let lowest_kept = response.tip_height.saturating_sub(KEPT_HEADERS - 1);
+    CHAIN_HEADERS.with_borrow_mut(|headers| {
+        for (height, header) in fetched {
+            headers.insert(height, consensus::serialize(&header));
+        }
+        let stale: Vec<u32> = headers
+            .iter()
+            .map(|(height, _)| height)
+            .filter(|height| *height < lowest_kept || *height > response.tip_height)
+            .collect();
+        for height in stale {
+            headers.remove(&height);
+        }
+    });
+    reorg
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::Network;
+
+    use super::*;
+    use crate::mock::{block_on, MockBitcoinApi};
+
+    #[test]
+    fn follows_the_tip_and_detects_reorgs() {
+        let bitcoin_api = MockBitcoinApi::new(Network::Regtest, 200);
+        assert_eq!(tip_height(), None);
+        assert_eq!(block_on(sync(&bitcoin_api)), None);
+        let chain_tip = tip().unwrap();
+        assert_eq!(chain_tip.height, 200);
+        // the mock stamps every block with its height
+        assert_eq!(chain_tip.median_time_past, 195);
+        assert_eq!(
+            CHAIN_HEADERS.with_borrow(|headers| headers.first_key_value().unwrap().0),
+            200 - KEPT_HEADERS + 1
+        );
+
+        bitcoin_api.mine(3);
+        assert_eq!(block_on(sync(&bitcoin_api)), None);
+        assert_eq!(tip_height(), Some(203));
+        let replaced_tip = tip().unwrap().hash;
+
+        bitcoin_api.reorg(2);
+        bitcoin_api.mine(1);
+        assert_eq!(
+            block_on(sync(&bitcoin_api)),
+            Some(Reorg {
+                fork_height: 201,
+                depth: 2,
+            })
+        );
+        let chain_tip = tip().unwrap();
+        assert_eq!(chain_tip.height, 204);
+        assert_ne!(chain_tip.hash, replaced_tip);
+        assert_eq!(CHAIN_HEADERS.with_borrow(|headers| headers.len()), 144);
+    }
+}
@@ -0,0 +1,100 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{self, AuditEvent},
+    metrics, STATE,
+};
+
+pub const DEFAULT_BREAKER_THRESHOLD: u32 = 5;
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OperatingMode {
+    #[default]
+    Running,
+    /// No new etchings or conversions are accepted, queued reveals are still sent.
+    PausedNewOperations,
+    /// Nothing is signed or broadcasted, not even queued reveals.
+    ReadOnly,
+}
+
+pub fn get_operating_mode() -> OperatingMode {
+    STATE.with_borrow(|state| state.operating_mode)
+}
+
+pub fn set_operating_mode(mode: OperatingMode) {
+    STATE.with_borrow_mut(|state| {
+        state.operating_mode = mode;
+        if mode == OperatingMode::Running {
+            state.consecutive_failures = 0;
+        }
+    })
+}
+
+/// Traps unless the canister accepts new operations.
+pub fn ensure_running() {
+    match get_operating_mode() {
+        OperatingMode::Running => {}
+        OperatingMode::PausedNewOperations => ic_cdk::trap("New operations are paused"),
+        OperatingMode::ReadOnly => ic_cdk::trap("Canister is read only"),
+    }
+}
+
+fn record_failure(caller: Principal, operation: &'static str) {
+    metrics::record_failure(operation);
+    audit::record_as(
+        caller,
+        AuditEvent::Failure {
+            reason: format!("{} failed", operation),
+        },
+    );
+    STATE.with_borrow_mut(|state| {
+        state.consecutive_failures += 1;
+        if state.breaker_threshold > 0
+            && state.consecutive_failures >= state.breaker_threshold
+            && state.operating_mode == OperatingMode::Running
+        {
+            ic_cdk::println!(
+                "Circuit breaker tripped after {} consecutive failures",
+                state.consecutive_failures
+            );
+            state.operating_mode = OperatingMode::PausedNewOperations;
+        }
+    })
+}
+
+/// Counts a signing or broadcasting call as failed unless
+/// [`FailureGuard::succeeded`] is called. A failing call traps in its
+/// callback, so the failure is recorded when the guard is dropped during
+/// the cleanup, which is the only way for it to outlive the trap.
+#[derive(Debug)]
+#[must_use]
+pub struct FailureGuard {
+    // captured up front, the caller isn't available during the cleanup
+    caller: Principal,
+    operation: &'static str,
+    succeeded: bool,
+}
+
+impl FailureGuard {
+    pub fn new(operation: &'static str) -> Self {
+        Self {
+            caller: ic_cdk::caller(),
+            operation,
+            succeeded: false,
+        }
+    }
+
+    pub fn succeeded(mut self) {
+        self.succeeded = true;
+        STATE.with_borrow_mut(|state| state.consecutive_failures = 0);
+    }
+}
+
+impl Drop for FailureGuard {
+    fn drop(&mut self) {
+        if !self.succeeded {
+            record_failure(self.caller, self.operation);
+        }
+    }
+}
@@ -273,3 +273,89 @@ impl CkBTC {
         .0
     }
 }
+
+#[allow(async_fn_in_trait)]
+pub trait CkBtcMinterApi {
+    async fn estimate_withdrawal_fee(&self, amount: Option<u64>) -> EstimateWithdrawalFeeResponse;
+
+    async fn get_deposit_fee(&self) -> u64;
+
+    async fn get_withdrawal_account(&self) -> Account;
+
+    async fn retrieve_btc(
+        &self,
+        retrieve_btc_args: RetrieveBtcArgs,
+    ) -> Result<RetrieveBtcOk, RetrieveBtcError>;
+
+    async fn retrieve_btc_status_v2(&self, arg: RetrieveBtcStatusArgs) -> RetrieveBtcStatusV2;
+}
+
+impl CkBtcMinterApi for CkBTCMinter {
+    async fn estimate_withdrawal_fee(&self, amount: Option<u64>) -> EstimateWithdrawalFeeResponse {
+        CkBTCMinter::estimate_withdrawal_fee(self, amount).await
+    }
+
+    async fn get_deposit_fee(&self) -> u64 {
+        CkBTCMinter::get_deposit_fee(self).await
+    }
+
+    async fn get_withdrawal_account(&self) -> Account {
+        CkBTCMinter::get_withdrawal_account(self).await
+    }
+
+    async fn retrieve_btc(
+        &self,
+        retrieve_btc_args: RetrieveBtcArgs,
+    ) -> Result<RetrieveBtcOk, RetrieveBtcError> {
+        CkBTCMinter::retrieve_btc(self, retrieve_btc_args).await
+    }
+
+    async fn retrieve_btc_status_v2(&self, arg: RetrieveBtcStatusArgs) -> RetrieveBtcStatusV2 {
+        CkBTCMinter::retrieve_btc_status_v2(self, arg).await
+    }
+}
+
+/// Amount of btc that is retrieved when converting `balance` ckBTC, after
+/// the ledger fee and the deposit and withdrawal fees of the minter.
+pub async fn conversion_amount(
+    minter: &impl CkBtcMinterApi,
+    balance: u64,
+    min_conversion_balance: u64,
+) -> Result<u64, String> {
+    let estimated_fee = minter.estimate_withdrawal_fee(Some(balance)).await;
+    let deposit_fee = minter.get_deposit_fee().await;
+    let total_fee = estimated_fee.bitcoin_fee + estimated_fee.minter_fee;
+    if balance <= total_fee + deposit_fee + min_conversion_balance {
+        return Err(format!(
+            "Balance too Low! Current Balance: {}, Expected atleast {}",
+            balance,
+            total_fee + deposit_fee + min_conversion_balance
+        ));
+    }
+    Ok(balance - 10 - total_fee - deposit_fee)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{block_on, MockCkBtcMinter};
+
+    const MINTER: MockCkBtcMinter = MockCkBtcMinter {
+        bitcoin_fee: 2_000,
+        minter_fee: 1_000,
+        deposit_fee: 500,
+    };
+
+    #[test]
+    fn converts_what_is_left_after_fees() {
+        assert_eq!(
+            block_on(conversion_amount(&MINTER, 100_000, 20_000)),
+            Ok(100_000 - 10 - 2_000 - 1_000 - 500)
+        );
+    }
+
+    #[test]
+    fn refuses_balances_below_the_margin() {
+        assert!(block_on(conversion_amount(&MINTER, 23_500, 20_000)).is_err());
+    }
+}
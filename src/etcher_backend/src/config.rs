@@ -0,0 +1,254 @@
+use candid::{CandidType, Principal};
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+use serde::Deserialize;
+
+use crate::{
+    schnorr_api::{SchnorrBackend, SchnorrKeyId},
+    EcdsaKeyIds, State, STATE,
+};
+
+pub const DEFAULT_POSTAGE: u64 = 10_000;
+pub const DEFAULT_MIN_ETCHING_BALANCE: u64 = 1000_0000;
+pub const DEFAULT_MIN_CONVERSION_BALANCE: u64 = 20_000;
+pub const DEFAULT_FEE_RATE: u64 = 10;
+pub const DEFAULT_MAX_FEE_RATE: u64 = 100;
+pub const DEFAULT_REBROADCAST_INTERVAL: u32 = 30;
+pub const DEFAULT_POSSIBLY_DROPPED_AFTER: u32 = 24 * 60;
+/// Dust threshold of a P2PKH output, the highest of the caller addresses a
+/// reveal pays the postage to.
+pub const MIN_POSTAGE: u64 = 546;
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct Config {
+    pub admins: Vec<Principal>,
+    pub ckbtc_ledger: Principal,
+    pub ckbtc_minter: Principal,
+    pub network: BitcoinNetwork,
+    pub ecdsa_key: EcdsaKeyIds,
+    pub schnorr_key: SchnorrKeyId,
+    pub schnorr_canister: Principal,
+    pub schnorr_backend: SchnorrBackend,
+    pub timer_for_reveal_txn: u32,
+    pub postage: u64,
+    pub min_etching_balance: u64,
+    pub min_conversion_balance: u64,
+    pub default_fee_rate: u64,
+    pub breaker_threshold: u32,
+    pub allowed_output_addresses: Vec<String>,
+    pub max_fee_rate: u64,
+    pub daily_spend_limit: u64,
+    pub rebroadcast_interval: u32,
+    pub possibly_dropped_after: u32,
+}
+
+/// Fields left as `None` keep their current value.
+#[derive(CandidType, Deserialize, Debug, Default)]
+pub struct UpdateConfigArgs {
+    pub admins: Option<Vec<Principal>>,
+    pub ckbtc_ledger: Option<Principal>,
+    pub ckbtc_minter: Option<Principal>,
+    pub ecdsa_key: Option<EcdsaKeyIds>,
+    pub schnorr_key: Option<SchnorrKeyId>,
+    pub schnorr_canister: Option<Principal>,
+    pub schnorr_backend: Option<SchnorrBackend>,
+    pub timer_for_reveal_txn: Option<u32>, // should be provided as mins
+    pub postage: Option<u64>,
+    pub min_etching_balance: Option<u64>,
+    pub min_conversion_balance: Option<u64>,
+    pub default_fee_rate: Option<u64>,
+    // consecutive signing or broadcasting failures that pause new operations, 0 disables it
+    pub breaker_threshold: Option<u32>,
+    // policy of `sign_psbt`: where co-signed transactions may pay to, their
+    // highest fee rate in sat/vB and how many sats a caller may spend per day
+    pub allowed_output_addresses: Option<Vec<String>>,
+    pub max_fee_rate: Option<u64>,
+    pub daily_spend_limit: Option<u64>,
+    // mins between rebroadcasts of unconfirmed transactions, 0 disables it,
+    // and mins after which they are flagged as possibly dropped
+    pub rebroadcast_interval: Option<u32>,
+    pub possibly_dropped_after: Option<u32>,
+}
+
+pub type UpgradeArgs = UpdateConfigArgs;
+
+/// Controllers and the admins listed in the config may manage the canister.
+pub fn is_admin(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal)
+        || STATE.with_borrow(|state| state.admins.contains(principal))
+}
+
+pub fn ensure_admin() {
+    if !is_admin(&ic_cdk::caller()) {
+        ic_cdk::trap("Caller is not an admin")
+    }
+}
+
+pub fn get_config(state: &State) -> Config {
+    Config {
+        admins: state.admins.clone(),
+        ckbtc_ledger: state.ckbtc_ledger.unwrap(),
+        ckbtc_minter: state.ckbtc_minter.unwrap(),
+        network: state.network.unwrap(),
+        ecdsa_key: state.ecdsa_key.clone().unwrap(),
+        schnorr_key: state.schnorr_key.clone().unwrap(),
+        schnorr_canister: state.schnorr_canister.unwrap(),
+        schnorr_backend: state.schnorr_backend,
+        timer_for_reveal_txn: state.timer_for_reveal_txn,
+        postage: state.postage,
+        min_etching_balance: state.min_etching_balance,
+        min_conversion_balance: state.min_conversion_balance,
+        default_fee_rate: state.default_fee_rate,
+        breaker_threshold: state.breaker_threshold,
+        allowed_output_addresses: state.allowed_output_addresses.clone(),
+        max_fee_rate: state.max_fee_rate,
+        daily_spend_limit: state.daily_spend_limit,
+        rebroadcast_interval: state.rebroadcast_interval,
+        possibly_dropped_after: state.possibly_dropped_after,
+    }
+}
+
+/// Rejects the values the canister can't work with.
+pub fn validate(args: &UpdateConfigArgs) -> Result<(), String> {
+    if args.timer_for_reveal_txn == Some(0) {
+        return Err("timer_for_reveal_txn must be at least one minute".into());
+    }
+    if let Some(postage) = args.postage.filter(|postage| *postage < MIN_POSTAGE) {
+        return Err(format!(
+            "postage of {} is below the dust threshold of {}",
+            postage, MIN_POSTAGE
+        ));
+    }
+    if args.default_fee_rate == Some(0) {
+        return Err("default_fee_rate must be above zero".into());
+    }
+    if args.max_fee_rate == Some(0) {
+        return Err("max_fee_rate must be above zero".into());
+    }
+    Ok(())
+}
+
+/// Applies `args` once they passed [`validate`], leaving the state as it
+/// was otherwise.
+pub fn update_config(state: &mut State, args: UpdateConfigArgs) -> Result<(), String> {
+    validate(&args)?;
+    if let Some(admins) = args.admins {
+        state.admins = admins;
+    }
+    if let Some(ckbtc_ledger) = args.ckbtc_ledger {
+        state.ckbtc_ledger = Some(ckbtc_ledger);
+    }
+    if let Some(ckbtc_minter) = args.ckbtc_minter {
+        state.ckbtc_minter = Some(ckbtc_minter);
+    }
+    // switching keys invalidates the cached root keys
+    if let Some(ecdsa_key) = args.ecdsa_key {
+        state.ecdsa_key = Some(ecdsa_key);
+        state.ecdsa_root_key = None;
+    }
+    if let Some(schnorr_key) = args.schnorr_key {
+        state.schnorr_key = Some(schnorr_key);
+        state.schnorr_root_key = None;
+    }
+    if let Some(schnorr_canister) = args.schnorr_canister {
+        state.schnorr_canister = Some(schnorr_canister);
+        state.schnorr_root_key = None;
+    }
+    if let Some(schnorr_backend) = args.schnorr_backend {
+        state.schnorr_backend = schnorr_backend;
+        state.schnorr_root_key = None;
+    }
+    if let Some(timer_for_reveal_txn) = args.timer_for_reveal_txn {
+        state.timer_for_reveal_txn = timer_for_reveal_txn;
+    }
+    if let Some(postage) = args.postage {
+        state.postage = postage;
+    }
+    if let Some(min_etching_balance) = args.min_etching_balance {
+        state.min_etching_balance = min_etching_balance;
+    }
+    if let Some(min_conversion_balance) = args.min_conversion_balance {
+        state.min_conversion_balance = min_conversion_balance;
+    }
+    if let Some(default_fee_rate) = args.default_fee_rate {
+        state.default_fee_rate = default_fee_rate;
+    }
+    if let Some(breaker_threshold) = args.breaker_threshold {
+        state.breaker_threshold = breaker_threshold;
+    }
+    if let Some(allowed_output_addresses) = args.allowed_output_addresses {
+        state.allowed_output_addresses = allowed_output_addresses;
+    }
+    if let Some(max_fee_rate) = args.max_fee_rate {
+        state.max_fee_rate = max_fee_rate;
+    }
+    if let Some(daily_spend_limit) = args.daily_spend_limit {
+        state.daily_spend_limit = daily_spend_limit;
+    }
+    if let Some(rebroadcast_interval) = args.rebroadcast_interval {
+        state.rebroadcast_interval = rebroadcast_interval;
+    }
+    if let Some(possibly_dropped_after) = args.possibly_dropped_after {
+        state.possibly_dropped_after = possibly_dropped_after;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_values_the_canister_cant_work_with() {
+        let mut state = State {
+            postage: DEFAULT_POSTAGE,
+            default_fee_rate: DEFAULT_FEE_RATE,
+            ..Default::default()
+        };
+        for (args, error) in [
+            (
+                UpdateConfigArgs {
+                    postage: Some(545),
+                    ..Default::default()
+                },
+                "postage of 545 is below the dust threshold of 546",
+            ),
+            (
+                UpdateConfigArgs {
+                    timer_for_reveal_txn: Some(0),
+                    ..Default::default()
+                },
+                "timer_for_reveal_txn must be at least one minute",
+            ),
+            (
+                UpdateConfigArgs {
+                    default_fee_rate: Some(0),
+                    ..Default::default()
+                },
+                "default_fee_rate must be above zero",
+            ),
+            (
+                UpdateConfigArgs {
+                    postage: Some(20_000),
+                    max_fee_rate: Some(0),
+                    ..Default::default()
+                },
+                "max_fee_rate must be above zero",
+            ),
+        ] {
+            assert_eq!(update_config(&mut state, args), Err(error.to_string()));
+        }
+        // nothing of a rejected update is written
+        assert_eq!(state.postage, DEFAULT_POSTAGE);
+
+        update_config(
+            &mut state,
+            UpdateConfigArgs {
+                postage: Some(MIN_POSTAGE),
+                default_fee_rate: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!((state.postage, state.default_fee_rate), (MIN_POSTAGE, 1));
+    }
+}
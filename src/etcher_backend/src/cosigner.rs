@@ -0,0 +1,697 @@
+//! Co-signs PSBTs built elsewhere with the keys derived for the caller, once
+//! they pass the signing policy of the config.
+
+use std::str::FromStr;
+
+use bitcoin::{
+    ecdsa,
+    hashes::{sha256, Hash},
+    psbt::{Input, PartiallySignedTransaction},
+    script::Instruction,
+    secp256k1::{ecdsa::Signature, schnorr, Message, PublicKey, Secp256k1, XOnlyPublicKey},
+    sighash::{EcdsaSighashType, SighashCache, TapSighashType},
+    taproot::{self, TapLeafHash},
+    Address, Network, OutPoint, Script, ScriptBuf, Transaction, TxOut,
+};
+use candid::Principal;
+use etcher_core::taproot_signing_data;
+use ordinals::{Artifact, Runestone};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ecdsa_api::EcdsaSigner,
+    schnorr_api::SchnorrSigner,
+    storage::{DAILY_SPENDS, RUNE_OUTPOINTS},
+    State,
+};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Sats spent by a caller through `sign_psbt` on `day`, counted in days
+/// since the epoch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DailySpend {
+    pub day: u64,
+    pub spent: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SignPolicy {
+    pub allowed_outputs: Vec<ScriptBuf>,
+    pub max_fee_rate: u64,
+    pub daily_spend_limit: u64,
+}
+
+impl SignPolicy {
+    pub fn from_state(state: &State, network: Network) -> Result<Self, String> {
+        let allowed_outputs = state
+            .allowed_output_addresses
+            .iter()
+            .map(|address| {
+                Address::from_str(address)
+                    .and_then(|address| address.require_network(network))
+                    .map(|address| address.script_pubkey())
+                    .map_err(|e| format!("Invalid allowed output address {}: {}", address, e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            allowed_outputs,
+            max_fee_rate: state.max_fee_rate,
+            daily_spend_limit: state.daily_spend_limit,
+        })
+    }
+}
+
+/// The keys derived for a caller. Outputs paying to the P2PKH or P2WPKH
+/// address of the ECDSA key belong to the caller, as do taproot script
+/// path spends through a leaf that checks the schnorr key.
+#[derive(Debug, Clone)]
+pub struct CallerKeys {
+    pub ecdsa_public_key: bitcoin::PublicKey,
+    pub schnorr_public_key: XOnlyPublicKey,
+}
+
+impl CallerKeys {
+    pub fn new(ecdsa_public_key: &[u8], schnorr_public_key: &[u8]) -> Result<Self, String> {
+        Ok(Self {
+            ecdsa_public_key: bitcoin::PublicKey::from_slice(ecdsa_public_key)
+                .map_err(|e| e.to_string())?,
+            schnorr_public_key: PublicKey::from_slice(schnorr_public_key)
+                .map_err(|e| e.to_string())?
+                .into(),
+        })
+    }
+
+    fn p2pkh_script(&self) -> ScriptBuf {
+        ScriptBuf::new_p2pkh(&self.ecdsa_public_key.pubkey_hash())
+    }
+
+    fn p2wpkh_script(&self) -> ScriptBuf {
+        ScriptBuf::new_v0_p2wpkh(&self.ecdsa_public_key.wpubkey_hash().unwrap())
+    }
+
+    pub fn owns(&self, script_pubkey: &Script) -> bool {
+        *script_pubkey == self.p2pkh_script() || *script_pubkey == self.p2wpkh_script()
+    }
+
+    fn checked_by_leaf(&self, leaf_script: &Script) -> bool {
+        let key = self.schnorr_public_key.serialize();
+        leaf_script.instructions().any(|instruction| {
+            matches!(instruction, Ok(Instruction::PushBytes(bytes)) if bytes.as_bytes() == key)
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum OwnedInput {
+    P2pkh,
+    P2wpkh,
+    TapLeaves(Vec<TapLeafHash>),
+}
+
+fn owned_input(keys: &CallerKeys, input: &Input, prevout: &TxOut) -> Option<OwnedInput> {
+    if prevout.script_pubkey == keys.p2pkh_script() {
+        return Some(OwnedInput::P2pkh);
+    }
+    if prevout.script_pubkey == keys.p2wpkh_script() {
+        return Some(OwnedInput::P2wpkh);
+    }
+    if !prevout.script_pubkey.is_v1_p2tr() {
+        return None;
+    }
+    let leaves: Vec<_> = input
+        .tap_scripts
+        .values()
+        .filter(|(script, _)| keys.checked_by_leaf(script))
+        .map(|(script, version)| TapLeafHash::from_script(script, *version))
+        .collect();
+    (!leaves.is_empty()).then_some(OwnedInput::TapLeaves(leaves))
+}
+
+/// The output spent by every input, taken from the previous transaction in
+/// `non_witness_utxo` or from `witness_utxo`. Only segwit outputs are taken
+/// from `witness_utxo`, as a legacy sighash doesn't commit to the amount
+/// spent, which could be understated otherwise.
+pub fn prevouts(psbt: &PartiallySignedTransaction) -> Result<Vec<TxOut>, String> {
+    psbt.unsigned_tx
+        .input
+        .iter()
+        .zip(psbt.inputs.iter())
+        .enumerate()
+        .map(|(index, (txin, input))| {
+            if let Some(tx) = &input.non_witness_utxo {
+                if tx.txid() != txin.previous_output.txid {
+                    return Err(format!(
+                        "Input {} doesn't spend its previous transaction",
+                        index
+                    ));
+                }
+                return tx
+                    .output
+                    .get(txin.previous_output.vout as usize)
+                    .cloned()
+                    .ok_or(format!("Input {} is missing its utxo", index));
+            }
+            match &input.witness_utxo {
+                Some(utxo) if utxo.script_pubkey.is_witness_program() => Ok(utxo.clone()),
+                Some(_) => Err(format!(
+                    "Input {} spends a non-segwit output without its previous transaction",
+                    index
+                )),
+                None => Err(format!("Input {} is missing its utxo", index)),
+            }
+        })
+        .collect()
+}
+
+/// Weight of `tx` once every input is signed, for the inputs whose kind of
+/// witness or script sig is known.
+fn estimated_signed_weight(tx: &Transaction, prevouts: &[TxOut]) -> u64 {
+    let mut weight = tx.weight().to_wu();
+    let mut segwit = false;
+    for prevout in prevouts {
+        let script_pubkey = &prevout.script_pubkey;
+        if script_pubkey.is_p2pkh() {
+            // signature and public key pushes
+            weight += 4 * (1 + 72 + 1 + 33);
+        } else if script_pubkey.is_v0_p2wpkh() {
+            weight += 1 + 1 + 72 + 1 + 33;
+            segwit = true;
+        } else if script_pubkey.is_v1_p2tr() {
+            weight += 1 + 1 + 65;
+            segwit = true;
+        }
+    }
+    if segwit {
+        // marker and flag
+        weight += 2;
+    }
+    weight
+}
+
+/// Checks `psbt` against `policy` and returns how many of the caller's sats
+/// it spends, i.e. what leaves the caller's inputs for someone else or for
+/// the fee.
+pub fn check_policy(
+    psbt: &PartiallySignedTransaction,
+    keys: &CallerKeys,
+    policy: &SignPolicy,
+    spent_today: u64,
+) -> Result<u64, String> {
+    let prevouts = prevouts(psbt)?;
+    let tx = &psbt.unsigned_tx;
+    let mut owned_value = 0;
+    for (index, (input, prevout)) in psbt.inputs.iter().zip(prevouts.iter()).enumerate() {
+        if owned_input(keys, input, prevout).is_none() {
+            continue;
+        }
+        if is_rune_outpoint(&tx.input[index].previous_output) {
+            return Err(format!("Input {} holds runes", index));
+        }
+        // anything but signing every output would let the outputs be
+        // changed after the policy was checked
+        let sighash_type = input.sighash_type.map(|sighash_type| sighash_type.to_u32());
+        if !matches!(
+            sighash_type,
+            None | Some(0x00) | Some(0x01) // Default, All
+        ) {
+            return Err(format!("Input {} doesn't sign every output", index));
+        }
+        owned_value += prevout.value;
+    }
+    if owned_value == 0 {
+        return Err("No input belongs to the caller".into());
+    }
+
+    let mut returned_value = 0;
+    for (index, output) in tx.output.iter().enumerate() {
+        if keys.owns(&output.script_pubkey) {
+            returned_value += output.value;
+        } else if !output.script_pubkey.is_op_return()
+            && !policy.allowed_outputs.contains(&output.script_pubkey)
+        {
+            return Err(format!(
+                "Output {} pays to an address that isn't allowed",
+                index
+            ));
+        }
+    }
+
+    let input_value: u64 = prevouts.iter().map(|prevout| prevout.value).sum();
+    let output_value: u64 = tx.output.iter().map(|output| output.value).sum();
+    let fee = input_value
+        .checked_sub(output_value)
+        .ok_or("Outputs exceed inputs")?;
+    let vsize = estimated_signed_weight(tx, &prevouts).div_ceil(4);
+    if fee > policy.max_fee_rate * vsize {
+        return Err(format!(
+            "Fee rate of {} sat/vB exceeds {} sat/vB",
+            fee / vsize,
+            policy.max_fee_rate
+        ));
+    }
+
+    let spend = owned_value.saturating_sub(returned_value);
+    if spent_today + spend > policy.daily_spend_limit {
+        return Err(format!(
+            "Spending {} sats exceeds the daily limit, {} sats are left for today",
+            spend,
+            policy.daily_spend_limit.saturating_sub(spent_today)
+        ));
+    }
+    Ok(spend)
+}
+
+/// Adds the caller's signature to every input that belongs to the caller and
+/// returns how many signatures were added. The PSBT has to have passed
+/// [`check_policy`].
+pub async fn sign_psbt(
+    ecdsa_signer: &impl EcdsaSigner,
+    schnorr_signer: &impl SchnorrSigner,
+    derivation_path: &[Vec<u8>],
+    keys: &CallerKeys,
+    psbt: &mut PartiallySignedTransaction,
+) -> Result<usize, String> {
+    let prevouts = prevouts(psbt)?;
+    let secp = Secp256k1::verification_only();
+    let mut signed = 0;
+    for index in 0..psbt.inputs.len() {
+        let Some(owned) = owned_input(keys, &psbt.inputs[index], &prevouts[index]) else {
+            continue;
+        };
+        let mut cache = SighashCache::new(&psbt.unsigned_tx);
+        match owned {
+            OwnedInput::P2pkh | OwnedInput::P2wpkh => {
+                let sighash_type = EcdsaSighashType::All;
+                let sighash = if owned == OwnedInput::P2pkh {
+                    cache
+                        .legacy_signature_hash(
+                            index,
+                            &prevouts[index].script_pubkey,
+                            sighash_type.to_u32(),
+                        )
+                        .map_err(|e| e.to_string())?
+                        .to_byte_array()
+                } else {
+                    cache
+                        .segwit_signature_hash(
+                            index,
+                            &keys.p2pkh_script(),
+                            prevouts[index].value,
+                            sighash_type,
+                        )
+                        .map_err(|e| e.to_string())?
+                        .to_byte_array()
+                };
+                let signature = ecdsa_signer
+                    .sign(sighash.to_vec(), derivation_path.to_vec())
+                    .await;
+                let mut signature = Signature::from_compact(&signature)
+                    .map_err(|e| format!("Invalid ecdsa signature: {}", e))?;
+                signature.normalize_s();
+                secp.verify_ecdsa(
+                    &Message::from_slice(&sighash).unwrap(),
+                    &signature,
+                    &keys.ecdsa_public_key.inner,
+                )
+                .map_err(|_| "ECDSA signature doesn't match the sighash")?;
+                psbt.inputs[index].partial_sigs.insert(
+                    keys.ecdsa_public_key,
+                    ecdsa::Signature {
+                        sig: signature,
+                        hash_ty: sighash_type,
+                    },
+                );
+            }
+            OwnedInput::TapLeaves(leaves) => {
+                let sighash_type = psbt.inputs[index]
+                    .sighash_type
+                    .map(|sighash_type| sighash_type.taproot_hash_ty())
+                    .transpose()
+                    .map_err(|e| e.to_string())?
+                    .unwrap_or(TapSighashType::Default);
+                for leaf_hash in leaves {
+                    let signing_data = taproot_signing_data(
+                        &psbt.unsigned_tx,
+                        index,
+                        &prevouts,
+                        Some(leaf_hash),
+                        sighash_type,
+                    )?;
+                    let sighash = sha256::Hash::hash(&signing_data);
+                    let signature = schnorr_signer
+                        .sign(signing_data, derivation_path.to_vec())
+                        .await;
+                    let signature = schnorr::Signature::from_slice(&signature)
+                        .map_err(|e| format!("Invalid schnorr signature: {}", e))?;
+                    secp.verify_schnorr(
+                        &signature,
+                        &Message::from_slice(sighash.as_ref()).unwrap(),
+                        &keys.schnorr_public_key,
+                    )
+                    .map_err(|_| "Schnorr signature doesn't match the sighash")?;
+                    psbt.inputs[index].tap_script_sigs.insert(
+                        (keys.schnorr_public_key, leaf_hash),
+                        taproot::Signature {
+                            sig: signature,
+                            hash_ty: sighash_type,
+                        },
+                    );
+                }
+            }
+        }
+        signed += 1;
+    }
+    Ok(signed)
+}
+
+/// Remembers the output an etching in `tx` puts its premine in, so that
+/// `sign_psbt` never spends it.
+pub fn remember_rune_outpoints(tx: &Transaction) {
+    let Some(Artifact::Runestone(runestone)) = Runestone::decipher(tx) else {
+        return;
+    };
+    let premine = runestone
+        .etching
+        .and_then(|etching| etching.premine)
+        .unwrap_or_default();
+    if premine == 0 {
+        return;
+    }
+    let vout = runestone.pointer.or_else(|| {
+        tx.output
+            .iter()
+            .position(|output| !output.script_pubkey.is_op_return())
+            .map(|vout| vout as u32)
+    });
+    if let Some(vout) = vout {
+        RUNE_OUTPOINTS
+            .with_borrow_mut(|outpoints| outpoints.insert((tx.txid().to_byte_array(), vout), ()));
+    }
+}
+
+pub fn is_rune_outpoint(outpoint: &OutPoint) -> bool {
+    RUNE_OUTPOINTS.with_borrow(|outpoints| {
+        outpoints.contains_key(&(outpoint.txid.to_byte_array(), outpoint.vout))
+    })
+}
+
+pub fn spent_today(caller: &Principal, now: u64) -> u64 {
+    DAILY_SPENDS
+        .with_borrow(|spends| spends.get(caller))
+        .filter(|spend| spend.day == now / NANOS_PER_DAY)
+        .map(|spend| spend.spent)
+        .unwrap_or_default()
+}
+
+pub fn record_spend(caller: &Principal, now: u64, spent: u64) {
+    let spent_today = spent_today(caller, now);
+    DAILY_SPENDS.with_borrow_mut(|spends| {
+        spends.insert(
+            *caller,
+            DailySpend {
+                day: now / NANOS_PER_DAY,
+                spent: spent_today + spent,
+            },
+        )
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        absolute::LockTime,
+        opcodes,
+        psbt::PsbtSighashType,
+        script::Builder,
+        taproot::{LeafVersion, TaprootBuilder},
+        Sequence, TxIn, Txid, Witness,
+    };
+
+    use super::*;
+    use crate::{
+        ecdsa_api::EcdsaSigner,
+        mock::{block_on, MockEcdsaSigner, MockSchnorrSigner},
+    };
+
+    const ALLOWED: &str = "bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x";
+
+    struct Cosigner {
+        ecdsa_signer: MockEcdsaSigner,
+        schnorr_signer: MockSchnorrSigner,
+        derivation_path: Vec<Vec<u8>>,
+        keys: CallerKeys,
+    }
+
+    fn cosigner() -> Cosigner {
+        let ecdsa_signer = MockEcdsaSigner::new([1; 32]);
+        let schnorr_signer = MockSchnorrSigner::new([2; 32]);
+        let derivation_path = vec![vec![9; 32]];
+        let keys = CallerKeys::new(
+            &block_on(EcdsaSigner::public_key(
+                &ecdsa_signer,
+                derivation_path.clone(),
+            )),
+            &block_on(SchnorrSigner::public_key(
+                &schnorr_signer,
+                derivation_path.clone(),
+            )),
+        )
+        .unwrap();
+        Cosigner {
+            ecdsa_signer,
+            schnorr_signer,
+            derivation_path,
+            keys,
+        }
+    }
+
+    fn policy() -> SignPolicy {
+        SignPolicy {
+            allowed_outputs: vec![allowed_script()],
+            max_fee_rate: 50,
+            daily_spend_limit: 100_000,
+        }
+    }
+
+    fn allowed_script() -> ScriptBuf {
+        Address::from_str(ALLOWED)
+            .unwrap()
+            .assume_checked()
+            .script_pubkey()
+    }
+
+    fn outpoint(vout: u32) -> OutPoint {
+        OutPoint::new(Txid::hash(b"wallet"), vout)
+    }
+
+    /// Spends 100_000 sats of the caller's P2WPKH address.
+    fn psbt(keys: &CallerKeys, outputs: Vec<TxOut>) -> PartiallySignedTransaction {
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: outpoint(0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }],
+            output: outputs,
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 100_000,
+            script_pubkey: keys.p2wpkh_script(),
+        });
+        psbt
+    }
+
+    fn payment(keys: &CallerKeys, amount: u64, fee: u64) -> PartiallySignedTransaction {
+        psbt(
+            keys,
+            vec![
+                TxOut {
+                    value: amount,
+                    script_pubkey: allowed_script(),
+                },
+                TxOut {
+                    value: 100_000 - amount - fee,
+                    script_pubkey: keys.p2wpkh_script(),
+                },
+            ],
+        )
+    }
+
+    #[test]
+    fn signs_a_payment_within_the_policy() {
+        let cosigner = cosigner();
+        let mut psbt = payment(&cosigner.keys, 30_000, 1_000);
+        assert_eq!(
+            check_policy(&psbt, &cosigner.keys, &policy(), 0),
+            Ok(31_000)
+        );
+        let signed = block_on(sign_psbt(
+            &cosigner.ecdsa_signer,
+            &cosigner.schnorr_signer,
+            &cosigner.derivation_path,
+            &cosigner.keys,
+            &mut psbt,
+        ));
+        assert_eq!(signed, Ok(1));
+        let funding_address =
+            Address::from_script(&cosigner.keys.p2wpkh_script(), Network::Regtest).unwrap();
+        // checks the signature against the sighash
+        etcher_core::finalize_funding_psbt(psbt, &funding_address).unwrap();
+    }
+
+    #[test]
+    fn enforces_the_policy() {
+        let cosigner = cosigner();
+        let keys = &cosigner.keys;
+
+        let mut psbt = payment(keys, 30_000, 1_000);
+        psbt.unsigned_tx.output[0].script_pubkey = ScriptBuf::new_p2pkh(
+            &bitcoin::PublicKey::from_slice(&[2; 33])
+                .unwrap()
+                .pubkey_hash(),
+        );
+        assert!(check_policy(&psbt, keys, &policy(), 0)
+            .unwrap_err()
+            .contains("isn't allowed"));
+
+        let psbt = payment(keys, 30_000, 20_000);
+        assert!(check_policy(&psbt, keys, &policy(), 0)
+            .unwrap_err()
+            .contains("exceeds 50 sat/vB"));
+
+        let psbt = payment(keys, 30_000, 1_000);
+        assert!(check_policy(&psbt, keys, &policy(), 80_000)
+            .unwrap_err()
+            .contains("daily limit"));
+
+        let mut psbt = payment(keys, 30_000, 1_000);
+        psbt.inputs[0].sighash_type = Some(PsbtSighashType::from(EcdsaSighashType::None));
+        assert!(check_policy(&psbt, keys, &policy(), 0)
+            .unwrap_err()
+            .contains("doesn't sign every output"));
+
+        let mut psbt = payment(keys, 30_000, 1_000);
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 100_000,
+            script_pubkey: allowed_script(),
+        });
+        assert_eq!(
+            check_policy(&psbt, keys, &policy(), 0),
+            Err("No input belongs to the caller".to_string())
+        );
+    }
+
+    #[test]
+    fn takes_legacy_amounts_from_the_previous_transaction() {
+        let cosigner = cosigner();
+        let keys = &cosigner.keys;
+        let previous_tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: keys.p2pkh_script(),
+            }],
+        };
+        // pays 30_000 sats and claims the input to be worth 31_000, which
+        // hides the 69_000 sats that would go to the miners otherwise
+        let mut psbt = psbt(
+            keys,
+            vec![TxOut {
+                value: 30_000,
+                script_pubkey: allowed_script(),
+            }],
+        );
+        psbt.unsigned_tx.input[0].previous_output = OutPoint::new(previous_tx.txid(), 0);
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 31_000,
+            script_pubkey: keys.p2pkh_script(),
+        });
+        assert_eq!(
+            check_policy(&psbt, keys, &policy(), 0),
+            Err("Input 0 spends a non-segwit output without its previous transaction".to_string())
+        );
+
+        psbt.inputs[0].non_witness_utxo = Some(payment(keys, 30_000, 1_000).unsigned_tx);
+        assert_eq!(
+            check_policy(&psbt, keys, &policy(), 0),
+            Err("Input 0 doesn't spend its previous transaction".to_string())
+        );
+
+        psbt.inputs[0].non_witness_utxo = Some(previous_tx);
+        assert!(check_policy(&psbt, keys, &policy(), 0)
+            .unwrap_err()
+            .contains("exceeds 50 sat/vB"));
+    }
+
+    #[test]
+    fn refuses_to_spend_runes() {
+        let cosigner = cosigner();
+        let psbt = payment(&cosigner.keys, 30_000, 1_000);
+        RUNE_OUTPOINTS.with_borrow_mut(|outpoints| {
+            outpoints.insert((outpoint(0).txid.to_byte_array(), 0), ())
+        });
+        assert_eq!(
+            check_policy(&psbt, &cosigner.keys, &policy(), 0),
+            Err("Input 0 holds runes".to_string())
+        );
+        RUNE_OUTPOINTS
+            .with_borrow_mut(|outpoints| outpoints.remove(&(outpoint(0).txid.to_byte_array(), 0)));
+    }
+
+    #[test]
+    fn signs_taproot_script_paths_with_the_schnorr_key() {
+        let cosigner = cosigner();
+        let keys = &cosigner.keys;
+        let secp = Secp256k1::new();
+        let leaf_script = Builder::new()
+            .push_slice(keys.schnorr_public_key.serialize())
+            .push_opcode(opcodes::all::OP_CHECKSIG)
+            .into_script();
+        let spend_info = TaprootBuilder::new()
+            .add_leaf(0, leaf_script.clone())
+            .unwrap()
+            .finalize(&secp, keys.schnorr_public_key)
+            .unwrap();
+        let control_block = spend_info
+            .control_block(&(leaf_script.clone(), LeafVersion::TapScript))
+            .unwrap();
+        let mut psbt = payment(keys, 30_000, 1_000);
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 100_000,
+            script_pubkey: ScriptBuf::new_v1_p2tr_tweaked(spend_info.output_key()),
+        });
+        psbt.inputs[0]
+            .tap_scripts
+            .insert(control_block, (leaf_script.clone(), LeafVersion::TapScript));
+        assert_eq!(check_policy(&psbt, keys, &policy(), 0), Ok(31_000));
+        let signed = block_on(sign_psbt(
+            &cosigner.ecdsa_signer,
+            &cosigner.schnorr_signer,
+            &cosigner.derivation_path,
+            keys,
+            &mut psbt,
+        ));
+        assert_eq!(signed, Ok(1));
+        let leaf_hash = TapLeafHash::from_script(&leaf_script, LeafVersion::TapScript);
+        assert!(psbt.inputs[0]
+            .tap_script_sigs
+            .contains_key(&(keys.schnorr_public_key, leaf_hash)));
+    }
+
+    #[test]
+    fn tracks_spends_per_day() {
+        let caller = Principal::anonymous();
+        let day = 19_000 * NANOS_PER_DAY;
+        record_spend(&caller, day, 10_000);
+        record_spend(&caller, day + 1, 5_000);
+        assert_eq!(spent_today(&caller, day + 2), 15_000);
+        assert_eq!(spent_today(&caller, day + NANOS_PER_DAY), 0);
+    }
+}
@@ -1,23 +1,57 @@
-use crate::STATE;
+use crate::{circuit_breaker::FailureGuard, key_derivation::RootKey, metrics, STATE};
 
-pub async fn get_ecdsa_public_key(derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+/// The canister's root key, fetched once and cached in the state.
+pub async fn get_ecdsa_root_key() -> RootKey {
+    if let Some(root_key) = STATE.with_borrow(|state| state.ecdsa_root_key.clone()) {
+        return root_key;
+    }
     let key_id = STATE.with_borrow(|state| state.ecdsa_key.as_ref().unwrap().to_key_id());
-    ic_cdk::api::management_canister::ecdsa::ecdsa_public_key(
+    let response = ic_cdk::api::management_canister::ecdsa::ecdsa_public_key(
         ic_cdk::api::management_canister::ecdsa::EcdsaPublicKeyArgument {
             canister_id: None,
-            derivation_path,
-            key_id,
+            derivation_path: vec![],
+            key_id: key_id.clone(),
         },
     )
     .await
     .unwrap()
-    .0
-    .public_key
+    .0;
+    let root_key = RootKey {
+        public_key: response.public_key,
+        chain_code: response.chain_code,
+    };
+    STATE.with_borrow_mut(|state| {
+        // the key may have been switched while fetching
+        if state.ecdsa_key.as_ref().unwrap().to_key_id() == key_id {
+            state.ecdsa_root_key = Some(root_key.clone());
+        }
+    });
+    root_key
+}
+
+pub async fn get_ecdsa_public_key(derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+    get_ecdsa_root_key()
+        .await
+        .derive_public_key(&derivation_path)
+        .unwrap_or_else(|e| ic_cdk::trap(&e))
+}
+
+/// Derives the public key from the cached root key, for queries.
+pub fn cached_ecdsa_public_key(derivation_path: &[Vec<u8>]) -> Option<Vec<u8>> {
+    STATE
+        .with_borrow(|state| state.ecdsa_root_key.clone())
+        .map(|root_key| {
+            root_key
+                .derive_public_key(derivation_path)
+                .unwrap_or_else(|e| ic_cdk::trap(&e))
+        })
 }
 
 pub async fn ecdsa_sign(message_hash: Vec<u8>, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
     let key_id = STATE.with_borrow(|state| state.ecdsa_key.as_ref().unwrap().to_key_id());
-    ic_cdk::api::management_canister::ecdsa::sign_with_ecdsa(
+    metrics::record_ecdsa_sign_call();
+    let failure_guard = FailureGuard::new("sign_with_ecdsa");
+    let signature = ic_cdk::api::management_canister::ecdsa::sign_with_ecdsa(
         ic_cdk::api::management_canister::ecdsa::SignWithEcdsaArgument {
             message_hash,
             derivation_path,
@@ -27,5 +61,31 @@ pub async fn ecdsa_sign(message_hash: Vec<u8>, derivation_path: Vec<Vec<u8>>) ->
     .await
     .unwrap()
     .0
-    .signature
+    .signature;
+    failure_guard.succeeded();
+    signature
+}
+
+// canister futures never leave the thread they were created on, so they
+// don't need to be `Send`
+#[allow(async_fn_in_trait)]
+pub trait EcdsaSigner {
+    async fn public_key(&self, derivation_path: Vec<Vec<u8>>) -> Vec<u8>;
+
+    /// Returns the 64 byte compact signature of `message_hash`.
+    async fn sign(&self, message_hash: Vec<u8>, derivation_path: Vec<Vec<u8>>) -> Vec<u8>;
+}
+
+/// Signs with the threshold ECDSA key of the management canister.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IcEcdsaSigner;
+
+impl EcdsaSigner for IcEcdsaSigner {
+    async fn public_key(&self, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+        get_ecdsa_public_key(derivation_path).await
+    }
+
+    async fn sign(&self, message_hash: Vec<u8>, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+        ecdsa_sign(message_hash, derivation_path).await
+    }
 }
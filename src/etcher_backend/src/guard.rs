@@ -0,0 +1,48 @@
+use std::fmt::Display;
+
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+use crate::STATE;
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum GuardError {
+    AlreadyProcessing,
+}
+
+impl Display for GuardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AlreadyProcessing => write!(
+                f,
+                "Another request of this principal is already being processed"
+            ),
+        }
+    }
+}
+
+/// Marks `principal` as busy for as long as the guard lives, so the same
+/// principal can't run a second guarded call concurrently. The principal is
+/// released on drop, which also happens when the call traps after an await.
+#[derive(Debug)]
+#[must_use]
+pub struct CallerGuard {
+    principal: Principal,
+}
+
+impl CallerGuard {
+    pub fn new(principal: Principal) -> Result<Self, GuardError> {
+        STATE.with_borrow_mut(|state| {
+            if !state.principal_guards.insert(principal) {
+                return Err(GuardError::AlreadyProcessing);
+            }
+            Ok(Self { principal })
+        })
+    }
+}
+
+impl Drop for CallerGuard {
+    fn drop(&mut self) {
+        STATE.with_borrow_mut(|state| state.principal_guards.remove(&self.principal));
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt::Write;
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    circuit_breaker, metrics, scheduled_etching, storage::ETCHING_HISTORY, storage::REVEAL_QUEUE,
+};
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn ok(content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            status_code: 200,
+            headers: vec![
+                ("Content-Type".to_string(), content_type.to_string()),
+                ("Content-Length".to_string(), body.len().to_string()),
+            ],
+            body,
+        }
+    }
+
+    fn not_found() -> Self {
+        Self {
+            status_code: 404,
+            headers: vec![],
+            body: b"Not found".to_vec(),
+        }
+    }
+}
+
+pub fn http_request(req: HttpRequest) -> HttpResponse {
+    let path = req.url.split('?').next().unwrap_or_default();
+    match path {
+        "/metrics" => HttpResponse::ok("text/plain; version=0.0.4", encode_metrics().into_bytes()),
+        "/status" => HttpResponse::ok("application/json", serde_json::to_vec(&status()).unwrap()),
+        _ => HttpResponse::not_found(),
+    }
+}
+
+fn encode_metrics() -> String {
+    let mut out = String::new();
+    let mut gauge = |name: &str, help: &str, value: String| {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} gauge", name).unwrap();
+        writeln!(out, "{} {}", name, value).unwrap();
+    };
+    gauge(
+        "etcher_queued_reveals",
+        "Reveal transactions waiting for their commit to confirm.",
+        REVEAL_QUEUE.with_borrow(|queue| queue.len()).to_string(),
+    );
+    gauge(
+        "etcher_cycles_balance",
+        "Cycles balance of the canister.",
+        ic_cdk::api::canister_balance128().to_string(),
+    );
+    gauge(
+        "etcher_stable_memory_bytes",
+        "Size of the stable memory in bytes.",
+        (ic_cdk::api::stable::stable64_size() * 65536).to_string(),
+    );
+    gauge(
+        "etcher_operating_mode",
+        "0 when running, 1 when new operations are paused, 2 when read only.",
+        (circuit_breaker::get_operating_mode() as u8).to_string(),
+    );
+
+    let (queued, revealed) = (
+        REVEAL_QUEUE.with_borrow(|queue| queue.len()),
+        ETCHING_HISTORY.with_borrow(|history| history.len()),
+    );
+    writeln!(out, "# HELP etcher_jobs Etching jobs by state.").unwrap();
+    writeln!(out, "# TYPE etcher_jobs gauge").unwrap();
+    writeln!(out, "etcher_jobs{{state=\"queued\"}} {}", queued).unwrap();
+    writeln!(out, "etcher_jobs{{state=\"revealed\"}} {}", revealed).unwrap();
+
+    metrics::with_metrics(|metrics| {
+        writeln!(out, "# HELP etcher_sign_calls_total Signing calls made.").unwrap();
+        writeln!(out, "# TYPE etcher_sign_calls_total counter").unwrap();
+        writeln!(
+            out,
+            "etcher_sign_calls_total{{scheme=\"ecdsa\"}} {}",
+            metrics.ecdsa_sign_calls
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "etcher_sign_calls_total{{scheme=\"schnorr\"}} {}",
+            metrics.schnorr_sign_calls
+        )
+        .unwrap();
+
+        writeln!(
+            out,
+            "# HELP etcher_failures_total Failed signing and broadcasting calls."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE etcher_failures_total counter").unwrap();
+        for (operation, count) in &metrics.failures {
+            writeln!(
+                out,
+                "etcher_failures_total{{operation=\"{}\"}} {}",
+                operation, count
+            )
+            .unwrap();
+        }
+
+        if let Some(height) = metrics.last_tip_height {
+            writeln!(
+                out,
+                "# HELP etcher_last_tip_height Highest tip height seen from the bitcoin api."
+            )
+            .unwrap();
+            writeln!(out, "# TYPE etcher_last_tip_height gauge").unwrap();
+            writeln!(out, "etcher_last_tip_height {}", height).unwrap();
+        }
+    });
+    out
+}
+
+#[derive(Serialize, Debug)]
+struct QueuedReveal {
+    id: u128,
+    commit_tx_address: String,
+    reveal_txid: String,
+}
+
+#[derive(Serialize, Debug)]
+struct ScheduledEtching {
+    id: u64,
+    rune: String,
+    target_height: u32,
+    commit_height: u32,
+}
+
+#[derive(Serialize, Debug)]
+struct Status {
+    operating_mode: circuit_breaker::OperatingMode,
+    queued_reveals: Vec<QueuedReveal>,
+    scheduled_etchings: Vec<ScheduledEtching>,
+    revealed: u64,
+}
+
+fn status() -> Status {
+    let queued_reveals = REVEAL_QUEUE.with_borrow(|queue| {
+        queue
+            .iter()
+            .map(|(id, queued)| QueuedReveal {
+                id,
+                commit_tx_address: queued.commit_tx_address,
+                reveal_txid: queued.reveal_txn.txid().to_string(),
+            })
+            .collect()
+    });
+    let scheduled_etchings = scheduled_etching::waiting()
+        .into_iter()
+        .map(|(id, etching)| ScheduledEtching {
+            id,
+            rune: etching.args.rune,
+            target_height: etching.target_height,
+            commit_height: etching.commit_height,
+        })
+        .collect();
+    Status {
+        operating_mode: circuit_breaker::get_operating_mode(),
+        queued_reveals,
+        scheduled_etchings,
+        revealed: ETCHING_HISTORY.with_borrow(|history| history.len()),
+    }
+}
@@ -0,0 +1,143 @@
+use std::{cell::RefCell, ops::Bound};
+
+use candid::{CandidType, Principal};
+use ic_stable_structures::StableBTreeMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{get_request_outcome_memory, psbt_etching::EtchingPsbt, Memory};
+
+/// How long the outcome of a request stays available for retries.
+pub const REQUEST_RETENTION_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// Outcomes checked for expiry per recorded one, enough to keep up with the
+/// outcomes expiring while new ones are recorded.
+const EXPIRY_BATCH: usize = 8;
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub enum RequestOutcome {
+    EtchRune((String, String)),
+    EtchRunes(Vec<Result<(String, String), String>>),
+    ConfirmAndConvertCkbtc(u64),
+    EtchRuneWithPsbt(EtchingPsbt),
+    SubmitEtchingPsbt((String, String)),
+    ScheduleEtching(u64),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct StoredOutcome {
+    pub outcome: RequestOutcome,
+    pub recorded_at: u64,
+}
+
+thread_local! {
+    static REQUEST_OUTCOMES: RefCell<StableBTreeMap<String, StoredOutcome, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_request_outcome_memory()));
+    // key the next expiry sweep continues after, from the first key if none
+    static EXPIRY_CURSOR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// request ids are chosen by the clients, so they are scoped by the caller
+fn request_key(caller: &Principal, request_id: &str) -> String {
+    format!("{}:{}", caller, request_id)
+}
+
+/// Returns the recorded outcome of `request_id`, if it was completed within
+/// the retention window.
+pub fn get_outcome(caller: &Principal, request_id: &str) -> Option<RequestOutcome> {
+    let now = ic_cdk::api::time();
+    REQUEST_OUTCOMES.with_borrow(|outcomes| {
+        outcomes
+            .get(&request_key(caller, request_id))
+            .filter(|stored| now.saturating_sub(stored.recorded_at) < REQUEST_RETENTION_NANOS)
+            .map(|stored| stored.outcome)
+    })
+}
+
+/// Returns what the caller's earlier call with `request_id` returned, taken
+/// out of its outcome by `result`. Traps if the id was used for a call to
+/// another endpoint, for which `result` returns `None`.
+pub fn earlier_result<T>(
+    request_id: Option<&str>,
+    result: impl FnOnce(RequestOutcome) -> Option<T>,
+) -> Option<T> {
+    let outcome = get_outcome(&ic_cdk::caller(), request_id?)?;
+    Some(
+        result(outcome)
+            .unwrap_or_else(|| ic_cdk::trap("Request id was already used for a different call")),
+    )
+}
+
+/// Records the outcome of `request_id` and drops the expired outcomes among
+/// the next few in line.
+pub fn record_outcome(caller: &Principal, request_id: &str, outcome: RequestOutcome) {
+    record(
+        request_key(caller, request_id),
+        outcome,
+        ic_cdk::api::time(),
+    );
+}
+
+fn record(key: String, outcome: RequestOutcome, now: u64) {
+    REQUEST_OUTCOMES.with_borrow_mut(|outcomes| {
+        let cursor = EXPIRY_CURSOR.take();
+        let batch: Vec<(String, StoredOutcome)> = match cursor {
+            Some(cursor) => outcomes
+                .range((Bound::Excluded(cursor), Bound::Unbounded))
+                .take(EXPIRY_BATCH)
+                .collect(),
+            None => outcomes.iter().take(EXPIRY_BATCH).collect(),
+        };
+        // the sweep starts over once it reached the last key
+        if batch.len() == EXPIRY_BATCH {
+            EXPIRY_CURSOR.set(batch.last().map(|(key, _)| key.clone()));
+        }
+        for (key, stored) in batch {
+            if now.saturating_sub(stored.recorded_at) >= REQUEST_RETENTION_NANOS {
+                outcomes.remove(&key);
+            }
+        }
+        outcomes.insert(
+            key,
+            StoredOutcome {
+                outcome,
+                recorded_at: now,
+            },
+        );
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_outcomes_a_batch_at_a_time() {
+        for id in 0..20 {
+            record(
+                format!("old:{:02}", id),
+                RequestOutcome::ScheduleEtching(id),
+                0,
+            );
+        }
+        let len = || REQUEST_OUTCOMES.with_borrow(|outcomes| outcomes.len());
+        assert_eq!(len(), 20);
+
+        let now = REQUEST_RETENTION_NANOS;
+        record(
+            "new:00".to_string(),
+            RequestOutcome::ScheduleEtching(0),
+            now,
+        );
+        // the first batch went through the old outcomes recorded at zero
+        assert_eq!(len(), 20 - EXPIRY_BATCH as u64 + 1);
+        for id in 1..4 {
+            record(
+                format!("new:{:02}", id),
+                RequestOutcome::ScheduleEtching(id),
+                now,
+            );
+        }
+        assert_eq!(len(), 4);
+        assert!(REQUEST_OUTCOMES
+            .with_borrow(|outcomes| outcomes.iter().all(|(key, _)| key.starts_with("new:"))));
+    }
+}
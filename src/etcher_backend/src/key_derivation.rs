@@ -0,0 +1,156 @@
+//! Derives the public keys of derivation paths locally from the canister's
+//! root key, the same way the threshold key holders derive them, so that
+//! only the root key has to be fetched.
+
+use bitcoin::{
+    hashes::{hmac, sha512, Hash, HashEngine},
+    secp256k1::{PublicKey, Scalar, Secp256k1},
+};
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+/// The public key of the empty derivation path, i.e. of the canister
+/// itself, and its chain code.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RootKey {
+    pub public_key: Vec<u8>,
+    pub chain_code: Vec<u8>,
+}
+
+impl RootKey {
+    /// SEC1 compressed public key of `derivation_path`.
+    pub fn derive_public_key(&self, derivation_path: &[Vec<u8>]) -> Result<Vec<u8>, String> {
+        let mut public_key = PublicKey::from_slice(&self.public_key)
+            .map_err(|e| format!("Invalid root public key: {}", e))?;
+        let mut chain_code: [u8; 32] = self
+            .chain_code
+            .as_slice()
+            .try_into()
+            .map_err(|_| "Chain code must be 32 bytes")?;
+        for index in derivation_path {
+            (chain_code, public_key) = derive_child(&public_key, &chain_code, index);
+        }
+        Ok(public_key.serialize().to_vec())
+    }
+}
+
+// HMAC-SHA512 of the input and the index, keyed with the chain code. When
+// the left half isn't a valid scalar the derivation is retried with the
+// right half as input, like in SLIP-10.
+fn offset_and_chain_code(index: &[u8], input: &[u8], chain_code: &[u8; 32]) -> (Scalar, [u8; 32]) {
+    let mut engine = hmac::HmacEngine::<sha512::Hash>::new(chain_code);
+    engine.input(input);
+    engine.input(index);
+    let output = hmac::Hmac::<sha512::Hash>::from_engine(engine).to_byte_array();
+    let next_chain_code: [u8; 32] = output[32..].try_into().unwrap();
+    match Scalar::from_be_bytes(output[..32].try_into().unwrap()) {
+        Ok(offset) => (offset, next_chain_code),
+        Err(_) => {
+            let mut input = vec![0x01];
+            input.extend(next_chain_code);
+            offset_and_chain_code(index, &input, chain_code)
+        }
+    }
+}
+
+fn derive_child(
+    public_key: &PublicKey,
+    chain_code: &[u8; 32],
+    index: &[u8],
+) -> ([u8; 32], PublicKey) {
+    let secp = Secp256k1::verification_only();
+    let mut input = public_key.serialize().to_vec();
+    loop {
+        let (offset, next_chain_code) = offset_and_chain_code(index, &input, chain_code);
+        // only fails if the tweaked key is the point at infinity
+        if let Ok(child) = public_key.add_exp_tweak(&secp, &offset) {
+            return (next_chain_code, child);
+        }
+        input = vec![0x01];
+        input.extend(next_chain_code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::secp256k1::SecretKey;
+
+    use super::*;
+
+    fn root() -> (SecretKey, RootKey) {
+        let secret_key = SecretKey::from_slice(&[5; 32]).unwrap();
+        let root = RootKey {
+            public_key: secret_key
+                .public_key(&Secp256k1::new())
+                .serialize()
+                .to_vec(),
+            chain_code: vec![6; 32],
+        };
+        (secret_key, root)
+    }
+
+    // computed with `PublicKey::derive_subkey_with_chain_code` of the
+    // `ic-secp256k1` crate, which the IC derives the keys of canisters with
+    #[test]
+    fn derives_the_keys_the_ic_returns() {
+        let root = RootKey {
+            public_key: hex::decode(
+                "0362c0a046dacce86ddd0343c6d3c7c79c2208ba0d9c9cf24a6d046d21d21f90f7",
+            )
+            .unwrap(),
+            chain_code: vec![6; 32],
+        };
+        assert_eq!(
+            root.derive_public_key(&[vec![1, 2, 3], vec![], vec![4; 40]])
+                .unwrap(),
+            hex::decode("026373c6e91854987b866d31694699b5457f002f24ec164e3b8513303a9d5d14f8")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn empty_path_is_the_root_key() {
+        let (_, root) = root();
+        assert_eq!(root.derive_public_key(&[]).unwrap(), root.public_key);
+    }
+
+    #[test]
+    fn derives_the_key_of_the_tweaked_secret() {
+        let (secret_key, root) = root();
+        let index = vec![7; 32];
+        let chain_code: [u8; 32] = root.chain_code.clone().try_into().unwrap();
+        let (offset, _) = offset_and_chain_code(&index, &root.public_key, &chain_code);
+        let child_secret = secret_key.add_tweak(&offset).unwrap();
+        assert_eq!(
+            root.derive_public_key(&[index]).unwrap(),
+            child_secret
+                .public_key(&Secp256k1::new())
+                .serialize()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn derives_paths_one_index_at_a_time() {
+        let (_, root) = root();
+        let path = vec![vec![1, 2, 3], vec![], vec![4; 40]];
+        let chain_code: [u8; 32] = root.chain_code.clone().try_into().unwrap();
+        let (child_chain_code, child) = derive_child(
+            &PublicKey::from_slice(&root.public_key).unwrap(),
+            &chain_code,
+            &path[0],
+        );
+        let child = RootKey {
+            public_key: child.serialize().to_vec(),
+            chain_code: child_chain_code.to_vec(),
+        };
+        assert_eq!(
+            root.derive_public_key(&path).unwrap(),
+            child.derive_public_key(&path[1..]).unwrap()
+        );
+        assert_ne!(
+            root.derive_public_key(&path[..1]).unwrap(),
+            root.derive_public_key(&path[1..2]).unwrap()
+        );
+    }
+}
@@ -1,11 +1,13 @@
 #![warn(missing_debug_implementations)]
 
-use std::{cell::RefCell, collections::HashMap, time::Duration};
+use std::{cell::RefCell, collections::HashSet, str::FromStr, time::Duration};
 
-use bitcoin::Transaction;
-use btc_api::check_etching;
+use bitcoin::{psbt::PartiallySignedTransaction, Address, BlockHash, Transaction};
+use btc_api::{check_etching, prepare_reveal};
 use candid::{CandidType, Principal};
 use ckbtc_api::{CkBTC, CkBTCMinter};
+pub use etcher_core::EtchingArgs;
+use etcher_core::{finalize_funding_psbt, rune_name, RevealPlan, RuneMinimum, RuneName};
 use hex::ToHex;
 use ic_cdk::{
     api::management_canister::{
@@ -16,30 +18,66 @@ use ic_cdk::{
 };
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
-    writer::Writer,
-    DefaultMemoryImpl, Memory as _,
+    DefaultMemoryImpl,
 };
 use icrc_ledger_types::icrc1::account::Account;
-use ordinals::Runestone;
-use schnorr_api::SchnorrKeyId;
+use schnorr_api::{SchnorrBackend, SchnorrKeyId};
 use serde::{Deserialize, Serialize};
-use slotmap::{Key, KeyData};
 
 use crate::{
-    btc_api::build_and_sign_etching_transaction,
-    ecdsa_api::get_ecdsa_public_key,
-    schnorr_api::get_schnorr_public_key,
+    audit::{AuditEvent, AuditLogPage, AuditLogQuery, FailureAudit},
+    broadcast_tracker::BroadcastInfo,
+    btc_api::{
+        build_and_sign_etching_transaction, build_and_sign_etching_transactions,
+        build_etching_psbt, sign_reveal_transactions, IcBitcoinApi,
+    },
+    chain_tracker::ChainTip,
+    circuit_breaker::{OperatingMode, DEFAULT_BREAKER_THRESHOLD},
+    config::{
+        Config, UpdateConfigArgs, UpgradeArgs, DEFAULT_FEE_RATE, DEFAULT_MAX_FEE_RATE,
+        DEFAULT_MIN_CONVERSION_BALANCE, DEFAULT_MIN_ETCHING_BALANCE,
+        DEFAULT_POSSIBLY_DROPPED_AFTER, DEFAULT_POSTAGE, DEFAULT_REBROADCAST_INTERVAL,
+    },
+    cosigner::{CallerKeys, SignPolicy},
+    ecdsa_api::{cached_ecdsa_public_key, get_ecdsa_public_key, IcEcdsaSigner},
+    guard::CallerGuard,
+    http::{HttpRequest, HttpResponse},
+    idempotency::RequestOutcome,
+    key_derivation::RootKey,
+    psbt_etching::{EtchingPsbt, PendingPsbtEtching, PSBT_EXPIRY_NANOS},
+    scheduled_etching::{CommitAttempt, ScheduledEtching, ScheduledEtchingStatus},
+    schnorr_api::{get_schnorr_public_key, IcSchnorrSigner},
+    storage::{EtchingRecord, ETCHING_HISTORY, REVEAL_QUEUE},
     utils::{always_fail, generate_derivation_path, public_key_to_p2pkh_address},
+    utxo_lock::UtxoReservation,
 };
 
+pub mod audit;
+pub mod broadcast_tracker;
 pub mod btc_api;
+pub mod chain_tracker;
+pub mod circuit_breaker;
 pub mod ckbtc_api;
+pub mod config;
+pub mod cosigner;
 pub mod ecdsa_api;
+pub mod guard;
+pub mod http;
+pub mod idempotency;
+pub mod key_derivation;
+pub mod metrics;
+pub mod migrations;
+#[cfg(test)]
+mod mock;
+pub mod psbt_etching;
+pub mod scheduled_etching;
+pub mod scheduler;
 pub mod schnorr_api;
-pub mod tags;
+pub mod storage;
 pub mod utils;
+pub mod utxo_lock;
 
-#[derive(CandidType, Serialize, Deserialize, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
 pub enum EcdsaKeyIds {
     TestKey1,
     ProductionKey,
@@ -62,8 +100,13 @@ impl EcdsaKeyIds {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QueuedRevealTxn {
     pub reveal_txn: Transaction,
-    pub timer_id: KeyData,
     pub commit_tx_address: String,
+    // height and hash of the block the commit was last seen in
+    #[serde(default)]
+    pub commit_block: Option<(u32, BlockHash)>,
+    // chain height from which on the scheduler checks the commit again
+    #[serde(default)]
+    pub next_check_height: u32,
 }
 
 #[derive(Default, Debug, Serialize, Deserialize)]
@@ -74,9 +117,27 @@ pub struct State {
     pub ecdsa_key: Option<EcdsaKeyIds>,
     pub schnorr_key: Option<SchnorrKeyId>,
     pub schnorr_canister: Option<Principal>,
+    pub schnorr_backend: SchnorrBackend,
     pub queue_count: u128,
     pub timer_for_reveal_txn: u32,
-    pub reveal_txn_in_queue: HashMap<u128, QueuedRevealTxn>,
+    pub admins: Vec<Principal>,
+    pub postage: u64,
+    pub min_etching_balance: u64,
+    pub min_conversion_balance: u64,
+    pub default_fee_rate: u64,
+    pub operating_mode: OperatingMode,
+    pub consecutive_failures: u32,
+    pub breaker_threshold: u32,
+    pub allowed_output_addresses: Vec<String>,
+    pub max_fee_rate: u64,
+    pub daily_spend_limit: u64,
+    pub rebroadcast_interval: u32,
+    pub possibly_dropped_after: u32,
+    // root keys of the canister, derivation paths are derived from them locally
+    pub ecdsa_root_key: Option<RootKey>,
+    pub schnorr_root_key: Option<RootKey>,
+    #[serde(skip)]
+    pub principal_guards: HashSet<Principal>,
 }
 
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
@@ -85,6 +146,54 @@ pub fn get_upgrade_memory() -> Memory {
     MEMORY_MANAGER.with_borrow(|memory| memory.get(MemoryId::new(0)))
 }
 
+pub fn get_request_outcome_memory() -> Memory {
+    MEMORY_MANAGER.with_borrow(|memory| memory.get(MemoryId::new(1)))
+}
+
+pub fn get_reveal_queue_memory() -> Memory {
+    MEMORY_MANAGER.with_borrow(|memory| memory.get(MemoryId::new(2)))
+}
+
+pub fn get_etching_history_memory() -> Memory {
+    MEMORY_MANAGER.with_borrow(|memory| memory.get(MemoryId::new(3)))
+}
+
+pub fn get_locked_utxos_memory() -> Memory {
+    MEMORY_MANAGER.with_borrow(|memory| memory.get(MemoryId::new(4)))
+}
+
+pub fn get_audit_log_index_memory() -> Memory {
+    MEMORY_MANAGER.with_borrow(|memory| memory.get(MemoryId::new(5)))
+}
+
+pub fn get_audit_log_data_memory() -> Memory {
+    MEMORY_MANAGER.with_borrow(|memory| memory.get(MemoryId::new(6)))
+}
+
+pub fn get_pending_psbt_etchings_memory() -> Memory {
+    MEMORY_MANAGER.with_borrow(|memory| memory.get(MemoryId::new(7)))
+}
+
+pub fn get_rune_outpoints_memory() -> Memory {
+    MEMORY_MANAGER.with_borrow(|memory| memory.get(MemoryId::new(8)))
+}
+
+pub fn get_daily_spends_memory() -> Memory {
+    MEMORY_MANAGER.with_borrow(|memory| memory.get(MemoryId::new(9)))
+}
+
+pub fn get_tracked_transactions_memory() -> Memory {
+    MEMORY_MANAGER.with_borrow(|memory| memory.get(MemoryId::new(10)))
+}
+
+pub fn get_chain_headers_memory() -> Memory {
+    MEMORY_MANAGER.with_borrow(|memory| memory.get(MemoryId::new(11)))
+}
+
+pub fn get_scheduled_etchings_memory() -> Memory {
+    MEMORY_MANAGER.with_borrow(|memory| memory.get(MemoryId::new(12)))
+}
+
 thread_local! {
     pub static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
     pub static STATE: RefCell<State> = RefCell::default();
@@ -97,16 +206,29 @@ pub struct InitArgs {
     pub network: BitcoinNetwork,
     pub schnorr_canister: Principal,
     pub timer_for_reveal_txn: u32, // should be provided as mins
+    // override the defaults of the network
+    pub schnorr_backend: Option<SchnorrBackend>,
+    pub schnorr_key: Option<SchnorrKeyId>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum EtcherArgs {
+    Init(InitArgs),
+    Upgrade(Option<UpgradeArgs>),
 }
 
 #[init]
-pub fn init(arg: InitArgs) {
+pub fn init(args: EtcherArgs) {
     getrandom::register_custom_getrandom!(always_fail);
+    let arg = match args {
+        EtcherArgs::Init(arg) => arg,
+        EtcherArgs::Upgrade(_) => ic_cdk::trap("Expected Init args"),
+    };
     let (ecdsa_key_id, schnorr_key) = match arg.network {
         BitcoinNetwork::Mainnet => (
             EcdsaKeyIds::ProductionKey,
             SchnorrKeyId {
-                name: "test_key_1".to_string(),
+                name: "key_1".to_string(),
                 algorithm: schnorr_api::SchnorrAlgorithm::Bip340Secp256k1,
             },
         ),
@@ -131,47 +253,106 @@ pub fn init(arg: InitArgs) {
         state.ckbtc_ledger = Some(arg.ckbtc_ledger);
         state.ecdsa_key = Some(ecdsa_key_id);
         state.schnorr_canister = Some(arg.schnorr_canister);
-        state.schnorr_key = Some(schnorr_key);
+        state.schnorr_key = Some(arg.schnorr_key.unwrap_or(schnorr_key));
+        state.schnorr_backend = arg
+            .schnorr_backend
+            .unwrap_or(SchnorrBackend::default_for(arg.network));
         state.timer_for_reveal_txn = arg.timer_for_reveal_txn;
-    })
+        state.postage = DEFAULT_POSTAGE;
+        state.min_etching_balance = DEFAULT_MIN_ETCHING_BALANCE;
+        state.min_conversion_balance = DEFAULT_MIN_CONVERSION_BALANCE;
+        state.default_fee_rate = DEFAULT_FEE_RATE;
+        state.breaker_threshold = DEFAULT_BREAKER_THRESHOLD;
+        state.max_fee_rate = DEFAULT_MAX_FEE_RATE;
+        state.rebroadcast_interval = DEFAULT_REBROADCAST_INTERVAL;
+        state.possibly_dropped_after = DEFAULT_POSSIBLY_DROPPED_AFTER;
+    });
+    schedule_root_key_fetch();
+    scheduler::schedule();
 }
 
 #[pre_upgrade]
 pub fn pre_upgrade() {
-    let mut state_bytes = vec![];
-    STATE
-        .with_borrow(|s| ciborium::ser::into_writer(&s, &mut state_bytes))
-        .expect("Failed to write bytes");
-    let len = state_bytes.len() as u32;
     let mut memory = get_upgrade_memory();
-    let mut writer = Writer::new(&mut memory, 0);
-    writer.write(&len.to_le_bytes()).unwrap();
-    writer.write(&state_bytes).unwrap();
+    STATE.with_borrow(|state| migrations::write_state(&mut memory, state));
 }
 
 #[post_upgrade]
-pub fn post_upgrade() {
+pub fn post_upgrade(args: Option<EtcherArgs>) {
     let memory = get_upgrade_memory();
+    let (version, state_bytes) = migrations::read_state_bytes(&memory);
+    let mut state = migrations::decode_state(version, &state_bytes);
+    match args {
+        Some(EtcherArgs::Upgrade(Some(upgrade_args))) => {
+            audit::record(AuditEvent::ConfigChanged {
+                change: format!("{:?}", upgrade_args),
+            });
+            config::update_config(&mut state, upgrade_args).unwrap_or_else(|e| ic_cdk::trap(&e))
+        }
+        Some(EtcherArgs::Init(_)) => ic_cdk::trap("Expected Upgrade args"),
+        Some(EtcherArgs::Upgrade(None)) | None => {}
+    }
+    STATE.with(|s| *s.borrow_mut() = state);
+    schedule_root_key_fetch();
+    scheduler::schedule();
+}
 
-    // Read the length of the state bytes.
-    let mut state_len_bytes = [0; 4];
-    memory.read(0, &mut state_len_bytes);
-    let state_len = u32::from_le_bytes(state_len_bytes) as usize;
+#[query]
+pub fn get_config() -> Config {
+    config::ensure_admin();
+    STATE.with_borrow(config::get_config)
+}
 
-    // Read the bytes
-    let mut state_bytes = vec![0; state_len];
-    memory.read(4, &mut state_bytes);
+#[update]
+pub fn update_config(args: UpdateConfigArgs) {
+    config::ensure_admin();
+    if args.admins.is_some() && !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only controllers can change the admins")
+    }
+    audit::record(AuditEvent::ConfigChanged {
+        change: format!("{:?}", args),
+    });
+    STATE
+        .with_borrow_mut(|state| config::update_config(state, args))
+        .unwrap_or_else(|e| ic_cdk::trap(&e));
+    schedule_root_key_fetch();
+    scheduler::schedule();
+}
 
-    // Deserialize and set the state.
-    let state = ciborium::de::from_reader(&*state_bytes).expect("failed to decode state");
-    STATE.with(|s| *s.borrow_mut() = state);
+#[query]
+pub fn get_operating_mode() -> OperatingMode {
+    circuit_breaker::get_operating_mode()
 }
 
 #[update]
-pub async fn get_deposit_address_for_bitcoin() -> String {
+pub fn set_operating_mode(mode: OperatingMode) {
+    config::ensure_admin();
+    audit::record(AuditEvent::ConfigChanged {
+        change: format!("operating mode set to {:?}", mode),
+    });
+    circuit_breaker::set_operating_mode(mode)
+}
+
+/// Returns the audit log, oldest entries first. At most
+/// [`audit::MAX_AUDIT_LOG_PAGE`] entries are returned per call.
+#[query]
+pub fn get_audit_log(args: AuditLogQuery) -> AuditLogPage {
+    config::ensure_admin();
+    audit::query(args)
+}
+
+/// Serves `/metrics` in the Prometheus text format and `/status` as JSON.
+#[query]
+pub fn http_request(req: HttpRequest) -> HttpResponse {
+    http::http_request(req)
+}
+
+#[query]
+pub fn get_deposit_address_for_bitcoin() -> String {
     let caller = ic_cdk::id();
     let derivation_path = generate_derivation_path(&caller);
-    let ecdsa_public_key = get_ecdsa_public_key(derivation_path).await;
+    let ecdsa_public_key = cached_ecdsa_public_key(&derivation_path)
+        .unwrap_or_else(|| ic_cdk::trap("Root key isn't fetched yet, try again shortly"));
     public_key_to_p2pkh_address(&ecdsa_public_key)
 }
 
@@ -201,7 +382,19 @@ pub async fn get_estimated_cbktc_conversion_fee() -> u64 {
 }
 
 #[update]
-pub async fn confirm_and_convert_ckbtc() -> u64 {
+pub async fn confirm_and_convert_ckbtc(request_id: Option<String>) -> u64 {
+    circuit_breaker::ensure_running();
+    let _guard =
+        CallerGuard::new(ic_cdk::caller()).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()));
+    if let Some(result) =
+        idempotency::earlier_result(request_id.as_deref(), |outcome| match outcome {
+            RequestOutcome::ConfirmAndConvertCkbtc(result) => Some(result),
+            _ => None,
+        })
+    {
+        return result;
+    }
+    let failure_audit = FailureAudit::new("confirm_and_convert_ckbtc");
     let caller = ic_cdk::id();
     let account = Account {
         owner: caller,
@@ -215,33 +408,31 @@ pub async fn confirm_and_convert_ckbtc() -> u64 {
     let balance = ckbtc_ledger.get_balance_of(account).await;
     let balance = u64::try_from(balance).unwrap();
     ic_cdk::println!("Balance: {}", balance);
-    let estimated_fee = ckbtc_minter.estimate_withdrawal_fee(Some(balance)).await;
-    let deposit_fee = ckbtc_minter.get_deposit_fee().await;
-    let total_fee = estimated_fee.bitcoin_fee + estimated_fee.minter_fee;
-    if balance <= total_fee + deposit_fee + 20000 {
-        let err_msg = format!(
-            "Balance too Low! Current Balance: {}, Expected atleast {}",
-            balance,
-            total_fee + deposit_fee + 20000
-        );
-        ic_cdk::trap(&err_msg)
-    }
+    let min_conversion_balance = STATE.with_borrow(|state| state.min_conversion_balance);
+    let amount = ckbtc_api::conversion_amount(&ckbtc_minter, balance, min_conversion_balance)
+        .await
+        .unwrap_or_else(|e| ic_cdk::trap(&e));
     let derivation_path = generate_derivation_path(&caller);
     let ecdsa_public_key = get_ecdsa_public_key(derivation_path.clone()).await;
     let p2pkh_address = public_key_to_p2pkh_address(&ecdsa_public_key);
     let ckbtc_deposit_address = ckbtc_minter.get_withdrawal_account().await;
-    if let Err(e) = ckbtc_ledger
+    match ckbtc_ledger
         .icrc1_transfer(ckbtc_deposit_address, balance as u128)
         .await
     {
-        let err_msg = format!("{:?}", e);
-        ic_cdk::trap(&err_msg)
+        Ok(block_index) => audit::record(AuditEvent::CkbtcTransferred {
+            block_index: block_index.0.try_into().unwrap_or(u64::MAX),
+            amount: balance,
+        }),
+        Err(e) => {
+            let err_msg = format!("{:?}", e);
+            ic_cdk::trap(&err_msg)
+        }
     }
-    let amount = balance - 10 - total_fee - deposit_fee;
     ic_cdk::println!("Amount: {}", amount);
-    match ckbtc_minter
+    let block_index = match ckbtc_minter
         .retrieve_btc(ckbtc_api::RetrieveBtcArgs {
-            address: p2pkh_address,
+            address: p2pkh_address.clone(),
             amount,
         })
         .await
@@ -251,7 +442,21 @@ pub async fn confirm_and_convert_ckbtc() -> u64 {
             let err_msg = format!("{:?}", e);
             ic_cdk::trap(&err_msg)
         }
+    };
+    audit::record(AuditEvent::BtcRetrieved {
+        block_index,
+        address: p2pkh_address,
+        amount,
+    });
+    if let Some(request_id) = request_id {
+        idempotency::record_outcome(
+            &ic_cdk::caller(),
+            &request_id,
+            RequestOutcome::ConfirmAndConvertCkbtc(block_index),
+        );
     }
+    failure_audit.completed();
+    block_index
 }
 
 #[query(composite = true)]
@@ -266,75 +471,626 @@ pub async fn query_conversion_status(block_index: u64) -> String {
         .to_string()
 }
 
-#[derive(CandidType, Deserialize, Debug)]
-pub struct EtchingArgs {
-    pub divisibility: u8,
-    pub symbol: u32,
-    pub rune: String,
-    pub amount: u128,
-    pub cap: u128,
-    pub turbo: bool,
-    pub premine: u128,
-    pub height: Option<(u64, u64)>,
-    pub offset: Option<(u64, u64)>,
-    pub fee_rate: Option<u64>,
+#[update]
+pub async fn etch_rune(mut args: EtchingArgs, request_id: Option<String>) -> (String, String) {
+    circuit_breaker::ensure_running();
+    let _guard =
+        CallerGuard::new(ic_cdk::caller()).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()));
+    if let Some(result) =
+        idempotency::earlier_result(request_id.as_deref(), |outcome| match outcome {
+            RequestOutcome::EtchRune(result) => Some(result),
+            _ => None,
+        })
+    {
+        return result;
+    }
+    let failure_audit = FailureAudit::new("etch_rune");
+    args.rune = args.rune.to_ascii_uppercase();
+    audit::record(AuditEvent::EtchingRequested {
+        runes: vec![args.rune.clone()],
+    });
+    let (commit_txid, reveal_txid) = commit_etching(args, None)
+        .await
+        .unwrap_or_else(|e| ic_cdk::trap(&e));
+    if let Some(request_id) = request_id {
+        idempotency::record_outcome(
+            &ic_cdk::caller(),
+            &request_id,
+            RequestOutcome::EtchRune((commit_txid.clone(), reveal_txid.clone())),
+        );
+    }
+    failure_audit.completed();
+    (commit_txid, reveal_txid)
 }
 
-#[update]
-pub async fn etch_rune(mut args: EtchingArgs) -> (String, String) {
+/// Funds, signs and broadcasts the commit of an etching by the canister and
+/// queues its reveal. The rune is checked against the minimum at
+/// `target_height`, or at the current tip without one.
+async fn commit_etching(
+    args: EtchingArgs,
+    target_height: Option<u32>,
+) -> Result<(String, String), String> {
     let caller = ic_cdk::id();
-    args.rune = args.rune.to_ascii_uppercase();
     let derivation_path = generate_derivation_path(&caller);
     let ecdsa_public_key = get_ecdsa_public_key(derivation_path.clone()).await;
     let schnorr_public_key = get_schnorr_public_key(derivation_path.clone()).await;
     let caller_p2pkh_address = public_key_to_p2pkh_address(&ecdsa_public_key);
+    let caller_address = Address::from_str(&caller_p2pkh_address)
+        .unwrap()
+        .assume_checked();
     let balance = btc_api::get_balance_of(caller_p2pkh_address.clone()).await;
-    if balance < 1000_0000 {
-        ic_cdk::trap("Not enough balance")
+    if balance < STATE.with_borrow(|state| state.min_etching_balance) {
+        return Err("Not enough balance".into());
     }
     let utxos_response = btc_api::get_utxos_of(caller_p2pkh_address.clone()).await;
-    check_etching(utxos_response.tip_height, &args);
+    check_etching(target_height.unwrap_or(utxos_response.tip_height), &args)?;
+    let plan = prepare_reveal(&schnorr_public_key, &caller_address, &args)?;
+    let utxos = utxo_lock::available_utxos(utxos_response.utxos);
+    if utxos.is_empty() {
+        return Err("No spendable UTXOs".into());
+    }
+    let utxos = btc_api::select_utxos(&utxos, &caller_address, std::slice::from_ref(&plan))?;
+    let reservation = UtxoReservation::reserve(&caller_p2pkh_address, &utxos)?;
+    let prevouts = btc_api::prevouts_of(&utxos, &caller_address);
     let (commit_tx_address, commit_tx, reveal_tx) = build_and_sign_etching_transaction(
+        &IcEcdsaSigner,
+        &IcSchnorrSigner,
         &derivation_path,
-        &utxos_response.utxos,
+        &utxos,
         &ecdsa_public_key,
+        &caller_address,
+        plan,
+    )
+    .await?;
+    let reveal_txid: String = reveal_tx.txid().encode_hex();
+    audit::record(AuditEvent::TransactionSigned {
+        txid: commit_tx.txid().encode_hex(),
+    });
+    audit::record(AuditEvent::TransactionSigned {
+        txid: reveal_txid.clone(),
+    });
+    let commit_txid = btc_api::send_bitcoin_transaction(commit_tx, &prevouts).await?;
+    reservation.mark_spent(&commit_txid);
+    queue_reveal_txn(commit_tx_address, reveal_tx);
+    Ok((commit_txid, reveal_txid))
+}
+
+/// Holds the etching until its reveal can land in block `target_height`,
+/// which the rune name is checked against, and returns the id to follow it
+/// with `get_scheduled_etching`. The canister broadcasts the commit once the
+/// chain reaches the commit height of the etching.
+#[update]
+pub fn schedule_etching(
+    mut args: EtchingArgs,
+    target_height: u32,
+    request_id: Option<String>,
+) -> u64 {
+    circuit_breaker::ensure_running();
+    if let Some(result) =
+        idempotency::earlier_result(request_id.as_deref(), |outcome| match outcome {
+            RequestOutcome::ScheduleEtching(result) => Some(result),
+            _ => None,
+        })
+    {
+        return result;
+    }
+    args.rune = args.rune.to_ascii_uppercase();
+    let tip_height =
+        chain_tracker::tip_height().unwrap_or_else(|| ic_cdk::trap("Chain tip isn't known yet"));
+    if let Err(e) = scheduled_etching::validate(tip_height, target_height, &args) {
+        ic_cdk::trap(&e)
+    }
+    audit::record(AuditEvent::EtchingRequested {
+        runes: vec![args.rune.clone()],
+    });
+    let id =
+        scheduled_etching::schedule(args, target_height, ic_cdk::caller(), ic_cdk::api::time());
+    if let Some(request_id) = request_id {
+        idempotency::record_outcome(
+            &ic_cdk::caller(),
+            &request_id,
+            RequestOutcome::ScheduleEtching(id),
+        );
+    }
+    id
+}
+
+/// The schedule of an etching and what became of it.
+#[query]
+pub fn get_scheduled_etching(id: u64) -> Option<ScheduledEtching> {
+    scheduled_etching::get(id)
+}
+
+async fn run_scheduled_etching(id: u64) {
+    let Some(etching) = scheduled_etching::get(id) else {
+        return;
+    };
+    let attempt = CommitAttempt::start(id);
+    let status = match commit_etching(etching.args, Some(etching.target_height)).await {
+        Ok((commit_txid, reveal_txid)) => ScheduledEtchingStatus::Committed {
+            commit_txid,
+            reveal_txid,
+        },
+        Err(reason) => {
+            audit::record(AuditEvent::Failure {
+                reason: format!("Scheduled etching {}: {}", id, reason),
+            });
+            ScheduledEtchingStatus::Failed { reason }
+        }
+    };
+    attempt.finish(status);
+}
+
+/// Etches several runes at once. All of them are funded by a single commit
+/// transaction and every reveal is queued on its own. The result at each
+/// index belongs to the etching at the same index of `args`; an etching that
+/// fails validation is reported there and left out of the commit.
+#[update]
+pub async fn etch_runes(
+    args: Vec<EtchingArgs>,
+    request_id: Option<String>,
+) -> Vec<Result<(String, String), String>> {
+    circuit_breaker::ensure_running();
+    let _guard =
+        CallerGuard::new(ic_cdk::caller()).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()));
+    if let Some(result) =
+        idempotency::earlier_result(request_id.as_deref(), |outcome| match outcome {
+            RequestOutcome::EtchRunes(result) => Some(result),
+            _ => None,
+        })
+    {
+        return result;
+    }
+    let failure_audit = FailureAudit::new("etch_runes");
+    audit::record(AuditEvent::EtchingRequested {
+        runes: args
+            .iter()
+            .map(|arg| arg.rune.to_ascii_uppercase())
+            .collect(),
+    });
+    let caller = ic_cdk::id();
+    let derivation_path = generate_derivation_path(&caller);
+    let ecdsa_public_key = get_ecdsa_public_key(derivation_path.clone()).await;
+    let schnorr_public_key = get_schnorr_public_key(derivation_path.clone()).await;
+    let caller_p2pkh_address = public_key_to_p2pkh_address(&ecdsa_public_key);
+    let caller_address = Address::from_str(&caller_p2pkh_address)
+        .unwrap()
+        .assume_checked();
+    let balance = btc_api::get_balance_of(caller_p2pkh_address.clone()).await;
+    if balance < STATE.with_borrow(|state| state.min_etching_balance) {
+        ic_cdk::trap("Not enough balance")
+    }
+    let utxos_response = btc_api::get_utxos_of(caller_p2pkh_address.clone()).await;
+
+    let mut plans: Vec<RevealPlan> = vec![];
+    let mut results: Vec<Result<usize, String>> = vec![];
+    for mut arg in args {
+        arg.rune = arg.rune.to_ascii_uppercase();
+        let plan = check_etching(utxos_response.tip_height, &arg)
+            .and_then(|_| prepare_reveal(&schnorr_public_key, &caller_address, &arg))
+            .and_then(|plan| {
+                if plans.iter().any(|queued| queued.rune == plan.rune) {
+                    return Err("Rune is already part of this batch".to_string());
+                }
+                Ok(plan)
+            });
+        if let Err(e) = &plan {
+            audit::record(AuditEvent::Failure {
+                reason: format!("{}: {}", arg.rune, e),
+            });
+        }
+        results.push(plan.map(|plan| {
+            plans.push(plan);
+            plans.len() - 1
+        }));
+    }
+    let (commit_txid, reveal_txids) = if plans.is_empty() {
+        (String::new(), vec![])
+    } else {
+        let utxos = utxo_lock::available_utxos(utxos_response.utxos);
+        if utxos.is_empty() {
+            ic_cdk::trap("No spendable UTXOs")
+        }
+        let utxos = btc_api::select_utxos(&utxos, &caller_address, &plans)
+            .unwrap_or_else(|e| ic_cdk::trap(&e));
+        let reservation = UtxoReservation::reserve(&caller_p2pkh_address, &utxos)
+            .unwrap_or_else(|e| ic_cdk::trap(&e));
+        let (commit_tx, reveals) = build_and_sign_etching_transactions(
+            &IcEcdsaSigner,
+            &IcSchnorrSigner,
+            &derivation_path,
+            &utxos,
+            &ecdsa_public_key,
+            &caller_address,
+            plans,
+        )
+        .await
+        .unwrap_or_else(|e| ic_cdk::trap(&e));
+        audit::record(AuditEvent::TransactionSigned {
+            txid: commit_tx.txid().encode_hex(),
+        });
+        for (_, reveal_tx) in &reveals {
+            audit::record(AuditEvent::TransactionSigned {
+                txid: reveal_tx.txid().encode_hex(),
+            });
+        }
+        let prevouts = btc_api::prevouts_of(&utxos, &caller_address);
+        let commit_txid = btc_api::send_bitcoin_transaction(commit_tx, &prevouts)
+            .await
+            .unwrap_or_else(|e| ic_cdk::trap(&e));
+        reservation.mark_spent(&commit_txid);
+        let reveal_txids: Vec<String> = reveals
+            .into_iter()
+            .map(|(commit_tx_address, reveal_tx)| {
+                let reveal_txid = reveal_tx.txid().encode_hex();
+                queue_reveal_txn(commit_tx_address, reveal_tx);
+                reveal_txid
+            })
+            .collect();
+        (commit_txid, reveal_txids)
+    };
+    let results: Vec<_> = results
+        .into_iter()
+        .map(|result| result.map(|index| (commit_txid.clone(), reveal_txids[index].clone())))
+        .collect();
+    if let Some(request_id) = request_id {
+        idempotency::record_outcome(
+            &ic_cdk::caller(),
+            &request_id,
+            RequestOutcome::EtchRunes(results.clone()),
+        );
+    }
+    failure_audit.completed();
+    results
+}
+
+/// Etches a rune funded by the caller's own utxos of `funding_address`,
+/// which has to be a P2WPKH or P2TR address. The commit transaction is
+/// returned as an unsigned PSBT and its utxos stay locked until the signed
+/// PSBT is handed to `submit_etching_psbt` or the PSBT expires.
+#[update]
+pub async fn etch_rune_with_psbt(
+    mut args: EtchingArgs,
+    funding_address: String,
+    request_id: Option<String>,
+) -> EtchingPsbt {
+    circuit_breaker::ensure_running();
+    let _guard =
+        CallerGuard::new(ic_cdk::caller()).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()));
+    if let Some(result) =
+        idempotency::earlier_result(request_id.as_deref(), |outcome| match outcome {
+            RequestOutcome::EtchRuneWithPsbt(result) => Some(result),
+            _ => None,
+        })
+    {
+        return result;
+    }
+    let failure_audit = FailureAudit::new("etch_rune_with_psbt");
+    args.rune = args.rune.to_ascii_uppercase();
+    audit::record(AuditEvent::EtchingRequested {
+        runes: vec![args.rune.clone()],
+    });
+    let address = Address::from_str(&funding_address)
+        .and_then(|address| address.require_network(btc_api::get_network()))
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Invalid funding address: {}", e)));
+    // the reveal is still signed by the canister
+    let schnorr_public_key = get_schnorr_public_key(generate_derivation_path(&ic_cdk::id())).await;
+    let utxos_response = btc_api::get_utxos_of(funding_address.clone()).await;
+    if let Err(e) = check_etching(utxos_response.tip_height, &args) {
+        ic_cdk::trap(&e)
+    }
+    let utxos = utxo_lock::available_utxos(utxos_response.utxos);
+    if utxos.is_empty() {
+        ic_cdk::trap("No spendable UTXOs")
+    }
+    let balance: u64 = utxos.iter().map(|utxo| utxo.value).sum();
+    if balance < STATE.with_borrow(|state| state.min_etching_balance) {
+        ic_cdk::trap("Not enough balance")
+    }
+    let (commit_tx_address, psbt) =
+        build_etching_psbt(&schnorr_public_key, &utxos, &address, &args)
+            .unwrap_or_else(|e| ic_cdk::trap(&e));
+    let commit_txid: String = psbt.unsigned_tx.txid().encode_hex();
+    psbt_etching::prune_expired();
+    UtxoReservation::reserve(
+        &funding_address,
+        &btc_api::spent_by(utxos, &psbt.unsigned_tx),
+    )
+    .unwrap_or_else(|e| ic_cdk::trap(&e))
+    .mark_spent(&commit_txid);
+    let (postage, default_fee_rate) =
+        STATE.with_borrow(|state| (state.postage, state.default_fee_rate));
+    let created_at = ic_cdk::api::time();
+    psbt_etching::insert(
+        commit_txid.clone(),
+        PendingPsbtEtching {
+            caller: ic_cdk::caller(),
+            funding_address,
+            commit_tx: psbt.unsigned_tx.clone(),
+            etching_args: args,
+            postage,
+            default_fee_rate,
+            created_at,
+        },
+    );
+    let result = EtchingPsbt {
+        psbt: psbt.to_string(),
+        commit_txid,
+        commit_tx_address: commit_tx_address.to_string(),
+        expires_at: created_at + PSBT_EXPIRY_NANOS,
+    };
+    if let Some(request_id) = request_id {
+        idempotency::record_outcome(
+            &ic_cdk::caller(),
+            &request_id,
+            RequestOutcome::EtchRuneWithPsbt(result.clone()),
+        );
+    }
+    failure_audit.completed();
+    result
+}
+
+/// Takes back a PSBT exported by `etch_rune_with_psbt` once the caller has
+/// signed every input, broadcasts the commit transaction and queues the
+/// reveal. Returns the commit and reveal txids.
+#[update]
+pub async fn submit_etching_psbt(psbt: String, request_id: Option<String>) -> (String, String) {
+    circuit_breaker::ensure_running();
+    let _guard =
+        CallerGuard::new(ic_cdk::caller()).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()));
+    if let Some(result) =
+        idempotency::earlier_result(request_id.as_deref(), |outcome| match outcome {
+            RequestOutcome::SubmitEtchingPsbt(result) => Some(result),
+            _ => None,
+        })
+    {
+        return result;
+    }
+    let failure_audit = FailureAudit::new("submit_etching_psbt");
+    let psbt = PartiallySignedTransaction::from_str(&psbt)
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Invalid PSBT: {}", e)));
+    let commit_txid: String = psbt.unsigned_tx.txid().encode_hex();
+    let pending = psbt_etching::get(&commit_txid)
+        .filter(|pending| pending.caller == ic_cdk::caller())
+        .unwrap_or_else(|| ic_cdk::trap("No pending etching for this PSBT"));
+    if psbt.unsigned_tx != pending.commit_tx {
+        ic_cdk::trap("PSBT doesn't match the exported commit transaction")
+    }
+    let funding_address = Address::from_str(&pending.funding_address)
+        .unwrap()
+        .assume_checked();
+    let prevouts: Vec<_> = psbt
+        .inputs
+        .iter()
+        .map(|input| input.witness_utxo.clone())
+        .collect::<Option<_>>()
+        .unwrap_or_else(|| ic_cdk::trap("PSBT input without its witness utxo"));
+    let commit_tx =
+        finalize_funding_psbt(psbt, &funding_address).unwrap_or_else(|e| ic_cdk::trap(&e));
+    let derivation_path = generate_derivation_path(&ic_cdk::id());
+    let schnorr_public_key = get_schnorr_public_key(derivation_path.clone()).await;
+    let plan = etcher_core::prepare_reveal(
         &schnorr_public_key,
-        caller_p2pkh_address,
-        args,
+        &funding_address,
+        &pending.etching_args,
+        btc_api::get_network(),
+        pending.postage,
+        pending.default_fee_rate,
+    )
+    .unwrap_or_else(|e| ic_cdk::trap(&e));
+    let (commit_tx_address, reveal_tx) = sign_reveal_transactions(
+        &IcSchnorrSigner,
+        &derivation_path,
+        &commit_tx,
+        &funding_address,
+        vec![plan],
+    )
+    .await
+    .unwrap_or_else(|e| ic_cdk::trap(&e))
+    .remove(0);
+    let reveal_txid: String = reveal_tx.txid().encode_hex();
+    audit::record(AuditEvent::TransactionSigned {
+        txid: reveal_txid.clone(),
+    });
+    let commit_txid = btc_api::send_bitcoin_transaction(commit_tx, &prevouts)
+        .await
+        .unwrap_or_else(|e| ic_cdk::trap(&e));
+    psbt_etching::remove(&commit_txid);
+    queue_reveal_txn(commit_tx_address, reveal_tx);
+    if let Some(request_id) = request_id {
+        idempotency::record_outcome(
+            &ic_cdk::caller(),
+            &request_id,
+            RequestOutcome::SubmitEtchingPsbt((commit_txid.clone(), reveal_txid.clone())),
+        );
+    }
+    failure_audit.completed();
+    (commit_txid, reveal_txid)
+}
+
+/// Co-signs a base64 `psbt` built by the caller's wallet, signing every
+/// input that spends from the caller's derived keys. The transaction has to
+/// pass the signing policy of the config first: outputs only pay to the
+/// caller or to allowed addresses, the fee rate stays below the maximum,
+/// the caller stays within the daily spend limit and no input holds runes.
+/// Returns the PSBT with the signatures added.
+#[update]
+pub async fn sign_psbt(psbt: String) -> String {
+    circuit_breaker::ensure_running();
+    let caller = ic_cdk::caller();
+    let _guard = CallerGuard::new(caller).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()));
+    let failure_audit = FailureAudit::new("sign_psbt");
+    let mut psbt = PartiallySignedTransaction::from_str(&psbt)
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Invalid PSBT: {}", e)));
+    let policy = STATE
+        .with_borrow(|state| SignPolicy::from_state(state, btc_api::get_network()))
+        .unwrap_or_else(|e| ic_cdk::trap(&e));
+    let derivation_path = generate_derivation_path(&caller);
+    let keys = CallerKeys::new(
+        &get_ecdsa_public_key(derivation_path.clone()).await,
+        &get_schnorr_public_key(derivation_path.clone()).await,
+    )
+    .unwrap_or_else(|e| ic_cdk::trap(&e));
+    let spent_today = cosigner::spent_today(&caller, ic_cdk::api::time());
+    let spend = cosigner::check_policy(&psbt, &keys, &policy, spent_today)
+        .unwrap_or_else(|e| ic_cdk::trap(&e));
+    cosigner::sign_psbt(
+        &IcEcdsaSigner,
+        &IcSchnorrSigner,
+        &derivation_path,
+        &keys,
+        &mut psbt,
     )
-    .await;
-    let commit_txid = btc_api::send_bitcoin_transaction(commit_tx).await;
+    .await
+    .unwrap_or_else(|e| ic_cdk::trap(&e));
+    cosigner::record_spend(&caller, ic_cdk::api::time(), spend);
+    audit::record(AuditEvent::TransactionSigned {
+        txid: psbt.unsigned_tx.txid().encode_hex(),
+    });
+    failure_audit.completed();
+    psbt.to_string()
+}
+
+/// Releases the utxos locked by a transaction that is never going to confirm,
+/// so they can be spent again. Only callable by admins.
+#[update]
+pub fn release_utxo_locks(txid: String) -> u64 {
+    config::ensure_admin();
+    audit::record(AuditEvent::ConfigChanged {
+        change: format!("released utxo locks of {}", txid),
+    });
+    utxo_lock::release(&txid)
+}
+
+/// Status of a transaction broadcast by the canister.
+#[query]
+pub fn get_broadcast(txid: String) -> Option<BroadcastInfo> {
+    broadcast_tracker::get_info(txid)
+}
+
+/// Transactions broadcast by the canister that haven't confirmed yet,
+/// including the ones flagged as possibly dropped.
+#[query]
+pub fn get_unconfirmed_broadcasts() -> Vec<BroadcastInfo> {
+    broadcast_tracker::unconfirmed()
+}
+
+/// Height, hash and median time past of the most recent block the canister
+/// has synced.
+#[query]
+pub fn get_chain_tip() -> Option<ChainTip> {
+    chain_tracker::tip()
+}
+
+/// Spacers, value, reservation and unlock height of the rune `name`, or why
+/// it isn't a valid name. Whether it was etched already isn't known here.
+#[query]
+pub fn get_rune_name(name: String) -> Result<RuneName, String> {
+    rune_name::rune_name(btc_api::get_network(), &name)
+}
+
+/// The name of the rune with the numeric `value`.
+#[query]
+pub fn get_rune_name_of_value(value: u128) -> String {
+    rune_name::rune_from_value(value)
+}
+
+/// The lowest name that can be etched in a block at `height`, which is the
+/// block after the chain tip if not given.
+#[query]
+pub fn get_rune_minimum(height: Option<u32>) -> Result<RuneMinimum, String> {
+    let height = match height {
+        Some(height) => height,
+        None => chain_tracker::tip_height().ok_or("Chain tip isn't known yet")? + 1,
+    };
+    Ok(rune_name::minimum_at_height(btc_api::get_network(), height))
+}
+
+/// Fetches the root keys that aren't cached yet, right after the current
+/// call, so that queries can derive addresses.
+fn schedule_root_key_fetch() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            ecdsa_api::get_ecdsa_root_key().await;
+            schnorr_api::get_schnorr_root_key().await;
+        })
+    });
+}
+
+async fn sync_chain() {
+    let Some(reorg) = chain_tracker::sync(&IcBitcoinApi).await else {
+        return;
+    };
+    audit::record_as(
+        ic_cdk::id(),
+        AuditEvent::ChainReorganized {
+            fork_height: reorg.fork_height,
+            depth: reorg.depth,
+        },
+    );
+    broadcast_tracker::unconfirm_above(reorg.fork_height);
+    scheduler::recheck_reveals_above(reorg.fork_height);
+}
+
+fn queue_reveal_txn(commit_tx_address: Address, reveal_txn: Transaction) {
     let id = STATE.with_borrow_mut(|state| {
         let id = state.queue_count;
         state.queue_count += 1;
         id
     });
-    let time = STATE.with_borrow(|state| state.timer_for_reveal_txn as u64 * 60);
-    let timer_id = ic_cdk_timers::set_timer_interval(Duration::from_secs(time), move || {
-        ic_cdk::spawn(confirm_min_commitment_and_send_reveal_txn(id))
-    });
     let queue_txn = QueuedRevealTxn {
         commit_tx_address: commit_tx_address.to_string(),
-        reveal_txn: reveal_tx.clone(),
-        timer_id: timer_id.data(),
+        reveal_txn,
+        commit_block: None,
+        next_check_height: 0,
     };
-    STATE.with_borrow_mut(|state| state.reveal_txn_in_queue.insert(id, queue_txn));
-    (commit_txid, reveal_tx.txid().encode_hex())
+    cosigner::remember_rune_outpoints(&queue_txn.reveal_txn);
+    REVEAL_QUEUE.with_borrow_mut(|queue| queue.insert(id, queue_txn));
 }
 
 pub async fn confirm_min_commitment_and_send_reveal_txn(id: u128) {
-    let reveal_txn = STATE.with_borrow(|state| state.reveal_txn_in_queue.get(&id).unwrap().clone());
-    let utxos_response = btc_api::get_utxos_of(reveal_txn.commit_tx_address).await;
-    let utxos = utxos_response.utxos;
-    if utxos.is_empty() {
-        ic_cdk::trap("No UTXOs Found")
+    if circuit_breaker::get_operating_mode() == OperatingMode::ReadOnly {
+        return;
     }
-    if utxos_response.tip_height - utxos[0].height < Runestone::COMMIT_CONFIRMATIONS as u32 - 1 {
-        ic_cdk::trap("Not enough commit confirmation")
-    }
-    btc_api::send_bitcoin_transaction(reveal_txn.reveal_txn).await;
-    ic_cdk_timers::clear_timer(reveal_txn.timer_id.into());
-    STATE.with_borrow_mut(|state| state.reveal_txn_in_queue.remove(&id));
+    let mut queued = REVEAL_QUEUE.with_borrow(|queue| queue.get(&id).unwrap());
+    let reveal_txid =
+        match btc_api::send_reveal_if_confirmed(&IcBitcoinApi, &mut queued, ic_cdk::api::time())
+            .await
+        {
+            Ok(reveal_txid) => reveal_txid,
+            Err(e) if e == btc_api::COMMIT_SPENT => {
+                REVEAL_QUEUE.with_borrow_mut(|queue| queue.remove(&id));
+                audit::record_as(
+                    ic_cdk::id(),
+                    AuditEvent::Failure {
+                        reason: format!(
+                            "Reveal {} of {} dropped: {}",
+                            id, queued.commit_tx_address, e
+                        ),
+                    },
+                );
+                return;
+            }
+            Err(e) => {
+                // keeps what was learned about the commit for the next try
+                queued.next_check_height =
+                    scheduler::next_check_height(&queued, chain_tracker::tip_height());
+                REVEAL_QUEUE.with_borrow_mut(|queue| queue.insert(id, queued));
+                ic_cdk::println!("Reveal {} not sent: {}", id, e);
+                return;
+            }
+        };
+    REVEAL_QUEUE.with_borrow_mut(|queue| queue.remove(&id));
+    ETCHING_HISTORY.with_borrow_mut(|history| {
+        history.insert(
+            id,
+            EtchingRecord {
+                commit_tx_address: queued.commit_tx_address,
+                reveal_txid,
+                revealed_at: ic_cdk::api::time(),
+            },
+        )
+    });
 }
 
 ic_cdk::export_candid!();
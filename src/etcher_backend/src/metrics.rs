@@ -0,0 +1,37 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+/// Counters kept on the heap. They start from zero again after an upgrade,
+/// which Prometheus treats as a regular counter reset.
+#[derive(Default, Debug)]
+pub struct Metrics {
+    pub ecdsa_sign_calls: u64,
+    pub schnorr_sign_calls: u64,
+    pub failures: BTreeMap<&'static str, u64>,
+    pub last_tip_height: Option<u32>,
+}
+
+thread_local! {
+    static METRICS: RefCell<Metrics> = RefCell::default();
+}
+
+pub fn with_metrics<R>(f: impl FnOnce(&Metrics) -> R) -> R {
+    METRICS.with_borrow(f)
+}
+
+pub fn record_ecdsa_sign_call() {
+    METRICS.with_borrow_mut(|metrics| metrics.ecdsa_sign_calls += 1)
+}
+
+pub fn record_schnorr_sign_call() {
+    METRICS.with_borrow_mut(|metrics| metrics.schnorr_sign_calls += 1)
+}
+
+pub fn record_failure(operation: &'static str) {
+    METRICS.with_borrow_mut(|metrics| *metrics.failures.entry(operation).or_default() += 1)
+}
+
+pub fn observe_tip_height(height: u32) {
+    METRICS.with_borrow_mut(|metrics| {
+        metrics.last_tip_height = metrics.last_tip_height.max(Some(height))
+    })
+}
@@ -0,0 +1,626 @@
+//! Versioned layout of the upgrade memory.
+//!
+//! `pre_upgrade` writes `MAGIC | version | length | CBOR encoded State`.
+//! Blobs written before the header existed start right away with the length
+//! and are treated as version 1.
+//!
+//! When `State` changes, freeze its current layout as `StateV{STATE_VERSION}`
+//! below, bump `STATE_VERSION`, add a migration from the frozen layout and a
+//! fixture of it to the tests.
+
+use std::collections::HashMap;
+
+use bitcoin::Transaction;
+use candid::Principal;
+use ic_cdk::api::management_canister::bitcoin::{BitcoinNetwork, Outpoint};
+use ic_stable_structures::{writer::Writer, Memory};
+use serde::Deserialize;
+use slotmap::KeyData;
+
+use crate::{
+    circuit_breaker::{OperatingMode, DEFAULT_BREAKER_THRESHOLD},
+    config::{
+        DEFAULT_FEE_RATE, DEFAULT_MAX_FEE_RATE, DEFAULT_MIN_CONVERSION_BALANCE,
+        DEFAULT_MIN_ETCHING_BALANCE, DEFAULT_POSSIBLY_DROPPED_AFTER, DEFAULT_POSTAGE,
+        DEFAULT_REBROADCAST_INTERVAL,
+    },
+    key_derivation::RootKey,
+    schnorr_api::{SchnorrBackend, SchnorrKeyId},
+    storage::{outpoint_key, LOCKED_UTXOS, REVEAL_QUEUE},
+    utxo_lock::UtxoLock,
+    EcdsaKeyIds, QueuedRevealTxn, State,
+};
+
+pub const MAGIC: &[u8; 4] = b"ETCH";
+pub const STATE_VERSION: u32 = 8;
+
+pub fn write_state<M: Memory>(memory: &mut M, state: &State) {
+    let mut state_bytes = vec![];
+    ciborium::ser::into_writer(state, &mut state_bytes).expect("Failed to write bytes");
+    let mut writer = Writer::new(memory, 0);
+    writer.write(MAGIC).unwrap();
+    writer.write(&STATE_VERSION.to_le_bytes()).unwrap();
+    writer
+        .write(&(state_bytes.len() as u32).to_le_bytes())
+        .unwrap();
+    writer.write(&state_bytes).unwrap();
+}
+
+/// Reads the state bytes and the version they were written with.
+pub fn read_state_bytes<M: Memory>(memory: &M) -> (u32, Vec<u8>) {
+    let mut header = [0; 4];
+    memory.read(0, &mut header);
+    let (version, offset) = if &header == MAGIC {
+        let mut version_bytes = [0; 4];
+        memory.read(4, &mut version_bytes);
+        (u32::from_le_bytes(version_bytes), 8)
+    } else {
+        (1, 0)
+    };
+
+    // Read the length of the state bytes.
+    let mut state_len_bytes = [0; 4];
+    memory.read(offset, &mut state_len_bytes);
+    let state_len = u32::from_le_bytes(state_len_bytes) as usize;
+
+    // Read the bytes
+    let mut state_bytes = vec![0; state_len];
+    memory.read(offset + 4, &mut state_bytes);
+    (version, state_bytes)
+}
+
+/// Decodes state bytes of any known version and migrates them up to the
+/// current `State`.
+pub fn decode_state(version: u32, state_bytes: &[u8]) -> State {
+    match version {
+        1 => migrate_v7(migrate_v6(migrate_v5(migrate_v4(migrate_v3(migrate_v2(
+            migrate_v1(decode(version, state_bytes)),
+        )))))),
+        2 => migrate_v7(migrate_v6(migrate_v5(migrate_v4(migrate_v3(migrate_v2(
+            decode(version, state_bytes),
+        )))))),
+        3 => migrate_v7(migrate_v6(migrate_v5(migrate_v4(migrate_v3(decode(
+            version,
+            state_bytes,
+        )))))),
+        4 => migrate_v7(migrate_v6(migrate_v5(migrate_v4(decode(
+            version,
+            state_bytes,
+        ))))),
+        5 => migrate_v7(migrate_v6(migrate_v5(decode(version, state_bytes)))),
+        6 => migrate_v7(migrate_v6(decode(version, state_bytes))),
+        7 => migrate_v7(decode(version, state_bytes)),
+        STATE_VERSION => decode(version, state_bytes),
+        _ => ic_cdk::trap(&format!("unknown state version {}", version)),
+    }
+}
+
+fn decode<T: for<'de> Deserialize<'de>>(version: u32, state_bytes: &[u8]) -> T {
+    ciborium::de::from_reader(state_bytes)
+        .unwrap_or_else(|e| panic!("failed to decode state version {}: {}", version, e))
+}
+
+/// Everything up to the move of the reveal queue and the utxo locks into
+/// stable memory, blobs without a header.
+#[derive(Deserialize, Debug)]
+pub struct StateV1 {
+    pub ckbtc_ledger: Option<Principal>,
+    pub ckbtc_minter: Option<Principal>,
+    pub network: Option<BitcoinNetwork>,
+    pub ecdsa_key: Option<EcdsaKeyIds>,
+    pub schnorr_key: Option<SchnorrKeyId>,
+    pub schnorr_canister: Option<Principal>,
+    pub queue_count: u128,
+    pub timer_for_reveal_txn: u32,
+    #[serde(default)]
+    pub reveal_txn_in_queue: HashMap<u128, QueuedRevealTxnV1>,
+    #[serde(default)]
+    pub locked_utxos: HashMap<Outpoint, UtxoLockV1>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct QueuedRevealTxnV1 {
+    pub reveal_txn: Transaction,
+    pub timer_id: KeyData,
+    pub commit_tx_address: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UtxoLockV1 {
+    pub address: String,
+    pub spent_by: Option<String>,
+    pub locked_at: u64,
+}
+
+fn migrate_v1(state: StateV1) -> StateV2 {
+    REVEAL_QUEUE.with_borrow_mut(|queue| {
+        for (id, queued) in state.reveal_txn_in_queue {
+            queue.insert(
+                id,
+                QueuedRevealTxn {
+                    reveal_txn: queued.reveal_txn,
+                    commit_tx_address: queued.commit_tx_address,
+                    commit_block: None,
+                    next_check_height: 0,
+                },
+            );
+        }
+    });
+    LOCKED_UTXOS.with_borrow_mut(|locks| {
+        for (outpoint, lock) in state.locked_utxos {
+            locks.insert(
+                outpoint_key(&outpoint),
+                UtxoLock {
+                    address: lock.address,
+                    spent_by: lock.spent_by,
+                    locked_at: lock.locked_at,
+                },
+            );
+        }
+    });
+    StateV2 {
+        ckbtc_ledger: state.ckbtc_ledger,
+        ckbtc_minter: state.ckbtc_minter,
+        network: state.network,
+        ecdsa_key: state.ecdsa_key,
+        schnorr_key: state.schnorr_key,
+        schnorr_canister: state.schnorr_canister,
+        queue_count: state.queue_count,
+        timer_for_reveal_txn: state.timer_for_reveal_txn,
+    }
+}
+
+/// Versioned blobs, before the admin configuration was added.
+#[derive(Deserialize, Debug)]
+pub struct StateV2 {
+    pub ckbtc_ledger: Option<Principal>,
+    pub ckbtc_minter: Option<Principal>,
+    pub network: Option<BitcoinNetwork>,
+    pub ecdsa_key: Option<EcdsaKeyIds>,
+    pub schnorr_key: Option<SchnorrKeyId>,
+    pub schnorr_canister: Option<Principal>,
+    pub queue_count: u128,
+    pub timer_for_reveal_txn: u32,
+}
+
+fn migrate_v2(state: StateV2) -> StateV3 {
+    StateV3 {
+        ckbtc_ledger: state.ckbtc_ledger,
+        ckbtc_minter: state.ckbtc_minter,
+        network: state.network,
+        ecdsa_key: state.ecdsa_key,
+        schnorr_key: state.schnorr_key,
+        schnorr_canister: state.schnorr_canister,
+        queue_count: state.queue_count,
+        timer_for_reveal_txn: state.timer_for_reveal_txn,
+        admins: vec![],
+        postage: DEFAULT_POSTAGE,
+        min_etching_balance: DEFAULT_MIN_ETCHING_BALANCE,
+        min_conversion_balance: DEFAULT_MIN_CONVERSION_BALANCE,
+        default_fee_rate: DEFAULT_FEE_RATE,
+    }
+}
+
+/// Before the operating mode and the circuit breaker were added.
+#[derive(Deserialize, Debug)]
+pub struct StateV3 {
+    pub ckbtc_ledger: Option<Principal>,
+    pub ckbtc_minter: Option<Principal>,
+    pub network: Option<BitcoinNetwork>,
+    pub ecdsa_key: Option<EcdsaKeyIds>,
+    pub schnorr_key: Option<SchnorrKeyId>,
+    pub schnorr_canister: Option<Principal>,
+    pub queue_count: u128,
+    pub timer_for_reveal_txn: u32,
+    pub admins: Vec<Principal>,
+    pub postage: u64,
+    pub min_etching_balance: u64,
+    pub min_conversion_balance: u64,
+    pub default_fee_rate: u64,
+}
+
+fn migrate_v3(state: StateV3) -> StateV4 {
+    StateV4 {
+        ckbtc_ledger: state.ckbtc_ledger,
+        ckbtc_minter: state.ckbtc_minter,
+        network: state.network,
+        ecdsa_key: state.ecdsa_key,
+        schnorr_key: state.schnorr_key,
+        schnorr_canister: state.schnorr_canister,
+        queue_count: state.queue_count,
+        timer_for_reveal_txn: state.timer_for_reveal_txn,
+        admins: state.admins,
+        postage: state.postage,
+        min_etching_balance: state.min_etching_balance,
+        min_conversion_balance: state.min_conversion_balance,
+        default_fee_rate: state.default_fee_rate,
+        operating_mode: OperatingMode::Running,
+        consecutive_failures: 0,
+        breaker_threshold: DEFAULT_BREAKER_THRESHOLD,
+    }
+}
+
+/// Before the policy of the PSBT co-signer was added.
+#[derive(Deserialize, Debug)]
+pub struct StateV4 {
+    pub ckbtc_ledger: Option<Principal>,
+    pub ckbtc_minter: Option<Principal>,
+    pub network: Option<BitcoinNetwork>,
+    pub ecdsa_key: Option<EcdsaKeyIds>,
+    pub schnorr_key: Option<SchnorrKeyId>,
+    pub schnorr_canister: Option<Principal>,
+    pub queue_count: u128,
+    pub timer_for_reveal_txn: u32,
+    pub admins: Vec<Principal>,
+    pub postage: u64,
+    pub min_etching_balance: u64,
+    pub min_conversion_balance: u64,
+    pub default_fee_rate: u64,
+    pub operating_mode: OperatingMode,
+    pub consecutive_failures: u32,
+    pub breaker_threshold: u32,
+}
+
+fn migrate_v4(state: StateV4) -> StateV5 {
+    StateV5 {
+        ckbtc_ledger: state.ckbtc_ledger,
+        ckbtc_minter: state.ckbtc_minter,
+        network: state.network,
+        ecdsa_key: state.ecdsa_key,
+        schnorr_key: state.schnorr_key,
+        schnorr_canister: state.schnorr_canister,
+        queue_count: state.queue_count,
+        timer_for_reveal_txn: state.timer_for_reveal_txn,
+        admins: state.admins,
+        postage: state.postage,
+        min_etching_balance: state.min_etching_balance,
+        min_conversion_balance: state.min_conversion_balance,
+        default_fee_rate: state.default_fee_rate,
+        operating_mode: state.operating_mode,
+        consecutive_failures: state.consecutive_failures,
+        breaker_threshold: state.breaker_threshold,
+        allowed_output_addresses: vec![],
+        max_fee_rate: DEFAULT_MAX_FEE_RATE,
+        daily_spend_limit: 0,
+    }
+}
+
+/// Before the root keys were cached.
+#[derive(Deserialize, Debug)]
+pub struct StateV5 {
+    pub ckbtc_ledger: Option<Principal>,
+    pub ckbtc_minter: Option<Principal>,
+    pub network: Option<BitcoinNetwork>,
+    pub ecdsa_key: Option<EcdsaKeyIds>,
+    pub schnorr_key: Option<SchnorrKeyId>,
+    pub schnorr_canister: Option<Principal>,
+    pub queue_count: u128,
+    pub timer_for_reveal_txn: u32,
+    pub admins: Vec<Principal>,
+    pub postage: u64,
+    pub min_etching_balance: u64,
+    pub min_conversion_balance: u64,
+    pub default_fee_rate: u64,
+    pub operating_mode: OperatingMode,
+    pub consecutive_failures: u32,
+    pub breaker_threshold: u32,
+    pub allowed_output_addresses: Vec<String>,
+    pub max_fee_rate: u64,
+    pub daily_spend_limit: u64,
+}
+
+fn migrate_v5(state: StateV5) -> StateV6 {
+    StateV6 {
+        ckbtc_ledger: state.ckbtc_ledger,
+        ckbtc_minter: state.ckbtc_minter,
+        network: state.network,
+        ecdsa_key: state.ecdsa_key,
+        schnorr_key: state.schnorr_key,
+        schnorr_canister: state.schnorr_canister,
+        queue_count: state.queue_count,
+        timer_for_reveal_txn: state.timer_for_reveal_txn,
+        admins: state.admins,
+        postage: state.postage,
+        min_etching_balance: state.min_etching_balance,
+        min_conversion_balance: state.min_conversion_balance,
+        default_fee_rate: state.default_fee_rate,
+        operating_mode: state.operating_mode,
+        consecutive_failures: state.consecutive_failures,
+        breaker_threshold: state.breaker_threshold,
+        allowed_output_addresses: state.allowed_output_addresses,
+        max_fee_rate: state.max_fee_rate,
+        daily_spend_limit: state.daily_spend_limit,
+        // fetched again after the upgrade
+        ecdsa_root_key: None,
+        schnorr_root_key: None,
+    }
+}
+
+/// Before the schnorr backend could be switched to the management canister.
+#[derive(Deserialize, Debug)]
+pub struct StateV6 {
+    pub ckbtc_ledger: Option<Principal>,
+    pub ckbtc_minter: Option<Principal>,
+    pub network: Option<BitcoinNetwork>,
+    pub ecdsa_key: Option<EcdsaKeyIds>,
+    pub schnorr_key: Option<SchnorrKeyId>,
+    pub schnorr_canister: Option<Principal>,
+    pub queue_count: u128,
+    pub timer_for_reveal_txn: u32,
+    pub admins: Vec<Principal>,
+    pub postage: u64,
+    pub min_etching_balance: u64,
+    pub min_conversion_balance: u64,
+    pub default_fee_rate: u64,
+    pub operating_mode: OperatingMode,
+    pub consecutive_failures: u32,
+    pub breaker_threshold: u32,
+    pub allowed_output_addresses: Vec<String>,
+    pub max_fee_rate: u64,
+    pub daily_spend_limit: u64,
+    pub ecdsa_root_key: Option<RootKey>,
+    pub schnorr_root_key: Option<RootKey>,
+}
+
+fn migrate_v6(state: StateV6) -> StateV7 {
+    StateV7 {
+        ckbtc_ledger: state.ckbtc_ledger,
+        ckbtc_minter: state.ckbtc_minter,
+        network: state.network,
+        ecdsa_key: state.ecdsa_key,
+        schnorr_key: state.schnorr_key,
+        schnorr_canister: state.schnorr_canister,
+        // keeps signing with the schnorr canister it was deployed with
+        schnorr_backend: SchnorrBackend::SchnorrCanister,
+        queue_count: state.queue_count,
+        timer_for_reveal_txn: state.timer_for_reveal_txn,
+        admins: state.admins,
+        postage: state.postage,
+        min_etching_balance: state.min_etching_balance,
+        min_conversion_balance: state.min_conversion_balance,
+        default_fee_rate: state.default_fee_rate,
+        operating_mode: state.operating_mode,
+        consecutive_failures: state.consecutive_failures,
+        breaker_threshold: state.breaker_threshold,
+        allowed_output_addresses: state.allowed_output_addresses,
+        max_fee_rate: state.max_fee_rate,
+        daily_spend_limit: state.daily_spend_limit,
+        ecdsa_root_key: state.ecdsa_root_key,
+        schnorr_root_key: state.schnorr_root_key,
+    }
+}
+
+/// Before broadcast transactions were tracked and rebroadcast.
+#[derive(Deserialize, Debug)]
+pub struct StateV7 {
+    pub ckbtc_ledger: Option<Principal>,
+    pub ckbtc_minter: Option<Principal>,
+    pub network: Option<BitcoinNetwork>,
+    pub ecdsa_key: Option<EcdsaKeyIds>,
+    pub schnorr_key: Option<SchnorrKeyId>,
+    pub schnorr_canister: Option<Principal>,
+    pub schnorr_backend: SchnorrBackend,
+    pub queue_count: u128,
+    pub timer_for_reveal_txn: u32,
+    pub admins: Vec<Principal>,
+    pub postage: u64,
+    pub min_etching_balance: u64,
+    pub min_conversion_balance: u64,
+    pub default_fee_rate: u64,
+    pub operating_mode: OperatingMode,
+    pub consecutive_failures: u32,
+    pub breaker_threshold: u32,
+    pub allowed_output_addresses: Vec<String>,
+    pub max_fee_rate: u64,
+    pub daily_spend_limit: u64,
+    pub ecdsa_root_key: Option<RootKey>,
+    pub schnorr_root_key: Option<RootKey>,
+}
+
+fn migrate_v7(state: StateV7) -> State {
+    State {
+        ckbtc_ledger: state.ckbtc_ledger,
+        ckbtc_minter: state.ckbtc_minter,
+        network: state.network,
+        ecdsa_key: state.ecdsa_key,
+        schnorr_key: state.schnorr_key,
+        schnorr_canister: state.schnorr_canister,
+        schnorr_backend: state.schnorr_backend,
+        queue_count: state.queue_count,
+        timer_for_reveal_txn: state.timer_for_reveal_txn,
+        admins: state.admins,
+        postage: state.postage,
+        min_etching_balance: state.min_etching_balance,
+        min_conversion_balance: state.min_conversion_balance,
+        default_fee_rate: state.default_fee_rate,
+        operating_mode: state.operating_mode,
+        consecutive_failures: state.consecutive_failures,
+        breaker_threshold: state.breaker_threshold,
+        allowed_output_addresses: state.allowed_output_addresses,
+        max_fee_rate: state.max_fee_rate,
+        daily_spend_limit: state.daily_spend_limit,
+        rebroadcast_interval: DEFAULT_REBROADCAST_INTERVAL,
+        possibly_dropped_after: DEFAULT_POSSIBLY_DROPPED_AFTER,
+        ecdsa_root_key: state.ecdsa_root_key,
+        schnorr_root_key: state.schnorr_root_key,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::VectorMemory;
+
+    use super::*;
+
+    fn load_fixture(fixture: &[u8]) -> State {
+        let memory = VectorMemory::default();
+        memory.grow(1);
+        memory.write(0, fixture);
+        let (version, state_bytes) = read_state_bytes(&memory);
+        decode_state(version, &state_bytes)
+    }
+
+    fn assert_config(state: &State) {
+        assert_eq!(
+            state.ckbtc_ledger,
+            Some(Principal::from_text("mc6ru-gyaaa-aaaar-qaaaq-cai").unwrap())
+        );
+        assert_eq!(
+            state.ckbtc_minter,
+            Some(Principal::from_text("ml52i-qqaaa-aaaar-qaaba-cai").unwrap())
+        );
+        assert_eq!(
+            state.schnorr_canister,
+            Some(Principal::from_text("6fwhw-fyaaa-aaaap-qb7ua-cai").unwrap())
+        );
+        assert_eq!(state.network, Some(BitcoinNetwork::Regtest));
+        assert!(matches!(
+            state.ecdsa_key,
+            Some(EcdsaKeyIds::TestKeyLocalDevelopment)
+        ));
+        assert_eq!(state.schnorr_key.as_ref().unwrap().name, "dfx_test_key");
+        assert_eq!(state.queue_count, 3);
+        assert_eq!(state.timer_for_reveal_txn, 1);
+    }
+
+    fn assert_default_config(state: &State) {
+        assert!(state.admins.is_empty());
+        assert_eq!(state.postage, DEFAULT_POSTAGE);
+        assert_eq!(state.min_etching_balance, DEFAULT_MIN_ETCHING_BALANCE);
+        assert_eq!(state.min_conversion_balance, DEFAULT_MIN_CONVERSION_BALANCE);
+        assert_eq!(state.default_fee_rate, DEFAULT_FEE_RATE);
+        assert_default_breaker(state);
+        assert_default_sign_policy(state);
+    }
+
+    fn assert_default_sign_policy(state: &State) {
+        assert!(state.allowed_output_addresses.is_empty());
+        assert_eq!(state.max_fee_rate, DEFAULT_MAX_FEE_RATE);
+        assert_eq!(state.daily_spend_limit, 0);
+    }
+
+    fn assert_default_breaker(state: &State) {
+        assert_eq!(state.operating_mode, OperatingMode::Running);
+        assert_eq!(state.consecutive_failures, 0);
+        assert_eq!(state.breaker_threshold, DEFAULT_BREAKER_THRESHOLD);
+    }
+
+    #[test]
+    fn decodes_v1_baseline() {
+        let state = load_fixture(include_bytes!("../fixtures/state_v1_baseline.bin"));
+        assert_config(&state);
+        assert_default_config(&state);
+        let queued = REVEAL_QUEUE.with_borrow(|queue| queue.get(&2)).unwrap();
+        assert_eq!(queued.reveal_txn.output[0].value, 10_000);
+        assert!(queued.commit_tx_address.starts_with("bcrt1p"));
+    }
+
+    #[test]
+    fn decodes_v1_with_utxo_locks() {
+        let state = load_fixture(include_bytes!("../fixtures/state_v1_utxo_locks.bin"));
+        assert_config(&state);
+        assert!(REVEAL_QUEUE.with_borrow(|queue| queue.contains_key(&2)));
+        let lock = LOCKED_UTXOS
+            .with_borrow(|locks| locks.get(&([7; 32], 1)))
+            .unwrap();
+        assert_eq!(lock.address, "mxkSc9r1rvfVUL8hh4cxE6gfSquP6e7uPF");
+        assert_eq!(lock.locked_at, 1_700_000_000_000_000_000);
+    }
+
+    #[test]
+    fn decodes_v1_with_stable_maps() {
+        let state = load_fixture(include_bytes!("../fixtures/state_v1_stable_maps.bin"));
+        assert_config(&state);
+        assert!(REVEAL_QUEUE.with_borrow(|queue| queue.is_empty()));
+    }
+
+    #[test]
+    fn decodes_v2() {
+        let state = load_fixture(include_bytes!("../fixtures/state_v2.bin"));
+        assert_config(&state);
+        assert_default_config(&state);
+    }
+
+    #[test]
+    fn decodes_v3() {
+        let state = load_fixture(include_bytes!("../fixtures/state_v3.bin"));
+        assert_config(&state);
+        assert_eq!(
+            state.admins,
+            vec![Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()]
+        );
+        assert_eq!(state.postage, 20_000);
+        assert_eq!(state.min_etching_balance, 5_000_000);
+        assert_eq!(state.min_conversion_balance, 30_000);
+        assert_eq!(state.default_fee_rate, 15);
+        assert_default_breaker(&state);
+        assert_default_sign_policy(&state);
+    }
+
+    #[test]
+    fn decodes_v4() {
+        let state = load_fixture(include_bytes!("../fixtures/state_v4.bin"));
+        assert_config(&state);
+        assert_eq!(state.postage, 20_000);
+        assert_eq!(state.operating_mode, OperatingMode::PausedNewOperations);
+        assert_eq!(state.consecutive_failures, 2);
+        assert_eq!(state.breaker_threshold, 3);
+        assert_default_sign_policy(&state);
+    }
+
+    #[test]
+    fn decodes_v5() {
+        let state = load_fixture(include_bytes!("../fixtures/state_v5.bin"));
+        assert_config(&state);
+        assert_eq!(state.breaker_threshold, 3);
+        assert_eq!(
+            state.allowed_output_addresses,
+            vec!["bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x".to_string()]
+        );
+        assert_eq!(state.max_fee_rate, 50);
+        assert_eq!(state.daily_spend_limit, 1_000_000);
+        assert!(state.ecdsa_root_key.is_none() && state.schnorr_root_key.is_none());
+    }
+
+    #[test]
+    fn decodes_v6() {
+        let state = load_fixture(include_bytes!("../fixtures/state_v6.bin"));
+        assert_config(&state);
+        assert_eq!(state.max_fee_rate, 50);
+        let ecdsa_root_key = state.ecdsa_root_key.unwrap();
+        assert_eq!(ecdsa_root_key.public_key[0], 0x02);
+        assert_eq!(ecdsa_root_key.chain_code, vec![1; 32]);
+        assert_eq!(state.schnorr_root_key.unwrap().chain_code, vec![2; 32]);
+        assert_eq!(state.schnorr_backend, SchnorrBackend::SchnorrCanister);
+    }
+
+    #[test]
+    fn decodes_v7() {
+        let state = load_fixture(include_bytes!("../fixtures/state_v7.bin"));
+        assert_config(&state);
+        assert!(state.ecdsa_root_key.is_some());
+        assert_eq!(state.schnorr_backend, SchnorrBackend::ManagementCanister);
+        assert_eq!(state.rebroadcast_interval, DEFAULT_REBROADCAST_INTERVAL);
+        assert_eq!(state.possibly_dropped_after, DEFAULT_POSSIBLY_DROPPED_AFTER);
+    }
+
+    #[test]
+    fn decodes_v8() {
+        let state = load_fixture(include_bytes!("../fixtures/state_v8.bin"));
+        assert_config(&state);
+        assert_eq!(state.schnorr_backend, SchnorrBackend::ManagementCanister);
+        assert_eq!(state.rebroadcast_interval, 15);
+        assert_eq!(state.possibly_dropped_after, 600);
+    }
+
+    #[test]
+    fn current_version_roundtrips() {
+        let state = load_fixture(include_bytes!("../fixtures/state_v8.bin"));
+        let mut memory = VectorMemory::default();
+        write_state(&mut memory, &state);
+        let (version, state_bytes) = read_state_bytes(&memory);
+        assert_eq!(version, STATE_VERSION);
+        assert_config(&decode_state(version, &state_bytes));
+    }
+}
@@ -0,0 +1,396 @@
+//! In-memory stand-ins for the bitcoin api, the signers and the ckBTC minter,
+//! so that the etching flows can run natively under `cargo test`.
+
+use std::{
+    cell::{Cell, RefCell},
+    future::Future,
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+
+use bitcoin::{
+    block::{Header, Version},
+    consensus,
+    hash_types::TxMerkleNode,
+    hashes::{sha256, sha256d, Hash},
+    secp256k1::{KeyPair, Message, PublicKey, Secp256k1, SecretKey},
+    Address, BlockHash, CompactTarget, Network, Transaction, TxOut,
+};
+use candid::Principal;
+use ic_cdk::api::management_canister::bitcoin::{GetUtxosResponse, Outpoint, Utxo};
+use icrc_ledger_types::icrc1::account::Account;
+
+use crate::{
+    btc_api::{preflight, BitcoinApi, GetBlockHeadersResponse},
+    ckbtc_api::{
+        CkBtcMinterApi, EstimateWithdrawalFeeResponse, RetrieveBtcArgs, RetrieveBtcError,
+        RetrieveBtcOk, RetrieveBtcStatusArgs, RetrieveBtcStatusV2,
+    },
+    ecdsa_api::EcdsaSigner,
+    schnorr_api::{SchnorrBackend, SchnorrSigner},
+};
+
+/// Drives `future` to completion. None of the mocks ever suspend, so a
+/// single poll is enough.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    match future
+        .as_mut()
+        .poll(&mut Context::from_waker(Waker::noop()))
+    {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("mock future suspended"),
+    }
+}
+
+fn derive_secret_key(seed: &[u8; 32], derivation_path: &[Vec<u8>]) -> SecretKey {
+    let mut engine = seed.to_vec();
+    derivation_path
+        .iter()
+        .for_each(|index| engine.extend(index));
+    SecretKey::from_slice(&sha256::Hash::hash(&engine).to_byte_array()).unwrap()
+}
+
+/// Signs with a key derived locally from `seed` and the derivation path.
+#[derive(Debug)]
+pub struct MockEcdsaSigner {
+    seed: [u8; 32],
+}
+
+impl MockEcdsaSigner {
+    pub fn new(seed: [u8; 32]) -> Self {
+        Self { seed }
+    }
+}
+
+impl EcdsaSigner for MockEcdsaSigner {
+    async fn public_key(&self, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+        let secret_key = derive_secret_key(&self.seed, &derivation_path);
+        PublicKey::from_secret_key(&Secp256k1::new(), &secret_key)
+            .serialize()
+            .to_vec()
+    }
+
+    async fn sign(&self, message_hash: Vec<u8>, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+        let secret_key = derive_secret_key(&self.seed, &derivation_path);
+        Secp256k1::new()
+            .sign_ecdsa(&Message::from_slice(&message_hash).unwrap(), &secret_key)
+            .serialize_compact()
+            .to_vec()
+    }
+}
+
+/// Signs with a key derived locally from `seed` and the derivation path,
+/// the way `backend` signs the message it is sent: the schnorr canister
+/// hashes it first, the management canister signs it as given.
+#[derive(Debug)]
+pub struct MockSchnorrSigner {
+    seed: [u8; 32],
+    backend: SchnorrBackend,
+}
+
+impl MockSchnorrSigner {
+    pub fn new(seed: [u8; 32]) -> Self {
+        Self::with_backend(seed, SchnorrBackend::SchnorrCanister)
+    }
+
+    pub fn with_backend(seed: [u8; 32], backend: SchnorrBackend) -> Self {
+        Self { seed, backend }
+    }
+}
+
+impl SchnorrSigner for MockSchnorrSigner {
+    async fn public_key(&self, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+        let secret_key = derive_secret_key(&self.seed, &derivation_path);
+        PublicKey::from_secret_key(&Secp256k1::new(), &secret_key)
+            .serialize()
+            .to_vec()
+    }
+
+    async fn sign(&self, signing_data: Vec<u8>, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+        let secp = Secp256k1::new();
+        let keypair =
+            KeyPair::from_secret_key(&secp, &derive_secret_key(&self.seed, &derivation_path));
+        let message = self.backend.message(signing_data);
+        let message = match self.backend {
+            SchnorrBackend::SchnorrCanister => {
+                sha256::Hash::hash(&message).to_byte_array().to_vec()
+            }
+            SchnorrBackend::ManagementCanister => message,
+        };
+        secp.sign_schnorr_no_aux_rand(&Message::from_slice(&message).unwrap(), &keypair)
+            .as_ref()
+            .to_vec()
+    }
+}
+
+#[derive(Debug)]
+struct MockChain {
+    network: Network,
+    tip_height: u32,
+    utxos: Vec<(String, Utxo)>,
+    mempool: Vec<Transaction>,
+    // utxos spent by the mempool, which come back if it is evicted
+    mempool_spends: Vec<(String, Utxo)>,
+    // headers of the blocks at heights 0 to the tip
+    headers: Vec<Header>,
+    // utxos spent by each block, which come back if it is reorged out
+    block_spends: Vec<(u32, String, Utxo)>,
+    // number of reorgs, so that replacement blocks get new hashes
+    forks: u32,
+    funded: u32,
+}
+
+impl MockChain {
+    fn extend_headers(&mut self) {
+        while self.headers.len() <= self.tip_height as usize {
+            let height = self.headers.len() as u32;
+            let prev_blockhash = self
+                .headers
+                .last()
+                .map_or(BlockHash::all_zeros(), Header::block_hash);
+            let seed = [height.to_le_bytes(), self.forks.to_le_bytes()].concat();
+            self.headers.push(Header {
+                version: Version::ONE,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::from_raw_hash(sha256d::Hash::hash(&seed)),
+                time: height,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            });
+        }
+    }
+}
+
+/// A chain that only knows about the utxos it was funded with and the
+/// transactions sent to it. Sent transactions stay in the mempool until
+/// [`MockBitcoinApi::mine`] is called.
+#[derive(Debug)]
+pub struct MockBitcoinApi {
+    chain: RefCell<MockChain>,
+    utxo_requests: Cell<u32>,
+}
+
+impl MockBitcoinApi {
+    pub fn new(network: Network, tip_height: u32) -> Self {
+        let api = Self {
+            chain: RefCell::new(MockChain {
+                network,
+                tip_height,
+                utxos: vec![],
+                mempool: vec![],
+                mempool_spends: vec![],
+                headers: vec![],
+                block_spends: vec![],
+                forks: 0,
+                funded: 0,
+            }),
+            utxo_requests: Cell::new(0),
+        };
+        api.chain.borrow_mut().extend_headers();
+        api
+    }
+
+    /// Adds a confirmed utxo of `value` to `address`.
+    pub fn fund(&self, address: &str, value: u64) {
+        let mut chain = self.chain.borrow_mut();
+        chain.funded += 1;
+        let utxo = Utxo {
+            outpoint: Outpoint {
+                txid: sha256::Hash::hash(&chain.funded.to_le_bytes())
+                    .to_byte_array()
+                    .to_vec(),
+                vout: 0,
+            },
+            value,
+            height: chain.tip_height,
+        };
+        chain.utxos.push((address.to_string(), utxo));
+    }
+
+    /// Includes the mempool in the next block and mines `blocks` blocks.
+    pub fn mine(&self, blocks: u32) {
+        let mut chain = self.chain.borrow_mut();
+        let height = chain.tip_height + 1;
+        let network = chain.network;
+        for (address, utxo) in std::mem::take(&mut chain.mempool_spends) {
+            chain.block_spends.push((height, address, utxo));
+        }
+        for txn in std::mem::take(&mut chain.mempool) {
+            for (vout, output) in txn.output.iter().enumerate() {
+                // outputs without an address, like the runestone, can't be spent
+                if let Ok(address) = Address::from_script(&output.script_pubkey, network) {
+                    let utxo = Utxo {
+                        outpoint: Outpoint {
+                            txid: txn.txid().to_byte_array().to_vec(),
+                            vout: vout as u32,
+                        },
+                        value: output.value,
+                        height,
+                    };
+                    chain.utxos.push((address.to_string(), utxo));
+                }
+            }
+        }
+        chain.tip_height += blocks;
+        chain.extend_headers();
+    }
+
+    /// Replaces the last `depth` blocks by empty ones. The transactions
+    /// they included are dropped rather than returned to the mempool.
+    pub fn reorg(&self, depth: u32) {
+        let mut chain = self.chain.borrow_mut();
+        let fork_height = chain.tip_height - depth;
+        let (reorged, kept) = std::mem::take(&mut chain.block_spends)
+            .into_iter()
+            .partition(|(height, _, _)| *height > fork_height);
+        chain.block_spends = kept;
+        for (_, address, utxo) in reorged {
+            chain.utxos.push((address, utxo));
+        }
+        chain.utxos.retain(|(_, utxo)| utxo.height <= fork_height);
+        chain.headers.truncate(fork_height as usize + 1);
+        chain.forks += 1;
+        chain.extend_headers();
+    }
+
+    /// Number of `get_utxos` calls so far.
+    pub fn utxo_requests(&self) -> u32 {
+        self.utxo_requests.get()
+    }
+
+    pub fn mempool(&self) -> Vec<Transaction> {
+        self.chain.borrow().mempool.clone()
+    }
+
+    /// Drops the mempool, like nodes do with transactions that don't
+    /// confirm for too long.
+    pub fn evict(&self) {
+        let mut chain = self.chain.borrow_mut();
+        chain.mempool.clear();
+        let spends = std::mem::take(&mut chain.mempool_spends);
+        chain.utxos.extend(spends);
+    }
+}
+
+impl BitcoinApi for MockBitcoinApi {
+    async fn get_balance(&self, address: String) -> u64 {
+        self.get_utxos(address)
+            .await
+            .utxos
+            .iter()
+            .map(|utxo| utxo.value)
+            .sum()
+    }
+
+    async fn get_utxos(&self, address: String) -> GetUtxosResponse {
+        self.utxo_requests.set(self.utxo_requests.get() + 1);
+        let chain = self.chain.borrow();
+        GetUtxosResponse {
+            utxos: chain
+                .utxos
+                .iter()
+                .filter(|(owner, _)| *owner == address)
+                .map(|(_, utxo)| utxo.clone())
+                .collect(),
+            tip_block_hash: vec![],
+            tip_height: chain.tip_height,
+            next_page: None,
+        }
+    }
+
+    async fn get_confirmed_utxos(
+        &self,
+        address: String,
+        min_confirmations: u32,
+    ) -> GetUtxosResponse {
+        let mut response = self.get_utxos(address).await;
+        let tip_height = response.tip_height;
+        response
+            .utxos
+            .retain(|utxo| tip_height + 1 >= utxo.height + min_confirmations);
+        response
+    }
+
+    async fn get_block_headers(
+        &self,
+        start_height: u32,
+        end_height: Option<u32>,
+    ) -> GetBlockHeadersResponse {
+        let chain = self.chain.borrow();
+        let end_height = end_height.unwrap_or(chain.tip_height).min(chain.tip_height);
+        GetBlockHeadersResponse {
+            tip_height: chain.tip_height,
+            block_headers: (start_height..=end_height)
+                .map(|height| consensus::serialize(&chain.headers[height as usize]))
+                .collect(),
+        }
+    }
+
+    async fn send_transaction(
+        &self,
+        txn: Transaction,
+        prevouts: &[TxOut],
+    ) -> Result<String, String> {
+        preflight(&txn, prevouts)?;
+        let mut chain = self.chain.borrow_mut();
+        if chain.mempool.contains(&txn) {
+            return Ok(txn.txid().to_string());
+        }
+        for input in txn.input.iter() {
+            let outpoint = Outpoint {
+                txid: input.previous_output.txid.to_byte_array().to_vec(),
+                vout: input.previous_output.vout,
+            };
+            let position = chain
+                .utxos
+                .iter()
+                .position(|(_, utxo)| utxo.outpoint == outpoint)
+                .unwrap_or_else(|| panic!("{} spends an unknown utxo", txn.txid()));
+            let spent = chain.utxos.remove(position);
+            chain.mempool_spends.push(spent);
+        }
+        let txid = txn.txid().to_string();
+        chain.mempool.push(txn);
+        Ok(txid)
+    }
+}
+
+/// A minter with fixed fees that accepts every retrieval.
+#[derive(Debug)]
+pub struct MockCkBtcMinter {
+    pub bitcoin_fee: u64,
+    pub minter_fee: u64,
+    pub deposit_fee: u64,
+}
+
+impl CkBtcMinterApi for MockCkBtcMinter {
+    async fn estimate_withdrawal_fee(&self, _amount: Option<u64>) -> EstimateWithdrawalFeeResponse {
+        EstimateWithdrawalFeeResponse {
+            bitcoin_fee: self.bitcoin_fee,
+            minter_fee: self.minter_fee,
+        }
+    }
+
+    async fn get_deposit_fee(&self) -> u64 {
+        self.deposit_fee
+    }
+
+    async fn get_withdrawal_account(&self) -> Account {
+        Account {
+            owner: Principal::anonymous(),
+            subaccount: None,
+        }
+    }
+
+    async fn retrieve_btc(
+        &self,
+        _retrieve_btc_args: RetrieveBtcArgs,
+    ) -> Result<RetrieveBtcOk, RetrieveBtcError> {
+        Ok(RetrieveBtcOk { block_index: 0 })
+    }
+
+    async fn retrieve_btc_status_v2(&self, _arg: RetrieveBtcStatusArgs) -> RetrieveBtcStatusV2 {
+        RetrieveBtcStatusV2::Pending
+    }
+}
@@ -0,0 +1,67 @@
+use bitcoin::Transaction;
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+use crate::{storage::PENDING_PSBT_ETCHINGS, utxo_lock, EtchingArgs};
+
+/// How long an exported PSBT can be submitted before its utxos are unlocked.
+pub const PSBT_EXPIRY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Returned by `etch_rune_with_psbt`. `psbt` is base64 encoded.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct EtchingPsbt {
+    pub psbt: String,
+    pub commit_txid: String,
+    pub commit_tx_address: String,
+    pub expires_at: u64,
+}
+
+/// An etching whose commit transaction was exported as a PSBT and is waiting
+/// for the caller to sign it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingPsbtEtching {
+    pub caller: Principal,
+    pub funding_address: String,
+    pub commit_tx: Transaction,
+    pub etching_args: EtchingArgs,
+    // the reveal has to be rebuilt exactly as the commit output was funded,
+    // even if the config changed in the meantime
+    pub postage: u64,
+    pub default_fee_rate: u64,
+    pub created_at: u64,
+}
+
+pub fn insert(commit_txid: String, pending: PendingPsbtEtching) {
+    PENDING_PSBT_ETCHINGS.with_borrow_mut(|etchings| etchings.insert(commit_txid, pending));
+}
+
+/// Returns the pending etching of `commit_txid` unless it has expired.
+pub fn get(commit_txid: &str) -> Option<PendingPsbtEtching> {
+    let now = ic_cdk::api::time();
+    PENDING_PSBT_ETCHINGS.with_borrow(|etchings| {
+        etchings
+            .get(&commit_txid.to_string())
+            .filter(|pending| now.saturating_sub(pending.created_at) < PSBT_EXPIRY_NANOS)
+    })
+}
+
+pub fn remove(commit_txid: &str) {
+    PENDING_PSBT_ETCHINGS.with_borrow_mut(|etchings| etchings.remove(&commit_txid.to_string()));
+}
+
+/// Drops every expired etching and unlocks the utxos its commit would have
+/// spent.
+pub fn prune_expired() {
+    let now = ic_cdk::api::time();
+    let expired: Vec<String> = PENDING_PSBT_ETCHINGS.with_borrow(|etchings| {
+        etchings
+            .iter()
+            .filter(|(_, pending)| now.saturating_sub(pending.created_at) >= PSBT_EXPIRY_NANOS)
+            .map(|(commit_txid, _)| commit_txid)
+            .collect()
+    });
+    for commit_txid in expired {
+        remove(&commit_txid);
+        utxo_lock::release(&commit_txid);
+    }
+}
@@ -0,0 +1,223 @@
+//! Etchings held back until a target block height.
+//!
+//! Rune names unlock over time, so a name is checked against the minimum at
+//! the height its reveal lands in. The commit of a scheduled etching is
+//! broadcast just early enough for the reveal to land in the target block:
+//! reveals are sent once the commit has [`Runestone::COMMIT_CONFIRMATIONS`]
+//! confirmations and are mined in the block after that at the earliest.
+
+use candid::{CandidType, Principal};
+use etcher_core::EtchingArgs;
+use ordinals::Runestone;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{self, AuditEvent},
+    btc_api::check_etching,
+    storage::SCHEDULED_ETCHINGS,
+};
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ScheduledEtchingStatus {
+    Waiting,
+    /// The commit is being built, signed and broadcast.
+    Committing,
+    Committed {
+        commit_txid: String,
+        reveal_txid: String,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduledEtching {
+    pub args: EtchingArgs,
+    pub target_height: u32,
+    /// Chain height at which the commit is broadcast.
+    pub commit_height: u32,
+    pub scheduled_by: Principal,
+    pub scheduled_at: u64,
+    pub status: ScheduledEtchingStatus,
+}
+
+pub fn commit_height(target_height: u32) -> u32 {
+    target_height.saturating_sub(Runestone::COMMIT_CONFIRMATIONS as u32 + 1)
+}
+
+/// Checks that `target_height` is still ahead of the chain and that the
+/// rune can be etched in a block at that height.
+pub fn validate(tip_height: u32, target_height: u32, args: &EtchingArgs) -> Result<(), String> {
+    if target_height <= tip_height {
+        return Err(format!(
+            "Target height {} isn't above the chain tip at {}",
+            target_height, tip_height
+        ));
+    }
+    check_etching(target_height, args)
+}
+
+/// Holds `args` until the chain reaches the commit height of
+/// `target_height`, returning the id of the scheduled etching.
+pub fn schedule(args: EtchingArgs, target_height: u32, scheduled_by: Principal, now: u64) -> u64 {
+    SCHEDULED_ETCHINGS.with_borrow_mut(|scheduled| {
+        let id = scheduled
+            .last_key_value()
+            .map_or(0, |(last_id, _)| last_id + 1);
+        scheduled.insert(
+            id,
+            ScheduledEtching {
+                args,
+                target_height,
+                commit_height: commit_height(target_height),
+                scheduled_by,
+                scheduled_at: now,
+                status: ScheduledEtchingStatus::Waiting,
+            },
+        );
+        id
+    })
+}
+
+pub fn get(id: u64) -> Option<ScheduledEtching> {
+    SCHEDULED_ETCHINGS.with_borrow(|scheduled| scheduled.get(&id))
+}
+
+/// The etchings still waiting for their commit height.
+pub fn waiting() -> Vec<(u64, ScheduledEtching)> {
+    SCHEDULED_ETCHINGS.with_borrow(|scheduled| {
+        scheduled
+            .iter()
+            .filter(|(_, etching)| etching.status == ScheduledEtchingStatus::Waiting)
+            .collect()
+    })
+}
+
+/// The waiting etchings whose commit is due at `tip_height`, the ones with
+/// the earliest target first.
+pub fn due(tip_height: u32) -> Vec<u64> {
+    let mut due: Vec<(u64, ScheduledEtching)> = waiting()
+        .into_iter()
+        .filter(|(_, etching)| etching.commit_height <= tip_height)
+        .collect();
+    due.sort_by_key(|(id, etching)| (etching.target_height, *id));
+    due.into_iter().map(|(id, _)| id).collect()
+}
+
+pub fn set_status(id: u64, status: ScheduledEtchingStatus) {
+    SCHEDULED_ETCHINGS.with_borrow_mut(|scheduled| {
+        let mut etching = scheduled.get(&id).unwrap();
+        etching.status = status;
+        scheduled.insert(id, etching);
+    });
+}
+
+/// Marks a scheduled etching as committing for as long as its commit is
+/// being made, so it isn't handed out again meanwhile. Dropped during the
+/// cleanup of a commit that trapped after an await, it leaves the etching
+/// failed rather than committing forever.
+#[derive(Debug)]
+#[must_use]
+pub struct CommitAttempt {
+    id: u64,
+    finished: bool,
+}
+
+impl CommitAttempt {
+    pub fn start(id: u64) -> Self {
+        set_status(id, ScheduledEtchingStatus::Committing);
+        Self {
+            id,
+            finished: false,
+        }
+    }
+
+    pub fn finish(mut self, status: ScheduledEtchingStatus) {
+        self.finished = true;
+        set_status(self.id, status);
+    }
+}
+
+impl Drop for CommitAttempt {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let reason = "Commit trapped".to_string();
+        audit::record_as(
+            ic_cdk::id(),
+            AuditEvent::Failure {
+                reason: format!("Scheduled etching {}: {}", self.id, reason),
+            },
+        );
+        set_status(self.id, ScheduledEtchingStatus::Failed { reason });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+
+    use super::*;
+    use crate::STATE;
+
+    fn etching_args(rune: &str) -> EtchingArgs {
+        EtchingArgs {
+            divisibility: 0,
+            symbol: 'E' as u32,
+            rune: rune.to_string(),
+            amount: 1,
+            cap: 100,
+            turbo: false,
+            premine: 0,
+            height: Some((0, 1_000)),
+            offset: None,
+            fee_rate: None,
+        }
+    }
+
+    #[test]
+    fn checks_the_name_at_the_target_height() {
+        STATE.with_borrow_mut(|state| state.network = Some(BitcoinNetwork::Mainnet));
+        // eleven letter names only start to unlock at height 857,500
+        let args = etching_args("ZZZZZZZZZZZ");
+        assert_eq!(
+            validate(850_000, 855_000, &args),
+            Err("Rune is less than Minimum".to_string())
+        );
+        assert_eq!(validate(850_000, 860_000, &args), Ok(()));
+        assert!(validate(860_000, 860_000, &args)
+            .unwrap_err()
+            .contains("isn't above the chain tip"));
+    }
+
+    #[test]
+    fn commits_ahead_of_the_target_height() {
+        assert_eq!(commit_height(1_000), 993);
+        let later = schedule(etching_args("LATERRUNE"), 1_100, Principal::anonymous(), 0);
+        let sooner = schedule(etching_args("SOONERRUNE"), 1_000, Principal::anonymous(), 0);
+        assert_eq!((later, sooner), (0, 1));
+        assert_eq!(due(992), Vec::<u64>::new());
+        assert_eq!(due(993), vec![sooner]);
+        assert_eq!(due(1_093), vec![sooner, later]);
+
+        set_status(
+            sooner,
+            ScheduledEtchingStatus::Failed {
+                reason: "No spendable UTXOs".to_string(),
+            },
+        );
+        assert_eq!(due(1_093), vec![later]);
+        assert_eq!(get(later).unwrap().commit_height, 1_093);
+
+        let attempt = CommitAttempt::start(later);
+        assert_eq!(due(1_093), Vec::<u64>::new());
+        let committed = ScheduledEtchingStatus::Committed {
+            commit_txid: "commit".to_string(),
+            reveal_txid: "reveal".to_string(),
+        };
+        attempt.finish(committed.clone());
+        assert_eq!(get(later).unwrap().status, committed);
+    }
+}
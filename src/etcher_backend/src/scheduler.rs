@@ -0,0 +1,307 @@
+//! Runs the background jobs of the canister from a single timer.
+//!
+//! Every tick the due jobs run in priority order until the tick's budget is
+//! spent, the others stay due for the next tick. The chain is synced first,
+//! as the scheduled commits and the reveals wait for a height of the chain,
+//! and the unconfirmed transactions are rebroadcast last.
+
+use std::{
+    cell::{Cell, RefCell},
+    time::Duration,
+};
+
+use ic_cdk_timers::TimerId;
+use ordinals::Runestone;
+
+use crate::{
+    broadcast_tracker, chain_tracker, circuit_breaker::OperatingMode, scheduled_etching,
+    storage::REVEAL_QUEUE, QueuedRevealTxn, STATE,
+};
+
+/// Jobs run per tick, none of them takes more than a few bitcoin api calls.
+pub const TICK_BUDGET: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Job {
+    SyncChain,
+    /// Broadcasts the commit of the scheduled etching with the id.
+    Etch(u64),
+    /// Sends the queued reveal with the id once its commit confirmed.
+    Reveal(u128),
+    CheckBroadcasts,
+}
+
+#[derive(Debug, Default)]
+struct Schedule {
+    timer: Option<TimerId>,
+    last_chain_sync: Option<u64>,
+    last_broadcast_check: Option<u64>,
+}
+
+thread_local! {
+    static SCHEDULE: RefCell<Schedule> = RefCell::default();
+    static TICKING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Lowest chain height at which checking `queued` again can change anything:
+/// the height its commit gets enough confirmations at, and the next block
+/// otherwise.
+pub fn next_check_height(queued: &QueuedRevealTxn, tip_height: Option<u32>) -> u32 {
+    let next_block = tip_height.map_or(0, |height| height + 1);
+    match queued.commit_block {
+        Some((height, _)) => next_block.max(height + Runestone::COMMIT_CONFIRMATIONS as u32 - 1),
+        None => next_block,
+    }
+}
+
+/// Checks the reveals whose commit confirmed above `fork_height` on the
+/// next tick, as their commit might have been reorged out.
+pub fn recheck_reveals_above(fork_height: u32) {
+    REVEAL_QUEUE.with_borrow_mut(|queue| {
+        let reorged: Vec<(u128, QueuedRevealTxn)> = queue
+            .iter()
+            .filter(|(_, queued)| {
+                queued
+                    .commit_block
+                    .is_some_and(|(height, _)| height > fork_height)
+            })
+            .collect();
+        for (id, mut queued) in reorged {
+            queued.next_check_height = 0;
+            queue.insert(id, queued);
+        }
+    });
+}
+
+/// The jobs due at `now`, in the order they run, at most [`TICK_BUDGET`].
+/// Reveals are ordered by the height they wait for, lowest first, and are
+/// all due while the chain tip isn't known yet. Each of them looks up its
+/// own commit address, so there is nothing to share between them.
+pub fn due_jobs(now: u64, operating_mode: OperatingMode) -> Vec<Job> {
+    let (last_chain_sync, last_broadcast_check) =
+        SCHEDULE.with_borrow(|schedule| (schedule.last_chain_sync, schedule.last_broadcast_check));
+    let is_due = |last_run: Option<u64>, interval: u64| {
+        last_run.is_none_or(|last_run| now.saturating_sub(last_run) >= interval)
+    };
+    let mut jobs = vec![];
+    if is_due(
+        last_chain_sync,
+        chain_tracker::SYNC_INTERVAL.as_nanos() as u64,
+    ) {
+        jobs.push(Job::SyncChain);
+    }
+    if operating_mode == OperatingMode::ReadOnly {
+        return jobs;
+    }
+
+    let tip_height = chain_tracker::tip_height();
+    // scheduled etchings are new operations, the queued reveals aren't
+    if let Some(tip_height) = tip_height.filter(|_| operating_mode == OperatingMode::Running) {
+        jobs.extend(
+            scheduled_etching::due(tip_height)
+                .into_iter()
+                .map(Job::Etch),
+        );
+    }
+    let mut reveals: Vec<(u32, u128)> = REVEAL_QUEUE.with_borrow(|queue| {
+        queue
+            .iter()
+            .filter(|(_, queued)| {
+                tip_height.is_none_or(|tip_height| queued.next_check_height <= tip_height)
+            })
+            .map(|(id, queued)| (queued.next_check_height, id))
+            .collect()
+    });
+    reveals.sort_unstable();
+    jobs.extend(reveals.into_iter().map(|(_, id)| Job::Reveal(id)));
+
+    let rebroadcast_interval =
+        STATE.with_borrow(|state| state.rebroadcast_interval as u64 * 60 * 1_000_000_000);
+    if rebroadcast_interval != 0 && is_due(last_broadcast_check, rebroadcast_interval) {
+        jobs.push(Job::CheckBroadcasts);
+    }
+    jobs.truncate(TICK_BUDGET);
+    jobs
+}
+
+/// Wakes up every `timer_for_reveal_txn` minutes, replacing the previous
+/// timer, and right away.
+pub fn schedule() {
+    if let Some(timer_id) = SCHEDULE.with_borrow_mut(|schedule| schedule.timer.take()) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+    let minutes = STATE.with_borrow(|state| state.timer_for_reveal_txn.max(1));
+    let timer_id =
+        ic_cdk_timers::set_timer_interval(Duration::from_secs(minutes as u64 * 60), || {
+            ic_cdk::spawn(tick())
+        });
+    SCHEDULE.with_borrow_mut(|schedule| schedule.timer = Some(timer_id));
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(tick()));
+}
+
+/// Skips the tick while the previous one is still waiting on calls, so
+/// that no job runs twice at once. Released on drop, also on a trap.
+struct TickGuard;
+
+impl TickGuard {
+    fn new() -> Option<Self> {
+        (!TICKING.replace(true)).then_some(Self)
+    }
+}
+
+impl Drop for TickGuard {
+    fn drop(&mut self) {
+        TICKING.set(false);
+    }
+}
+
+async fn tick() {
+    let Some(_guard) = TickGuard::new() else {
+        return;
+    };
+    let now = ic_cdk::api::time();
+    for job in due_jobs(now, crate::circuit_breaker::get_operating_mode()) {
+        match job {
+            Job::SyncChain => {
+                SCHEDULE.with_borrow_mut(|schedule| schedule.last_chain_sync = Some(now));
+                crate::sync_chain().await
+            }
+            Job::Etch(id) => crate::run_scheduled_etching(id).await,
+            Job::Reveal(id) => crate::confirm_min_commitment_and_send_reveal_txn(id).await,
+            Job::CheckBroadcasts => {
+                SCHEDULE.with_borrow_mut(|schedule| schedule.last_broadcast_check = Some(now));
+                broadcast_tracker::check_broadcasts(&crate::btc_api::IcBitcoinApi, now).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{absolute::LockTime, hashes::Hash, BlockHash, Transaction};
+    use candid::Principal;
+    use etcher_core::EtchingArgs;
+
+    use super::*;
+    use crate::storage::CHAIN_HEADERS;
+
+    const MINUTE: u64 = 60_000_000_000;
+
+    fn queue(id: u128, commit_block: Option<u32>, next_check_height: u32) {
+        let queued = QueuedRevealTxn {
+            reveal_txn: Transaction {
+                version: 2,
+                lock_time: LockTime::ZERO,
+                input: vec![],
+                output: vec![],
+            },
+            commit_tx_address: String::new(),
+            commit_block: commit_block.map(|height| (height, BlockHash::all_zeros())),
+            next_check_height,
+        };
+        REVEAL_QUEUE.with_borrow_mut(|queue| queue.insert(id, queued));
+    }
+
+    fn set_tip_height(height: u32) {
+        CHAIN_HEADERS.with_borrow_mut(|headers| headers.insert(height, vec![]));
+    }
+
+    #[test]
+    fn runs_due_jobs_by_priority_within_the_budget() {
+        STATE.with_borrow_mut(|state| state.rebroadcast_interval = 30);
+        // the chain tip isn't known yet, so every reveal is checked
+        queue(0, None, 0);
+        queue(1, None, 120);
+        assert_eq!(
+            due_jobs(0, OperatingMode::Running),
+            vec![
+                Job::SyncChain,
+                Job::Reveal(0),
+                Job::Reveal(1),
+                Job::CheckBroadcasts
+            ]
+        );
+
+        set_tip_height(100);
+        SCHEDULE.with_borrow_mut(|schedule| {
+            schedule.last_chain_sync = Some(0);
+            schedule.last_broadcast_check = Some(0);
+        });
+        // waits for the sixth confirmation of a commit in block 98
+        queue(2, Some(98), 103);
+        queue(3, None, 100);
+        queue(4, Some(90), 95);
+        assert_eq!(
+            due_jobs(MINUTE, OperatingMode::Running),
+            vec![Job::Reveal(0), Job::Reveal(4), Job::Reveal(3)]
+        );
+        assert_eq!(
+            due_jobs(30 * MINUTE, OperatingMode::ReadOnly),
+            vec![Job::SyncChain]
+        );
+
+        recheck_reveals_above(97);
+        // commits for block 106 so that its reveal can land there
+        let etching = scheduled_etching::schedule(
+            EtchingArgs {
+                divisibility: 0,
+                symbol: 'E' as u32,
+                rune: "SCHEDULEDRUNE".to_string(),
+                amount: 1,
+                cap: 100,
+                turbo: false,
+                premine: 0,
+                height: None,
+                offset: Some((0, 1_000)),
+                fee_rate: None,
+            },
+            106,
+            Principal::anonymous(),
+            0,
+        );
+        assert_eq!(
+            due_jobs(MINUTE, OperatingMode::Running),
+            vec![
+                Job::Etch(etching),
+                Job::Reveal(0),
+                Job::Reveal(2),
+                Job::Reveal(4),
+                Job::Reveal(3)
+            ]
+        );
+        // the commit waits while new operations are paused
+        assert_eq!(
+            due_jobs(MINUTE, OperatingMode::PausedNewOperations),
+            vec![
+                Job::Reveal(0),
+                Job::Reveal(2),
+                Job::Reveal(4),
+                Job::Reveal(3)
+            ]
+        );
+        for id in 5..5 + TICK_BUDGET as u128 {
+            queue(id, None, 0);
+        }
+        assert_eq!(due_jobs(MINUTE, OperatingMode::Running).len(), TICK_BUDGET);
+    }
+
+    #[test]
+    fn checks_again_once_something_can_have_changed() {
+        let mut queued = QueuedRevealTxn {
+            reveal_txn: Transaction {
+                version: 2,
+                lock_time: LockTime::ZERO,
+                input: vec![],
+                output: vec![],
+            },
+            commit_tx_address: String::new(),
+            commit_block: None,
+            next_check_height: 0,
+        };
+        assert_eq!(next_check_height(&queued, None), 0);
+        assert_eq!(next_check_height(&queued, Some(100)), 101);
+        queued.commit_block = Some((100, BlockHash::all_zeros()));
+        assert_eq!(next_check_height(&queued, Some(100)), 105);
+        assert_eq!(next_check_height(&queued, Some(110)), 111);
+    }
+}
@@ -1,8 +1,10 @@
-use crate::STATE;
+use crate::{circuit_breaker::FailureGuard, key_derivation::RootKey, metrics, State, STATE};
+use bitcoin::hashes::{sha256, Hash};
 use candid::{CandidType, Principal};
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
 use serde::{Deserialize, Serialize};
 
-#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub enum SchnorrAlgorithm {
     #[serde(rename = "bip340secp256k1")]
     Bip340Secp256k1,
@@ -10,12 +12,50 @@ pub enum SchnorrAlgorithm {
     Ed25519,
 }
 
-#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct SchnorrKeyId {
     pub algorithm: SchnorrAlgorithm,
     pub name: String,
 }
 
+/// Where the schnorr key lives. The management canister's threshold schnorr
+/// API is used on the IC, the separately deployed schnorr canister stays
+/// around for local development.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SchnorrBackend {
+    #[default]
+    SchnorrCanister,
+    ManagementCanister,
+}
+
+impl SchnorrBackend {
+    pub fn default_for(network: BitcoinNetwork) -> Self {
+        match network {
+            BitcoinNetwork::Regtest => Self::SchnorrCanister,
+            BitcoinNetwork::Mainnet | BitcoinNetwork::Testnet => Self::ManagementCanister,
+        }
+    }
+
+    /// The message to have the backend sign for the BIP-341 `signing_data`.
+    /// The management canister signs messages as given, so it gets the
+    /// sighash, while the schnorr canister hashes the message itself.
+    pub fn message(self, signing_data: Vec<u8>) -> Vec<u8> {
+        match self {
+            Self::SchnorrCanister => signing_data,
+            Self::ManagementCanister => sha256::Hash::hash(&signing_data).to_byte_array().to_vec(),
+        }
+    }
+}
+
+/// Cycles attached to `sign_with_schnorr` calls to the management canister.
+pub fn sign_with_schnorr_cycles(key_id: &SchnorrKeyId) -> u128 {
+    match key_id.name.as_str() {
+        // the production key is held by the larger fiduciary subnet
+        "key_1" => 26_153_846_153,
+        _ => 10_000_000_000,
+    }
+}
+
 #[derive(CandidType, Deserialize, Serialize, Debug)]
 struct SchnorrPublicKey {
     pub canister_id: Option<Principal>,
@@ -41,15 +81,22 @@ struct SignWithSchnorrReply {
     pub signature: Vec<u8>,
 }
 
-pub async fn get_schnorr_public_key(derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
-    let (schnorr_canister, key_id) = STATE.with_borrow(|state| {
-        (
-            *state.schnorr_canister.as_ref().unwrap(),
-            state.schnorr_key.as_ref().unwrap().clone(),
-        )
-    });
+// the canister serving the configured key and that key
+fn schnorr_service(state: &State) -> (Principal, SchnorrKeyId) {
+    let canister = match state.schnorr_backend {
+        SchnorrBackend::SchnorrCanister => *state.schnorr_canister.as_ref().unwrap(),
+        SchnorrBackend::ManagementCanister => Principal::management_canister(),
+    };
+    (canister, state.schnorr_key.as_ref().unwrap().clone())
+}
+
+async fn fetch_public_key(
+    canister: Principal,
+    key_id: SchnorrKeyId,
+    derivation_path: Vec<Vec<u8>>,
+) -> SchnorrPublicKeyReply {
     ic_cdk::call::<(SchnorrPublicKey,), (SchnorrPublicKeyReply,)>(
-        schnorr_canister,
+        canister,
         "schnorr_public_key",
         (SchnorrPublicKey {
             canister_id: None,
@@ -60,27 +107,92 @@ pub async fn get_schnorr_public_key(derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
     .await
     .unwrap()
     .0
-    .public_key
 }
 
-pub async fn schnorr_sign(message: Vec<u8>, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
-    let (schnorr_canister, key_id) = STATE.with_borrow(|state| {
-        (
-            *state.schnorr_canister.as_ref().unwrap(),
-            state.schnorr_key.as_ref().unwrap().clone(),
-        )
+/// The canister's root key, fetched once and cached in the state.
+pub async fn get_schnorr_root_key() -> RootKey {
+    if let Some(root_key) = STATE.with_borrow(|state| state.schnorr_root_key.clone()) {
+        return root_key;
+    }
+    let (canister, key_id) = STATE.with_borrow(schnorr_service);
+    let response = fetch_public_key(canister, key_id.clone(), vec![]).await;
+    let root_key = RootKey {
+        public_key: response.public_key,
+        chain_code: response.chain_code,
+    };
+    STATE.with_borrow_mut(|state| {
+        // the key may have been switched while fetching
+        if schnorr_service(state) == (canister, key_id) {
+            state.schnorr_root_key = Some(root_key.clone());
+        }
     });
-    ic_cdk::call::<(SignWithSchnorr,), (SignWithSchnorrReply,)>(
-        schnorr_canister,
-        "sign_with_schnorr",
-        (SignWithSchnorr {
-            message,
-            derivation_path,
-            key_id,
-        },),
-    )
-    .await
-    .unwrap()
-    .0
-    .signature
+    root_key
+}
+
+/// Keys of the management canister are derived from the root key, the
+/// schnorr canister of local development is asked for every key as it
+/// isn't bound to derive them the way the IC does.
+pub async fn get_schnorr_public_key(derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+    let (canister, key_id) = STATE.with_borrow(schnorr_service);
+    if canister != Principal::management_canister() {
+        return fetch_public_key(canister, key_id, derivation_path)
+            .await
+            .public_key;
+    }
+    get_schnorr_root_key()
+        .await
+        .derive_public_key(&derivation_path)
+        .unwrap_or_else(|e| ic_cdk::trap(&e))
+}
+
+pub async fn schnorr_sign(signing_data: Vec<u8>, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+    let (backend, (canister, key_id)) =
+        STATE.with_borrow(|state| (state.schnorr_backend, schnorr_service(state)));
+    let cycles = if canister == Principal::management_canister() {
+        sign_with_schnorr_cycles(&key_id)
+    } else {
+        0
+    };
+    metrics::record_schnorr_sign_call();
+    let failure_guard = FailureGuard::new("sign_with_schnorr");
+    let signature =
+        ic_cdk::api::call::call_with_payment128::<(SignWithSchnorr,), (SignWithSchnorrReply,)>(
+            canister,
+            "sign_with_schnorr",
+            (SignWithSchnorr {
+                message: backend.message(signing_data),
+                derivation_path,
+                key_id,
+            },),
+            cycles,
+        )
+        .await
+        .unwrap()
+        .0
+        .signature;
+    failure_guard.succeeded();
+    signature
+}
+
+#[allow(async_fn_in_trait)]
+pub trait SchnorrSigner {
+    async fn public_key(&self, derivation_path: Vec<Vec<u8>>) -> Vec<u8>;
+
+    /// Returns the BIP-340 signature of the sighash of the BIP-341
+    /// `signing_data`, i.e. of its sha256.
+    async fn sign(&self, signing_data: Vec<u8>, derivation_path: Vec<Vec<u8>>) -> Vec<u8>;
+}
+
+/// Signs with the configured schnorr backend.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IcSchnorrSigner;
+
+impl SchnorrSigner for IcSchnorrSigner {
+    async fn public_key(&self, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+        get_schnorr_public_key(derivation_path).await
+    }
+
+    async fn sign(&self, signing_data: Vec<u8>, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+        schnorr_sign(signing_data, derivation_path).await
+    }
 }
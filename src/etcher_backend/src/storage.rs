@@ -0,0 +1,80 @@
+use std::{borrow::Cow, cell::RefCell};
+
+use candid::Principal;
+use ic_cdk::api::management_canister::bitcoin::Outpoint;
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    broadcast_tracker::TrackedTransaction, cosigner::DailySpend, get_chain_headers_memory,
+    get_daily_spends_memory, get_etching_history_memory, get_locked_utxos_memory,
+    get_pending_psbt_etchings_memory, get_reveal_queue_memory, get_rune_outpoints_memory,
+    get_scheduled_etchings_memory, get_tracked_transactions_memory, idempotency::StoredOutcome,
+    psbt_etching::PendingPsbtEtching, scheduled_etching::ScheduledEtching, utxo_lock::UtxoLock,
+    Memory, QueuedRevealTxn,
+};
+
+/// Outpoints are stored as `(txid, vout)`.
+pub type OutpointKey = ([u8; 32], u32);
+
+pub fn outpoint_key(outpoint: &Outpoint) -> OutpointKey {
+    (outpoint.txid.as_slice().try_into().unwrap(), outpoint.vout)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EtchingRecord {
+    pub commit_tx_address: String,
+    pub reveal_txid: String,
+    pub revealed_at: u64,
+}
+
+macro_rules! impl_cbor_storable {
+    ($t:ty) => {
+        impl Storable for $t {
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                let mut bytes = vec![];
+                ciborium::ser::into_writer(self, &mut bytes).unwrap();
+                Cow::Owned(bytes)
+            }
+
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                ciborium::de::from_reader(bytes.as_ref()).unwrap()
+            }
+
+            const BOUND: Bound = Bound::Unbounded;
+        }
+    };
+}
+
+impl_cbor_storable!(QueuedRevealTxn);
+impl_cbor_storable!(EtchingRecord);
+impl_cbor_storable!(UtxoLock);
+impl_cbor_storable!(PendingPsbtEtching);
+impl_cbor_storable!(DailySpend);
+impl_cbor_storable!(TrackedTransaction);
+impl_cbor_storable!(ScheduledEtching);
+impl_cbor_storable!(StoredOutcome);
+
+thread_local! {
+    pub static REVEAL_QUEUE: RefCell<StableBTreeMap<u128, QueuedRevealTxn, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_reveal_queue_memory()));
+    pub static ETCHING_HISTORY: RefCell<StableBTreeMap<u128, EtchingRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_etching_history_memory()));
+    pub static LOCKED_UTXOS: RefCell<StableBTreeMap<OutpointKey, UtxoLock, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_locked_utxos_memory()));
+    pub static PENDING_PSBT_ETCHINGS: RefCell<StableBTreeMap<String, PendingPsbtEtching, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_pending_psbt_etchings_memory()));
+    // outpoints holding the premine of an etching made by the canister
+    pub static RUNE_OUTPOINTS: RefCell<StableBTreeMap<OutpointKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_rune_outpoints_memory()));
+    pub static DAILY_SPENDS: RefCell<StableBTreeMap<Principal, DailySpend, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_daily_spends_memory()));
+    // every transaction broadcast by the canister, by txid
+    pub static TRACKED_TRANSACTIONS: RefCell<StableBTreeMap<String, TrackedTransaction, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_tracked_transactions_memory()));
+    // consensus encoded headers of the most recent blocks, by height
+    pub static CHAIN_HEADERS: RefCell<StableBTreeMap<u32, Vec<u8>, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_chain_headers_memory()));
+    pub static SCHEDULED_ETCHINGS: RefCell<StableBTreeMap<u64, ScheduledEtching, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_scheduled_etchings_memory()));
+}
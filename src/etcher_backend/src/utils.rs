@@ -64,37 +64,3 @@ pub fn public_key_to_p2pkh_address(public_key: &[u8]) -> String {
 
     bs58::encode(full_address).into_string()
 }
-
-// Converts a SEC1 ECDSA signature to the DER format.
-pub fn sec1_to_der(sec1_signature: Vec<u8>) -> Vec<u8> {
-    let r: Vec<u8> = if sec1_signature[0] & 0x80 != 0 {
-        // r is negative. Prepend a zero byte.
-        let mut tmp = vec![0x00];
-        tmp.extend(sec1_signature[..32].to_vec());
-        tmp
-    } else {
-        // r is positive.
-        sec1_signature[..32].to_vec()
-    };
-
-    let s: Vec<u8> = if sec1_signature[32] & 0x80 != 0 {
-        // s is negative. Prepend a zero byte.
-        let mut tmp = vec![0x00];
-        tmp.extend(sec1_signature[32..].to_vec());
-        tmp
-    } else {
-        // s is positive.
-        sec1_signature[32..].to_vec()
-    };
-
-    // Convert signature to DER.
-    vec![
-        vec![0x30, 4 + r.len() as u8 + s.len() as u8, 0x02, r.len() as u8],
-        r,
-        vec![0x02, s.len() as u8],
-        s,
-    ]
-    .into_iter()
-    .flatten()
-    .collect()
-}
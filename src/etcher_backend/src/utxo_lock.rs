@@ -0,0 +1,131 @@
+use ic_cdk::api::management_canister::bitcoin::Utxo;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{outpoint_key, OutpointKey, LOCKED_UTXOS};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UtxoLock {
+    pub address: String,
+    // txid of the transaction spending the outpoint, `None` while that
+    // transaction is still being built and signed
+    pub spent_by: Option<String>,
+    pub locked_at: u64,
+}
+
+/// Filters out every utxo that is already reserved by an in-flight or
+/// unconfirmed transaction.
+pub fn available_utxos(utxos: Vec<Utxo>) -> Vec<Utxo> {
+    LOCKED_UTXOS.with_borrow(|locks| {
+        utxos
+            .into_iter()
+            .filter(|utxo| !locks.contains_key(&outpoint_key(&utxo.outpoint)))
+            .collect()
+    })
+}
+
+/// Releases the locks of `address` whose spending transaction has confirmed,
+/// i.e. the outpoint no longer shows up in the address' utxo set.
+/// `utxos` has to be the complete set of the address.
+pub fn release_spent(address: &str, utxos: &[Utxo]) {
+    let unspent: Vec<OutpointKey> = utxos
+        .iter()
+        .map(|utxo| outpoint_key(&utxo.outpoint))
+        .collect();
+    LOCKED_UTXOS.with_borrow_mut(|locks| {
+        let spent: Vec<OutpointKey> = locks
+            .iter()
+            .filter(|(outpoint, lock)| {
+                lock.address == address && lock.spent_by.is_some() && !unspent.contains(outpoint)
+            })
+            .map(|(outpoint, _)| outpoint)
+            .collect();
+        for outpoint in spent {
+            locks.remove(&outpoint);
+        }
+    })
+}
+
+/// Releases every lock held for the transaction `txid`, returning how many
+/// outpoints were freed.
+pub fn release(txid: &str) -> u64 {
+    LOCKED_UTXOS.with_borrow_mut(|locks| {
+        let held: Vec<OutpointKey> = locks
+            .iter()
+            .filter(|(_, lock)| lock.spent_by.as_deref() == Some(txid))
+            .map(|(outpoint, _)| outpoint)
+            .collect();
+        for outpoint in held.iter() {
+            locks.remove(outpoint);
+        }
+        held.len() as u64
+    })
+}
+
+/// Holds the locks on the utxos selected for a transaction while it is being
+/// built, signed and broadcasted. Unless [`UtxoReservation::mark_spent`] is
+/// called the locks are released once the reservation is dropped, which also
+/// happens when the call traps after an await.
+#[derive(Debug)]
+#[must_use]
+pub struct UtxoReservation {
+    outpoints: Vec<OutpointKey>,
+    spent: bool,
+}
+
+impl UtxoReservation {
+    pub fn reserve(address: &str, utxos: &[Utxo]) -> Result<Self, String> {
+        let locked_at = ic_cdk::api::time();
+        let outpoints: Vec<OutpointKey> = utxos
+            .iter()
+            .map(|utxo| outpoint_key(&utxo.outpoint))
+            .collect();
+        LOCKED_UTXOS.with_borrow_mut(|locks| {
+            if outpoints
+                .iter()
+                .any(|outpoint| locks.contains_key(outpoint))
+            {
+                return Err("Utxo is already in use by another transaction".to_string());
+            }
+            for outpoint in outpoints.iter() {
+                locks.insert(
+                    *outpoint,
+                    UtxoLock {
+                        address: address.to_string(),
+                        spent_by: None,
+                        locked_at,
+                    },
+                );
+            }
+            Ok(Self {
+                outpoints,
+                spent: false,
+            })
+        })
+    }
+
+    /// Keeps the utxos locked until `txid` confirms or is released.
+    pub fn mark_spent(mut self, txid: &str) {
+        LOCKED_UTXOS.with_borrow_mut(|locks| {
+            for outpoint in self.outpoints.iter() {
+                if let Some(mut lock) = locks.get(outpoint) {
+                    lock.spent_by = Some(txid.to_string());
+                    locks.insert(*outpoint, lock);
+                }
+            }
+        });
+        self.spent = true;
+    }
+}
+
+impl Drop for UtxoReservation {
+    fn drop(&mut self) {
+        if self.spent {
+            return;
+        }
+        LOCKED_UTXOS.with_borrow_mut(|locks| {
+            for outpoint in self.outpoints.iter() {
+                locks.remove(outpoint);
+            }
+        })
+    }
+}
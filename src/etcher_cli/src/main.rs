@@ -0,0 +1,419 @@
+//! Builds the commit and reveal transactions of an etching offline, with the
+//! same logic the canister uses, so that what the canister would have done
+//! can be reproduced without deploying it.
+
+use std::{fs, path::PathBuf, str::FromStr};
+
+use bitcoin::{
+    consensus,
+    psbt::PartiallySignedTransaction,
+    secp256k1::{KeyPair, Message, Secp256k1},
+    Address, Network, OutPoint, PrivateKey, Transaction, Txid,
+};
+use clap::{Parser, ValueEnum};
+use etcher_core::{
+    build_commit_transaction, build_reveal_transaction, check_etching, commit_psbt,
+    finalize_commit_transaction, finalize_reveal_transaction, prepare_reveal, reveal_psbt,
+    EtchingArgs, UnsignedCommit, UnsignedReveal, Utxo,
+};
+use serde::{Deserialize, Serialize};
+
+// same defaults as the canister config
+const DEFAULT_POSTAGE: u64 = 10_000;
+const DEFAULT_FEE_RATE: u64 = 10;
+
+#[derive(Parser, Debug)]
+#[command(about = "Builds the commit and reveal transactions of a rune etching")]
+struct Cli {
+    /// JSON file holding the request, replaces the request flags
+    #[arg(long, conflicts_with_all = ["utxos", "rune"])]
+    request: Option<PathBuf>,
+    #[command(flatten)]
+    flags: RequestFlags,
+    #[arg(long, value_enum, default_value_t = Format::Hex)]
+    format: Format,
+    /// WIF key owning the utxos, which also signs the reveal. Regtest only.
+    #[arg(long)]
+    wif: Option<String>,
+    /// Signed commit transaction as hex, only the reveal spending it is built
+    #[arg(long)]
+    commit_tx: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct RequestFlags {
+    #[arg(long, default_value = "regtest")]
+    network: String,
+    /// Utxo to spend as `txid:vout:value`, can be repeated
+    #[arg(long = "utxo", value_parser = parse_utxo)]
+    utxos: Vec<UtxoArg>,
+    /// P2PKH address owning the utxos, derived from `--wif` when omitted
+    #[arg(long)]
+    address: Option<String>,
+    /// SEC1 encoded key signing the reveal, derived from `--wif` when omitted
+    #[arg(long)]
+    schnorr_public_key: Option<String>,
+    /// Current block height, to check the rune against the minimum name length
+    #[arg(long)]
+    block_height: Option<u32>,
+    #[arg(long)]
+    rune: Option<String>,
+    #[arg(long, default_value_t = '¤')]
+    symbol: char,
+    #[arg(long, default_value_t = 0)]
+    divisibility: u8,
+    #[arg(long, default_value_t = 1)]
+    amount: u128,
+    #[arg(long, default_value_t = 1)]
+    cap: u128,
+    #[arg(long, default_value_t = 0)]
+    premine: u128,
+    #[arg(long)]
+    turbo: bool,
+    /// Absolute mint heights as `start:stop`
+    #[arg(long, value_parser = parse_range)]
+    height: Option<(u64, u64)>,
+    /// Mint heights relative to the etching as `start:stop`
+    #[arg(long, value_parser = parse_range)]
+    offset: Option<(u64, u64)>,
+    #[arg(long)]
+    fee_rate: Option<u64>,
+    #[arg(long, default_value_t = DEFAULT_POSTAGE)]
+    postage: u64,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Hex,
+    Psbt,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct UtxoArg {
+    txid: String,
+    vout: u32,
+    value: u64,
+    /// Hex encoded transaction the utxo belongs to, added to the PSBT
+    #[serde(default)]
+    previous_transaction: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Request {
+    network: Network,
+    utxos: Vec<UtxoArg>,
+    #[serde(default)]
+    address: Option<String>,
+    #[serde(default)]
+    schnorr_public_key: Option<String>,
+    #[serde(default)]
+    block_height: Option<u32>,
+    etching: EtchingArgs,
+    #[serde(default = "default_postage")]
+    postage: u64,
+    #[serde(default = "default_fee_rate")]
+    default_fee_rate: u64,
+}
+
+fn default_postage() -> u64 {
+    DEFAULT_POSTAGE
+}
+
+fn default_fee_rate() -> u64 {
+    DEFAULT_FEE_RATE
+}
+
+#[derive(Serialize, Debug)]
+struct EncodedTransaction {
+    txid: String,
+    signed: bool,
+    /// Hex encoded transaction or base64 encoded PSBT, depending on the format
+    encoded: String,
+}
+
+#[derive(Serialize, Debug)]
+struct Output {
+    commit_tx_address: String,
+    commit: Option<EncodedTransaction>,
+    /// Only built once the commit is signed, since signing changes its txid
+    reveal: Option<EncodedTransaction>,
+}
+
+fn parse_utxo(arg: &str) -> Result<UtxoArg, String> {
+    let mut parts = arg.split(':');
+    let (Some(txid), Some(vout), Some(value), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err("expected txid:vout:value".into());
+    };
+    Ok(UtxoArg {
+        txid: txid.to_string(),
+        vout: vout.parse().map_err(|e| format!("invalid vout: {}", e))?,
+        value: value.parse().map_err(|e| format!("invalid value: {}", e))?,
+        previous_transaction: None,
+    })
+}
+
+fn parse_range(arg: &str) -> Result<(u64, u64), String> {
+    let (start, stop) = arg.split_once(':').ok_or("expected start:stop")?;
+    Ok((
+        start.parse().map_err(|e| format!("invalid start: {}", e))?,
+        stop.parse().map_err(|e| format!("invalid stop: {}", e))?,
+    ))
+}
+
+impl RequestFlags {
+    fn into_request(self) -> Result<Request, String> {
+        Ok(Request {
+            network: Network::from_str(&self.network).map_err(|e| e.to_string())?,
+            utxos: self.utxos,
+            address: self.address,
+            schnorr_public_key: self.schnorr_public_key,
+            block_height: self.block_height,
+            etching: EtchingArgs {
+                divisibility: self.divisibility,
+                symbol: self.symbol as u32,
+                rune: self.rune.ok_or("--rune is required")?,
+                amount: self.amount,
+                cap: self.cap,
+                turbo: self.turbo,
+                premine: self.premine,
+                height: self.height,
+                offset: self.offset,
+                fee_rate: self.fee_rate,
+            },
+            postage: self.postage,
+            default_fee_rate: DEFAULT_FEE_RATE,
+        })
+    }
+}
+
+fn decode_transaction(hex: &str) -> Result<Transaction, String> {
+    let bytes = hex::decode(hex).map_err(|e| e.to_string())?;
+    consensus::deserialize(&bytes).map_err(|e| e.to_string())
+}
+
+fn encode(
+    format: Format,
+    transaction: &Transaction,
+    signed: bool,
+    psbt: impl FnOnce() -> PartiallySignedTransaction,
+) -> EncodedTransaction {
+    EncodedTransaction {
+        txid: transaction.txid().to_string(),
+        signed,
+        encoded: match format {
+            Format::Hex => hex::encode(consensus::serialize(transaction)),
+            Format::Psbt => psbt().to_string(),
+        },
+    }
+}
+
+fn unsigned_commit_psbt(
+    unsigned: &UnsignedCommit,
+    utxos: &[UtxoArg],
+) -> Result<PartiallySignedTransaction, String> {
+    let mut psbt = commit_psbt(unsigned);
+    for (input, utxo) in psbt.inputs.iter_mut().zip(utxos) {
+        if let Some(previous_transaction) = &utxo.previous_transaction {
+            input.non_witness_utxo = Some(decode_transaction(previous_transaction)?);
+        }
+    }
+    Ok(psbt)
+}
+
+fn run(cli: Cli) -> Result<Output, String> {
+    let request = match cli.request {
+        Some(path) => {
+            let json = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+            serde_json::from_str(&json).map_err(|e| e.to_string())?
+        }
+        None => cli.flags.into_request()?,
+    };
+    let secp = Secp256k1::new();
+    let private_key = cli
+        .wif
+        .as_deref()
+        .map(|wif| PrivateKey::from_wif(wif).map_err(|e| e.to_string()))
+        .transpose()?;
+    if private_key.is_some() && request.network != Network::Regtest {
+        return Err("Local signing is only supported on regtest".into());
+    }
+    let address = match (&request.address, &private_key) {
+        (Some(address), _) => Address::from_str(address)
+            .map_err(|e| e.to_string())?
+            .require_network(request.network)
+            .map_err(|e| e.to_string())?,
+        (None, Some(private_key)) => {
+            Address::p2pkh(&private_key.public_key(&secp), request.network)
+        }
+        (None, None) => return Err("Either an address or a WIF key is required".into()),
+    };
+    let schnorr_public_key = match (&request.schnorr_public_key, &private_key) {
+        (Some(public_key), _) => hex::decode(public_key).map_err(|e| e.to_string())?,
+        (None, Some(private_key)) => private_key.public_key(&secp).to_bytes(),
+        (None, None) => return Err("Either a schnorr public key or a WIF key is required".into()),
+    };
+    if let Some(block_height) = request.block_height {
+        check_etching(request.network, block_height, &request.etching)?;
+    }
+    let prepare = || {
+        prepare_reveal(
+            &schnorr_public_key,
+            &address,
+            &request.etching,
+            request.network,
+            request.postage,
+            request.default_fee_rate,
+        )
+    };
+    let plan = prepare()?;
+    let commit_tx_address = plan.commit_tx_address.to_string();
+
+    let sign_reveal = |unsigned: UnsignedReveal| -> Result<EncodedTransaction, String> {
+        let Some(private_key) = &private_key else {
+            let reveal_tx = unsigned.transaction.clone();
+            return Ok(encode(cli.format, &reveal_tx, false, || {
+                reveal_psbt(&unsigned)
+            }));
+        };
+        let keypair = KeyPair::from_secret_key(&secp, &private_key.inner);
+        let message = Message::from_slice(&unsigned.sighash()).unwrap();
+        let signature = secp.sign_schnorr_no_aux_rand(&message, &keypair);
+        let mut psbt = reveal_psbt(&unsigned);
+        let reveal_tx = finalize_reveal_transaction(unsigned, signature.as_ref())?;
+        psbt.inputs[0].final_script_witness = Some(reveal_tx.input[0].witness.clone());
+        Ok(encode(cli.format, &reveal_tx, true, || psbt))
+    };
+
+    if let Some(commit_tx) = &cli.commit_tx {
+        let commit_tx = decode_transaction(commit_tx)?;
+        let unsigned = build_reveal_transaction(&commit_tx, 0, &address, plan)?;
+        return Ok(Output {
+            commit_tx_address,
+            commit: None,
+            reveal: Some(sign_reveal(unsigned)?),
+        });
+    }
+
+    let utxos = request
+        .utxos
+        .iter()
+        .map(|utxo| {
+            Ok(Utxo {
+                outpoint: OutPoint::new(
+                    Txid::from_str(&utxo.txid).map_err(|e| e.to_string())?,
+                    utxo.vout,
+                ),
+                value: utxo.value,
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let unsigned = build_commit_transaction(&utxos, &address, &[plan])?;
+    let mut psbt = unsigned_commit_psbt(&unsigned, &request.utxos)?;
+    let Some(private_key) = &private_key else {
+        let commit_tx = unsigned.transaction.clone();
+        return Ok(Output {
+            commit_tx_address,
+            commit: Some(encode(cli.format, &commit_tx, false, || psbt)),
+            reveal: None,
+        });
+    };
+    let signatures: Vec<_> = unsigned
+        .sighashes
+        .iter()
+        .map(|sighash| {
+            secp.sign_ecdsa(&Message::from_slice(sighash).unwrap(), &private_key.inner)
+                .serialize_compact()
+                .to_vec()
+        })
+        .collect();
+    let public_key = private_key.public_key(&secp).to_bytes();
+    let commit_tx = finalize_commit_transaction(unsigned, &signatures, &public_key)
+        .map_err(|e| e.to_string())?;
+    for (input, signed) in psbt.inputs.iter_mut().zip(commit_tx.input.iter()) {
+        input.final_script_sig = Some(signed.script_sig.clone());
+    }
+    let unsigned_reveal = build_reveal_transaction(&commit_tx, 0, &address, prepare()?)?;
+    Ok(Output {
+        commit_tx_address,
+        commit: Some(encode(cli.format, &commit_tx, true, || psbt)),
+        reveal: Some(sign_reveal(unsigned_reveal)?),
+    })
+}
+
+fn main() {
+    match run(Cli::parse()) {
+        Ok(output) => println!("{}", serde_json::to_string_pretty(&output).unwrap()),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::{sha256, Hash};
+
+    use super::*;
+
+    const WIF: &str = "cMahea7zqjxrtgAbB7LSGbcQUr1uX1ojuat9jZodMN87JcbXMTcA";
+
+    fn cli(args: &[&str]) -> Cli {
+        let funding = sha256::Hash::hash(b"funding").to_string();
+        let utxo = format!("{}:0:1000000", funding);
+        let mut argv = vec![
+            "etcher_cli",
+            "--utxo",
+            &utxo,
+            "--rune",
+            "ETCHERTESTRUNE",
+            "--offset",
+            "0:100",
+            "--cap",
+            "1000",
+        ];
+        argv.extend(args);
+        Cli::parse_from(argv)
+    }
+
+    #[test]
+    fn signs_both_transactions_with_a_wif_key() {
+        let output = run(cli(&["--wif", WIF])).unwrap();
+        let commit = output.commit.unwrap();
+        let reveal = output.reveal.unwrap();
+        assert!(commit.signed && reveal.signed);
+        let commit_tx = decode_transaction(&commit.encoded).unwrap();
+        let reveal_tx = decode_transaction(&reveal.encoded).unwrap();
+        assert_eq!(reveal_tx.input[0].previous_output.txid, commit_tx.txid());
+        assert_eq!(reveal_tx.input[0].witness.len(), 3);
+    }
+
+    #[test]
+    fn exports_an_unsigned_commit_as_psbt() {
+        let wif = PrivateKey::from_wif(WIF).unwrap();
+        let public_key = wif.public_key(&Secp256k1::new());
+        let address = Address::p2pkh(&public_key, Network::Regtest).to_string();
+        let schnorr_public_key = public_key.to_string();
+        let output = run(cli(&[
+            "--address",
+            &address,
+            "--schnorr-public-key",
+            &schnorr_public_key,
+            "--format",
+            "psbt",
+        ]))
+        .unwrap();
+        let commit = output.commit.unwrap();
+        assert!(!commit.signed);
+        assert!(output.reveal.is_none());
+        let psbt = PartiallySignedTransaction::from_str(&commit.encoded).unwrap();
+        assert_eq!(psbt.unsigned_tx.txid().to_string(), commit.txid);
+    }
+
+    #[test]
+    fn refuses_local_signing_outside_regtest() {
+        assert!(run(cli(&["--wif", WIF, "--network", "bitcoin"])).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+use std::str::FromStr;
+
+use bitcoin::{
+    opcodes,
+    script::Builder,
+    secp256k1::{constants::SCHNORR_SIGNATURE_SIZE, PublicKey, Secp256k1, XOnlyPublicKey},
+    taproot::{ControlBlock, LeafVersion, Signature, TaprootBuilder},
+    Address, Amount, FeeRate, Network, OutPoint, Script, ScriptBuf, Sequence, Transaction, TxIn,
+    TxOut, Witness,
+};
+use ordinals::{Etching, Rune, Runestone, SpacedRune, Terms};
+
+use crate::{tags::Tag, EtchingArgs};
+
+pub fn check_etching(network: Network, height: u32, arg: &EtchingArgs) -> Result<(), String> {
+    if arg.height.is_none() && arg.offset.is_none() {
+        return Err("No mint term selected".into());
+    }
+    let minimum = Rune::minimum_at_height(network, ordinals::Height(height));
+    let SpacedRune { rune, spacers: _ } =
+        SpacedRune::from_str(&arg.rune).map_err(|e| format!("Invalid rune name: {}", e))?;
+    if rune < minimum {
+        return Err("Rune is less than Minimum".into());
+    }
+    if rune.is_reserved() {
+        return Err("Rune is reserved".into());
+    }
+    if char::from_u32(arg.symbol).is_none() {
+        return Err("Failed to validate symbol".into());
+    }
+    if arg.amount == 0 || arg.cap == 0 {
+        return Err("Can't be Zero".into());
+    }
+    if arg.divisibility > 38 {
+        return Err("Exceeds max allowed divisibility".into());
+    }
+    if let Some((start, stop)) = arg.height {
+        if start >= stop {
+            return Err("Height Start must be lower than Height Stop".into());
+        }
+    }
+    if let Some((start, stop)) = arg.offset {
+        if start >= stop {
+            return Err("Offset Start must be lower than Offset Stop".into());
+        }
+    }
+    Ok(())
+}
+
+/// Everything needed to fund and later sign the reveal transaction of a single etching.
+#[derive(Debug)]
+pub struct RevealPlan {
+    pub rune: Rune,
+    pub runestone: Runestone,
+    pub schnorr_public_key: XOnlyPublicKey,
+    pub reveal_script: ScriptBuf,
+    pub control_block: ControlBlock,
+    pub commit_tx_address: Address,
+    pub reveal_output: Vec<TxOut>,
+    pub reveal_fee: Amount,
+    pub fee_rate: FeeRate,
+    pub postage: u64,
+}
+
+impl RevealPlan {
+    /// Minimum value the commit output has to carry so that the reveal
+    /// transaction can pay its fee and every output stays above dust.
+    pub fn required_commit_value(&self) -> u64 {
+        self.reveal_fee.to_sat()
+            + self
+                .reveal_output
+                .iter()
+                .map(|output| output.value)
+                .sum::<u64>()
+            + self.postage
+    }
+}
+
+/// Builds the reveal script committing to the rune, the taproot address the
+/// commit transaction has to pay to and the outputs of the reveal.
+///
+/// `schnorr_public_key` is the SEC1 encoded key that signs the reveal, the
+/// premine and the change go to `caller_address`.
+pub fn prepare_reveal(
+    schnorr_public_key: &[u8],
+    caller_address: &Address,
+    etching_args: &EtchingArgs,
+    network: Network,
+    postage: u64,
+    default_fee_rate: u64,
+) -> Result<RevealPlan, String> {
+    let SpacedRune { rune, spacers } = SpacedRune::from_str(&etching_args.rune)
+        .map_err(|e| format!("Invalid rune name: {}", e))?;
+    let symbol = char::from_u32(etching_args.symbol).ok_or("Failed to validate symbol")?;
+    // building the reveal script
+    let secp256k1 = Secp256k1::new();
+    let schnorr_public_key: XOnlyPublicKey = PublicKey::from_slice(schnorr_public_key)
+        .map_err(|e| format!("Invalid schnorr public key: {}", e))?
+        .into();
+    const PROTOCOL_ID: [u8; 3] = *b"ord";
+    let mut reveal_script = Builder::new()
+        .push_slice(schnorr_public_key.serialize())
+        .push_opcode(opcodes::all::OP_CHECKSIG)
+        .push_opcode(opcodes::OP_FALSE)
+        .push_opcode(opcodes::all::OP_IF)
+        .push_slice(PROTOCOL_ID);
+
+    Tag::Rune.encode(&mut reveal_script, &Some(rune.commitment()));
+
+    let reveal_script = reveal_script
+        .push_opcode(opcodes::all::OP_ENDIF)
+        .into_script();
+
+    let taproot_send_info = TaprootBuilder::new()
+        .add_leaf(0, reveal_script.clone())
+        .unwrap()
+        .finalize(&secp256k1, schnorr_public_key)
+        .unwrap();
+
+    let control_block = taproot_send_info
+        .control_block(&(reveal_script.clone(), LeafVersion::TapScript))
+        .unwrap();
+
+    let commit_tx_address = Address::p2tr_tweaked(taproot_send_info.output_key(), network);
+
+    let mut reveal_output = vec![];
+
+    let mut pointer = None;
+    if etching_args.premine > 0 {
+        reveal_output.push(TxOut {
+            script_pubkey: caller_address.script_pubkey(),
+            value: postage,
+        });
+        pointer = Some(reveal_output.len() as u32 - 1u32);
+    }
+    let (height, offset) = match (etching_args.height, etching_args.offset) {
+        (Some((start, stop)), None) => {
+            let height = (Some(start), Some(stop));
+            (height, (None, None))
+        }
+        (None, Some((start, stop))) => {
+            let offset = (Some(start), Some(stop));
+            ((None, None), offset)
+        }
+        (Some((h_start, h_stop)), Some((o_start, o_stop))) => {
+            let height = (Some(h_start), Some(h_stop));
+            let offset = (Some(o_start), Some(o_stop));
+            (height, offset)
+        }
+        (None, None) => return Err("No Term Set".into()),
+    };
+    let runestone = Runestone {
+        etching: Some(Etching {
+            rune: Some(rune),
+            symbol: Some(symbol),
+            divisibility: Some(etching_args.divisibility),
+            premine: Some(etching_args.premine),
+            spacers: Some(spacers),
+            turbo: etching_args.turbo,
+            terms: Some(Terms {
+                cap: Some(etching_args.cap),
+                amount: Some(etching_args.amount),
+                height,
+                offset,
+            }),
+        }),
+        edicts: vec![],
+        mint: None,
+        pointer,
+    };
+
+    let script_pubkey = runestone.encipher();
+    if script_pubkey.len() > 82 {
+        return Err("Exceeds OP_RETURN size of 82".into());
+    }
+    reveal_output.push(TxOut {
+        script_pubkey,
+        value: 0,
+    });
+    let fee_rate = FeeRate::from_sat_per_vb(etching_args.fee_rate.unwrap_or(default_fee_rate))
+        .ok_or("Invalid fee rate")?;
+    // the change output is only added once the commit output is funded, but
+    // it still has to be paid for
+    let mut fee_output = reveal_output.clone();
+    fee_output.push(TxOut {
+        script_pubkey: caller_address.script_pubkey(),
+        value: postage,
+    });
+    let reveal_fee = estimate_reveal_fee(&control_block, fee_rate, fee_output, &reveal_script);
+    Ok(RevealPlan {
+        rune,
+        runestone,
+        schnorr_public_key,
+        reveal_script,
+        control_block,
+        commit_tx_address,
+        reveal_output,
+        reveal_fee,
+        fee_rate,
+        postage,
+    })
+}
+
+/// Estimates the fee of a reveal transaction spending a single commit output
+/// by filling its witness with a placeholder signature.
+fn estimate_reveal_fee(
+    control_block: &ControlBlock,
+    fee_rate: FeeRate,
+    output: Vec<TxOut>,
+    script: &Script,
+) -> Amount {
+    let mut witness = Witness::new();
+    witness.push(
+        Signature::from_slice(&[0; SCHNORR_SIGNATURE_SIZE])
+            .unwrap()
+            .to_vec(),
+    );
+    witness.push(script);
+    witness.push(control_block.serialize());
+    let reveal_txn = Transaction {
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: ScriptBuf::new(),
+            witness,
+            sequence: Sequence::from_height(Runestone::COMMIT_CONFIRMATIONS - 1),
+        }],
+        output,
+        lock_time: bitcoin::absolute::LockTime::ZERO,
+        version: 2,
+    };
+    fee_rate * reveal_txn.weight()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn etching_args(rune: &str) -> EtchingArgs {
+        EtchingArgs {
+            divisibility: 2,
+            symbol: '$' as u32,
+            rune: rune.to_string(),
+            amount: 1_000,
+            cap: 100,
+            turbo: true,
+            premine: 0,
+            height: Some((10, 20)),
+            offset: None,
+            fee_rate: None,
+        }
+    }
+
+    #[test]
+    fn accepts_a_valid_etching() {
+        assert_eq!(
+            check_etching(Network::Regtest, 100, &etching_args("ETCHER•TEST•RUNE")),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_etchings() {
+        let mut args = etching_args("ETCHERTESTRUNE");
+        args.height = Some((20, 10));
+        assert!(check_etching(Network::Regtest, 100, &args).is_err());
+
+        let mut args = etching_args("ETCHERTESTRUNE");
+        args.cap = 0;
+        assert!(check_etching(Network::Regtest, 100, &args).is_err());
+
+        let mut args = etching_args("ETCHERTESTRUNE");
+        args.height = None;
+        assert!(check_etching(Network::Regtest, 100, &args).is_err());
+
+        assert!(check_etching(Network::Regtest, 100, &etching_args("etcher")).is_err());
+    }
+}
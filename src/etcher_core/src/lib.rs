@@ -0,0 +1,41 @@
+//! Builds the commit and reveal transactions of rune etchings.
+//!
+//! Nothing in here signs or talks to the network: the builders return
+//! unsigned transactions along with what has to be signed, and the
+//! signatures are put in place afterwards by the `finalize_*` functions.
+
+#![warn(missing_debug_implementations)]
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+pub mod etching;
+pub mod psbt;
+pub mod rune_name;
+pub mod standardness;
+mod tags;
+pub mod transaction;
+
+pub use etching::{check_etching, prepare_reveal, RevealPlan};
+pub use psbt::{commit_psbt, finalize_funding_psbt, funding_psbt, reveal_psbt};
+pub use rune_name::{RuneMinimum, RuneName};
+pub use standardness::{check_standardness, Violation};
+pub use transaction::{
+    build_commit_transaction, build_reveal_transaction, finalize_commit_transaction,
+    finalize_reveal_transaction, fund_commit_transaction, select_utxos, taproot_signing_data,
+    with_placeholder_signatures, SigningError, UnsignedCommit, UnsignedReveal, Utxo,
+};
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct EtchingArgs {
+    pub divisibility: u8,
+    pub symbol: u32,
+    pub rune: String,
+    pub amount: u128,
+    pub cap: u128,
+    pub turbo: bool,
+    pub premine: u128,
+    pub height: Option<(u64, u64)>,
+    pub offset: Option<(u64, u64)>,
+    pub fee_rate: Option<u64>,
+}
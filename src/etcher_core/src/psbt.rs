@@ -0,0 +1,317 @@
+//! BIP-174 exports of the etching transactions, for signers that work on
+//! PSBTs rather than on sighashes.
+
+use bitcoin::{
+    ecdsa,
+    psbt::{PartiallySignedTransaction, PsbtSighashType},
+    secp256k1::{Message, Secp256k1, XOnlyPublicKey},
+    sighash::{Prevouts, SighashCache, TapSighashType},
+    taproot::{self, LeafVersion},
+    Address, AddressType, PublicKey, ScriptBuf, Transaction, TxOut, Witness,
+};
+
+use crate::{transaction::SIG_HASH_TYPE, RevealPlan, UnsignedCommit, UnsignedReveal, Utxo};
+
+/// Unsigned commit transaction as a PSBT. The commit only spends legacy
+/// outputs, whose previous transactions aren't known here, so callers that
+/// have them should fill in `non_witness_utxo`.
+pub fn commit_psbt(unsigned: &UnsignedCommit) -> PartiallySignedTransaction {
+    let mut psbt = PartiallySignedTransaction::from_unsigned_tx(unsigned.transaction.clone())
+        .expect("commit inputs are unsigned");
+    for input in psbt.inputs.iter_mut() {
+        input.sighash_type = Some(PsbtSighashType::from(SIG_HASH_TYPE));
+    }
+    psbt
+}
+
+/// Unsigned reveal transaction as a PSBT spending the commit output through
+/// the reveal script.
+pub fn reveal_psbt(unsigned: &UnsignedReveal) -> PartiallySignedTransaction {
+    let mut psbt = PartiallySignedTransaction::from_unsigned_tx(unsigned.transaction.clone())
+        .expect("reveal input is unsigned");
+    let RevealPlan {
+        schnorr_public_key,
+        reveal_script,
+        control_block,
+        ..
+    } = &unsigned.plan;
+    let input = &mut psbt.inputs[0];
+    input.witness_utxo = Some(unsigned.commit_output.clone());
+    input.sighash_type = Some(PsbtSighashType::from(TapSighashType::Default));
+    input.tap_internal_key = Some(*schnorr_public_key);
+    input.tap_scripts.insert(
+        control_block.clone(),
+        (reveal_script.clone(), LeafVersion::TapScript),
+    );
+    psbt
+}
+
+/// Commit transaction spending utxos of a segwit `funding_address` as a
+/// PSBT, for the owner of the address to sign.
+pub fn funding_psbt(
+    commit_tx: &Transaction,
+    utxos: &[Utxo],
+    funding_address: &Address,
+) -> Result<PartiallySignedTransaction, String> {
+    let sighash_type = match funding_address.address_type() {
+        Some(AddressType::P2wpkh) => PsbtSighashType::from(SIG_HASH_TYPE),
+        Some(AddressType::P2tr) => PsbtSighashType::from(TapSighashType::Default),
+        _ => return Err("Only P2WPKH and P2TR addresses can fund a PSBT etching".into()),
+    };
+    let mut psbt = PartiallySignedTransaction::from_unsigned_tx(commit_tx.clone())
+        .map_err(|e| e.to_string())?;
+    for (input, utxo) in psbt.inputs.iter_mut().zip(utxos) {
+        input.witness_utxo = Some(TxOut {
+            value: utxo.value,
+            script_pubkey: funding_address.script_pubkey(),
+        });
+        input.sighash_type = Some(sighash_type);
+    }
+    Ok(psbt)
+}
+
+/// Extracts the commit transaction from a PSBT built by [`funding_psbt`] and
+/// signed by the owner of `funding_address`. Inputs may either be finalized
+/// already or only carry their signature, and every signature is checked.
+pub fn finalize_funding_psbt(
+    psbt: PartiallySignedTransaction,
+    funding_address: &Address,
+) -> Result<Transaction, String> {
+    let script_pubkey = funding_address.script_pubkey();
+    let prevouts = psbt
+        .inputs
+        .iter()
+        .map(|input| {
+            input
+                .witness_utxo
+                .clone()
+                .filter(|utxo| utxo.script_pubkey == script_pubkey)
+                .ok_or("Input doesn't spend the funding address")
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut witnesses = Vec::with_capacity(psbt.inputs.len());
+    for input in psbt.inputs.iter() {
+        let witness = match (&input.final_script_witness, funding_address.address_type()) {
+            (Some(witness), _) => witness.clone(),
+            (None, Some(AddressType::P2wpkh)) => {
+                let (public_key, signature) = input
+                    .partial_sigs
+                    .iter()
+                    .next()
+                    .ok_or("Input isn't signed")?;
+                Witness::from_slice(&[signature.to_vec(), public_key.to_bytes()])
+            }
+            (None, Some(AddressType::P2tr)) => {
+                let signature = input.tap_key_sig.ok_or("Input isn't signed")?;
+                Witness::from_slice(&[signature.to_vec()])
+            }
+            _ => return Err("Only P2WPKH and P2TR addresses can fund a PSBT etching".into()),
+        };
+        witnesses.push(witness);
+    }
+    let mut commit_tx = psbt.unsigned_tx;
+    let secp = Secp256k1::verification_only();
+    let mut cache = SighashCache::new(&commit_tx);
+    for (index, witness) in witnesses.iter().enumerate() {
+        match funding_address.address_type() {
+            Some(AddressType::P2wpkh) => {
+                let (Some(signature), Some(public_key), 2) =
+                    (witness.nth(0), witness.nth(1), witness.len())
+                else {
+                    return Err("Malformed P2WPKH witness".into());
+                };
+                let signature = ecdsa::Signature::from_slice(signature)
+                    .map_err(|e| format!("Invalid signature: {}", e))?;
+                let public_key = PublicKey::from_slice(public_key)
+                    .map_err(|e| format!("Invalid public key: {}", e))?;
+                let wpubkey_hash = public_key
+                    .wpubkey_hash()
+                    .ok_or("Public key must be compressed")?;
+                if ScriptBuf::new_v0_p2wpkh(&wpubkey_hash) != script_pubkey {
+                    return Err("Public key doesn't match the funding address".into());
+                }
+                let sighash = cache
+                    .segwit_signature_hash(
+                        index,
+                        &ScriptBuf::new_p2pkh(&public_key.pubkey_hash()),
+                        prevouts[index].value,
+                        signature.hash_ty,
+                    )
+                    .map_err(|e| e.to_string())?;
+                secp.verify_ecdsa(
+                    &Message::from_slice(sighash.as_ref()).unwrap(),
+                    &signature.sig,
+                    &public_key.inner,
+                )
+                .map_err(|_| format!("Invalid signature for input {}", index))?;
+            }
+            _ => {
+                let (Some(signature), 1) = (witness.nth(0), witness.len()) else {
+                    return Err("Malformed P2TR key path witness".into());
+                };
+                let signature = taproot::Signature::from_slice(signature)
+                    .map_err(|e| format!("Invalid signature: {}", e))?;
+                let output_key = XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..])
+                    .map_err(|e| e.to_string())?;
+                let sighash = cache
+                    .taproot_key_spend_signature_hash(
+                        index,
+                        &Prevouts::All(&prevouts),
+                        signature.hash_ty,
+                    )
+                    .map_err(|e| e.to_string())?;
+                secp.verify_schnorr(
+                    &signature.sig,
+                    &Message::from_slice(sighash.as_ref()).unwrap(),
+                    &output_key,
+                )
+                .map_err(|_| format!("Invalid signature for input {}", index))?;
+            }
+        }
+    }
+    for (input, witness) in commit_tx.input.iter_mut().zip(witnesses) {
+        input.witness = witness;
+    }
+    Ok(commit_tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        hashes::Hash,
+        key::TapTweak,
+        secp256k1::{KeyPair, SecretKey},
+        Network, OutPoint,
+    };
+
+    use super::*;
+    use crate::{fund_commit_transaction, prepare_reveal, EtchingArgs};
+
+    fn secret_key() -> SecretKey {
+        SecretKey::from_slice(&[3; 32]).unwrap()
+    }
+
+    fn commit(funding_address: &Address) -> (Transaction, Vec<Utxo>) {
+        let args = EtchingArgs {
+            divisibility: 0,
+            symbol: 'E' as u32,
+            rune: "ETCHERTESTRUNE".to_string(),
+            amount: 1,
+            cap: 1_000,
+            turbo: false,
+            premine: 0,
+            height: None,
+            offset: Some((0, 100)),
+            fee_rate: Some(5),
+        };
+        let schnorr_public_key =
+            bitcoin::secp256k1::PublicKey::from_secret_key(&Secp256k1::new(), &secret_key());
+        let plan = prepare_reveal(
+            &schnorr_public_key.serialize(),
+            funding_address,
+            &args,
+            Network::Regtest,
+            10_000,
+            10,
+        )
+        .unwrap();
+        let utxos = vec![
+            Utxo {
+                outpoint: OutPoint::new(bitcoin::Txid::hash(b"funding"), 0),
+                value: 30_000,
+            },
+            Utxo {
+                outpoint: OutPoint::new(bitcoin::Txid::hash(b"funding"), 1),
+                value: 30_000,
+            },
+        ];
+        let commit_tx = fund_commit_transaction(&utxos, funding_address, &[plan]).unwrap();
+        (commit_tx, utxos)
+    }
+
+    #[test]
+    fn finalizes_a_p2wpkh_funded_commit() {
+        let secp = Secp256k1::new();
+        let public_key = PublicKey::new(secret_key().public_key(&secp));
+        let address = Address::p2wpkh(&public_key, Network::Regtest).unwrap();
+        let (commit_tx, utxos) = commit(&address);
+        let mut psbt = funding_psbt(&commit_tx, &utxos, &address).unwrap();
+        let mut cache = SighashCache::new(&psbt.unsigned_tx);
+        let signatures: Vec<_> = utxos
+            .iter()
+            .enumerate()
+            .map(|(index, utxo)| {
+                let sighash = cache
+                    .segwit_signature_hash(
+                        index,
+                        &ScriptBuf::new_p2pkh(&public_key.pubkey_hash()),
+                        utxo.value,
+                        SIG_HASH_TYPE,
+                    )
+                    .unwrap();
+                ecdsa::Signature::sighash_all(secp.sign_ecdsa(
+                    &Message::from_slice(sighash.as_ref()).unwrap(),
+                    &secret_key(),
+                ))
+            })
+            .collect();
+        for (input, signature) in psbt.inputs.iter_mut().zip(signatures) {
+            input.partial_sigs.insert(public_key, signature);
+        }
+        let mut tampered = psbt.clone();
+        let signed = finalize_funding_psbt(psbt, &address).unwrap();
+        assert_eq!(signed.txid(), commit_tx.txid());
+        assert!(signed.input.iter().all(|input| input.witness.len() == 2));
+
+        tampered.unsigned_tx.output[0].value -= 1;
+        assert!(finalize_funding_psbt(tampered, &address).is_err());
+    }
+
+    #[test]
+    fn finalizes_a_p2tr_funded_commit() {
+        let secp = Secp256k1::new();
+        let keypair = KeyPair::from_secret_key(&secp, &secret_key());
+        let address = Address::p2tr(&secp, keypair.x_only_public_key().0, None, Network::Regtest);
+        let (commit_tx, utxos) = commit(&address);
+        let mut psbt = funding_psbt(&commit_tx, &utxos, &address).unwrap();
+        let prevouts: Vec<_> = psbt
+            .inputs
+            .iter()
+            .map(|input| input.witness_utxo.clone().unwrap())
+            .collect();
+        let tweaked = keypair.tap_tweak(&secp, None).to_inner();
+        let mut cache = SighashCache::new(&psbt.unsigned_tx);
+        let signatures: Vec<_> = (0..prevouts.len())
+            .map(|index| {
+                let sighash = cache
+                    .taproot_key_spend_signature_hash(
+                        index,
+                        &Prevouts::All(&prevouts),
+                        TapSighashType::Default,
+                    )
+                    .unwrap();
+                taproot::Signature {
+                    sig: secp.sign_schnorr_no_aux_rand(
+                        &Message::from_slice(sighash.as_ref()).unwrap(),
+                        &tweaked,
+                    ),
+                    hash_ty: TapSighashType::Default,
+                }
+            })
+            .collect();
+        for (input, signature) in psbt.inputs.iter_mut().zip(signatures) {
+            input.tap_key_sig = Some(signature);
+        }
+        let signed = finalize_funding_psbt(psbt, &address).unwrap();
+        assert_eq!(signed.txid(), commit_tx.txid());
+    }
+
+    #[test]
+    fn rejects_unsupported_funding_addresses() {
+        let secp = Secp256k1::new();
+        let public_key = PublicKey::new(secret_key().public_key(&secp));
+        let address = Address::p2pkh(&public_key, Network::Regtest);
+        let (commit_tx, utxos) = commit(&address);
+        assert!(funding_psbt(&commit_tx, &utxos, &address).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+//! Rune names: their spacers, their numeric value and when they unlock.
+//!
+//! Names shorter than thirteen letters unlock step by step during the
+//! halving period after the runes activation, see
+//! [`Rune::minimum_at_height`]. Reserved names can't be etched at all.
+
+use std::str::FromStr;
+
+use bitcoin::{blockdata::constants::SUBSIDY_HALVING_INTERVAL, Network};
+use candid::CandidType;
+use ordinals::{Rune, SpacedRune};
+use serde::{Deserialize, Serialize};
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RuneName {
+    /// The name with spacers written as `•`.
+    pub spaced_rune: String,
+    /// The name without spacers.
+    pub rune: String,
+    pub value: u128,
+    /// Bit `i` is set if there is a spacer after the letter at index `i`.
+    pub spacers: u32,
+    pub reserved: bool,
+    /// First block height the name can be etched in, `None` if reserved.
+    pub unlock_height: Option<u32>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RuneMinimum {
+    pub height: u32,
+    /// The lowest name that can be etched in a block at `height`.
+    pub rune: String,
+    /// Length of the shortest names that can be etched at `height`.
+    pub length: u32,
+}
+
+/// Parses `name` in upper or lower case, with `•` or `.` as spacers, and
+/// reports what makes it invalid otherwise.
+pub fn parse_rune_name(name: &str) -> Result<SpacedRune, String> {
+    SpacedRune::from_str(&name.trim().to_ascii_uppercase())
+        .map_err(|e| format!("Invalid rune name: {}", e))
+}
+
+pub fn rune_name(network: Network, name: &str) -> Result<RuneName, String> {
+    let spaced_rune = parse_rune_name(name)?;
+    Ok(RuneName {
+        spaced_rune: spaced_rune.to_string(),
+        rune: spaced_rune.rune.to_string(),
+        value: spaced_rune.rune.n(),
+        spacers: spaced_rune.spacers,
+        reserved: spaced_rune.rune.is_reserved(),
+        unlock_height: unlock_height(network, spaced_rune.rune),
+    })
+}
+
+/// The name of the rune with the numeric `value`, every value has one.
+pub fn rune_from_value(value: u128) -> String {
+    Rune(value).to_string()
+}
+
+pub fn minimum_at_height(network: Network, height: u32) -> RuneMinimum {
+    let rune = Rune::minimum_at_height(network, ordinals::Height(height)).to_string();
+    RuneMinimum {
+        height,
+        length: rune.len() as u32,
+        rune,
+    }
+}
+
+/// First block height `rune` can be etched in, found by bisecting the
+/// minimum, which only ever goes down.
+pub fn unlock_height(network: Network, rune: Rune) -> Option<u32> {
+    if rune.is_reserved() {
+        return None;
+    }
+    let is_unlocked =
+        |height: u32| Rune::minimum_at_height(network, ordinals::Height(height)) <= rune;
+    // every name is unlocked a halving period after the first rune height
+    let (mut low, mut high) = (
+        0,
+        Rune::first_rune_height(network) + SUBSIDY_HALVING_INTERVAL,
+    );
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if is_unlocked(middle) {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+    Some(low)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_spacers_and_case() {
+        let name = rune_name(Network::Bitcoin, "etcher.test•rune").unwrap();
+        assert_eq!(name.spaced_rune, "ETCHER•TEST•RUNE");
+        assert_eq!(name.rune, "ETCHERTESTRUNE");
+        assert_eq!(name.spacers, 0b1000100000);
+        assert_eq!(rune_from_value(name.value), "ETCHERTESTRUNE");
+        assert!(!name.reserved);
+        // fourteen letters were etchable from the start
+        assert_eq!(name.unlock_height, Some(0));
+
+        for (name, error) in [
+            ("•RUNE", "leading spacer"),
+            ("RUNE•", "trailing spacer"),
+            ("RU••NE", "double spacer"),
+            ("RU NE", "invalid character ` `"),
+        ] {
+            assert_eq!(
+                parse_rune_name(name),
+                Err(format!("Invalid rune name: {}", error))
+            );
+        }
+    }
+
+    #[test]
+    fn finds_the_height_names_unlock_at() {
+        assert_eq!(rune_from_value(0), "A");
+        assert_eq!(rune_from_value(26), "AA");
+
+        let minimum = minimum_at_height(Network::Bitcoin, 840_000);
+        assert_eq!(minimum.rune, "ZZYZXBRKWXVA");
+        assert_eq!(minimum.length, 12);
+        assert_eq!(minimum_at_height(Network::Bitcoin, 1_050_000).length, 1);
+
+        let rune = Rune::from_str("ZZZZZZZZZZZ").unwrap();
+        let height = unlock_height(Network::Bitcoin, rune).unwrap();
+        assert!(Rune::minimum_at_height(Network::Bitcoin, ordinals::Height(height)) <= rune);
+        assert!(Rune::minimum_at_height(Network::Bitcoin, ordinals::Height(height - 1)) > rune);
+        assert!((857_500..860_000).contains(&height));
+        assert_eq!(unlock_height(Network::Bitcoin, Rune(0)), Some(1_049_999));
+
+        let reserved = rune_name(Network::Bitcoin, "AAAAAAAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        assert!(reserved.reserved);
+        assert_eq!(reserved.unlock_height, None);
+    }
+}
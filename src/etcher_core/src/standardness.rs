@@ -0,0 +1,530 @@
+//! Checks transactions against the relay policy of Bitcoin Core's default
+//! configuration, so that nothing gets signed or broadcast that nodes won't
+//! relay.
+
+use std::fmt;
+
+use bitcoin::{
+    opcodes::all::OP_PUSHNUM_16, script::Instruction, taproot::TAPROOT_ANNEX_PREFIX, FeeRate,
+    Script, Transaction, TxOut,
+};
+
+/// Maximum weight of a standard transaction.
+pub const MAX_STANDARD_TX_WEIGHT: u64 = 400_000;
+/// Minimum size of a standard transaction without its witness.
+pub const MIN_STANDARD_TX_NONWITNESS_SIZE: usize = 65;
+/// Fee rate below which transactions aren't relayed.
+pub const MIN_RELAY_FEE_RATE: FeeRate = FeeRate::from_sat_per_vb_unchecked(1);
+/// Maximum size of an OP_RETURN output script.
+pub const MAX_OP_RETURN_RELAY: usize = 83;
+/// Maximum size of a standard script sig.
+pub const MAX_STANDARD_SCRIPTSIG_SIZE: usize = 1_650;
+/// Maximum size of a witness script spent through P2WSH.
+pub const MAX_STANDARD_P2WSH_SCRIPT_SIZE: usize = 3_600;
+/// Maximum number of stack items of a P2WSH spend, besides the script.
+pub const MAX_STANDARD_P2WSH_STACK_ITEMS: usize = 100;
+/// Maximum size of a stack item of a P2WSH spend, besides the script.
+pub const MAX_STANDARD_P2WSH_STACK_ITEM_SIZE: usize = 80;
+/// Maximum size of a stack item of a tapscript spend, besides the script and
+/// the control block.
+pub const MAX_STANDARD_TAPSCRIPT_STACK_ITEM_SIZE: usize = 80;
+
+/// A way in which a transaction breaks relay policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    Version(i32),
+    Weight {
+        weight: u64,
+        max: u64,
+    },
+    TooSmall {
+        size: usize,
+    },
+    /// The number of previous outputs doesn't match the number of inputs.
+    PrevoutCount {
+        expected: usize,
+        got: usize,
+    },
+    /// The outputs are worth more than the spent previous outputs.
+    NegativeFee {
+        inputs: u64,
+        outputs: u64,
+    },
+    FeeBelowMinRelay {
+        fee: u64,
+        required: u64,
+    },
+    NonStandardOutput {
+        vout: usize,
+    },
+    Dust {
+        vout: usize,
+        value: u64,
+        threshold: u64,
+    },
+    OpReturnSize {
+        vout: usize,
+        size: usize,
+        max: usize,
+    },
+    /// More than one OP_RETURN output.
+    OpReturnCount(usize),
+    ScriptSigSize {
+        input: usize,
+        size: usize,
+    },
+    ScriptSigNotPushOnly {
+        input: usize,
+    },
+    WitnessScriptSize {
+        input: usize,
+        size: usize,
+    },
+    WitnessStackItems {
+        input: usize,
+        count: usize,
+    },
+    WitnessItemSize {
+        input: usize,
+        size: usize,
+        max: usize,
+    },
+    Annex {
+        input: usize,
+    },
+    /// The transaction has a lock time, but every input disables it.
+    LockTimeDisabled,
+    /// The input has a relative lock time, which only version 2 transactions
+    /// enforce.
+    RelativeLockTimeIgnored {
+        input: usize,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Version(version) => write!(f, "Version {} isn't standard", version),
+            Self::Weight { weight, max } => {
+                write!(f, "Weight of {} exceeds the maximum of {}", weight, max)
+            }
+            Self::TooSmall { size } => {
+                write!(f, "Size without witness of {} bytes is too small", size)
+            }
+            Self::PrevoutCount { expected, got } => {
+                write!(f, "Expected {} previous outputs, got {}", expected, got)
+            }
+            Self::NegativeFee { inputs, outputs } => {
+                write!(f, "Outputs of {} exceed the inputs of {}", outputs, inputs)
+            }
+            Self::FeeBelowMinRelay { fee, required } => {
+                write!(
+                    f,
+                    "Fee of {} is below the minimum relay fee of {}",
+                    fee, required
+                )
+            }
+            Self::NonStandardOutput { vout } => {
+                write!(f, "Output {} has a non-standard script", vout)
+            }
+            Self::Dust {
+                vout,
+                value,
+                threshold,
+            } => write!(
+                f,
+                "Output {} of {} is below the dust threshold of {}",
+                vout, value, threshold
+            ),
+            Self::OpReturnSize { vout, size, max } => write!(
+                f,
+                "OP_RETURN output {} of {} bytes exceeds {} bytes",
+                vout, size, max
+            ),
+            Self::OpReturnCount(count) => {
+                write!(f, "{} OP_RETURN outputs, at most one is relayed", count)
+            }
+            Self::ScriptSigSize { input, size } => {
+                write!(f, "Script sig of input {} has {} bytes", input, size)
+            }
+            Self::ScriptSigNotPushOnly { input } => {
+                write!(f, "Script sig of input {} isn't push only", input)
+            }
+            Self::WitnessScriptSize { input, size } => {
+                write!(f, "Witness script of input {} has {} bytes", input, size)
+            }
+            Self::WitnessStackItems { input, count } => {
+                write!(f, "Witness of input {} has {} stack items", input, count)
+            }
+            Self::WitnessItemSize { input, size, max } => write!(
+                f,
+                "Witness item of input {} has {} bytes, at most {} are relayed",
+                input, size, max
+            ),
+            Self::Annex { input } => write!(f, "Witness of input {} has an annex", input),
+            Self::LockTimeDisabled => {
+                write!(f, "Lock time is set but every input disables it")
+            }
+            Self::RelativeLockTimeIgnored { input } => write!(
+                f,
+                "Relative lock time of input {} needs transaction version 2",
+                input
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Violation {}
+
+/// Checks `tx`, spending `prevouts` in input order, against relay policy and
+/// returns every violation found. Witnesses and script sigs are checked as
+/// they are, so an unsigned transaction has to carry placeholders of the
+/// size of its signatures for the weight and the fee to be meaningful.
+pub fn check_standardness(tx: &Transaction, prevouts: &[TxOut]) -> Result<(), Vec<Violation>> {
+    let mut violations = vec![];
+    if !(1..=2).contains(&tx.version) {
+        violations.push(Violation::Version(tx.version));
+    }
+    let weight = tx.weight().to_wu();
+    if weight > MAX_STANDARD_TX_WEIGHT {
+        violations.push(Violation::Weight {
+            weight,
+            max: MAX_STANDARD_TX_WEIGHT,
+        });
+    }
+    if tx.strippedsize() < MIN_STANDARD_TX_NONWITNESS_SIZE {
+        violations.push(Violation::TooSmall {
+            size: tx.strippedsize(),
+        });
+    }
+    check_fee(tx, prevouts, &mut violations);
+    check_outputs(tx, &mut violations);
+    for (index, input) in tx.input.iter().enumerate() {
+        if input.script_sig.len() > MAX_STANDARD_SCRIPTSIG_SIZE {
+            violations.push(Violation::ScriptSigSize {
+                input: index,
+                size: input.script_sig.len(),
+            });
+        }
+        if !is_push_only(&input.script_sig) {
+            violations.push(Violation::ScriptSigNotPushOnly { input: index });
+        }
+        if let Some(prevout) = prevouts.get(index) {
+            check_witness(index, &input.witness.to_vec(), prevout, &mut violations);
+        }
+    }
+    check_lock_times(tx, &mut violations);
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+fn check_fee(tx: &Transaction, prevouts: &[TxOut], violations: &mut Vec<Violation>) {
+    if prevouts.len() != tx.input.len() {
+        violations.push(Violation::PrevoutCount {
+            expected: tx.input.len(),
+            got: prevouts.len(),
+        });
+        return;
+    }
+    let inputs: u64 = prevouts.iter().map(|prevout| prevout.value).sum();
+    let outputs: u64 = tx.output.iter().map(|output| output.value).sum();
+    let Some(fee) = inputs.checked_sub(outputs) else {
+        violations.push(Violation::NegativeFee { inputs, outputs });
+        return;
+    };
+    let required = MIN_RELAY_FEE_RATE.to_sat_per_vb_ceil() * tx.vsize() as u64;
+    if fee < required {
+        violations.push(Violation::FeeBelowMinRelay { fee, required });
+    }
+}
+
+fn check_outputs(tx: &Transaction, violations: &mut Vec<Violation>) {
+    let mut op_returns = 0;
+    for (vout, output) in tx.output.iter().enumerate() {
+        let script = &output.script_pubkey;
+        if script.is_op_return() {
+            op_returns += 1;
+            if script.len() > MAX_OP_RETURN_RELAY {
+                violations.push(Violation::OpReturnSize {
+                    vout,
+                    size: script.len(),
+                    max: MAX_OP_RETURN_RELAY,
+                });
+            }
+            continue;
+        }
+        if !(script.is_p2pkh() || script.is_p2sh() || script.is_witness_program()) {
+            violations.push(Violation::NonStandardOutput { vout });
+        }
+        let threshold = script.dust_value();
+        if output.value < threshold.to_sat() {
+            violations.push(Violation::Dust {
+                vout,
+                value: output.value,
+                threshold: threshold.to_sat(),
+            });
+        }
+    }
+    if op_returns > 1 {
+        violations.push(Violation::OpReturnCount(op_returns));
+    }
+}
+
+fn check_witness(
+    input: usize,
+    witness: &[Vec<u8>],
+    prevout: &TxOut,
+    violations: &mut Vec<Violation>,
+) {
+    let script = &prevout.script_pubkey;
+    if script.is_v0_p2wsh() {
+        let Some((witness_script, stack)) = witness.split_last() else {
+            return;
+        };
+        if witness_script.len() > MAX_STANDARD_P2WSH_SCRIPT_SIZE {
+            violations.push(Violation::WitnessScriptSize {
+                input,
+                size: witness_script.len(),
+            });
+        }
+        if stack.len() > MAX_STANDARD_P2WSH_STACK_ITEMS {
+            violations.push(Violation::WitnessStackItems {
+                input,
+                count: stack.len(),
+            });
+        }
+        check_item_sizes(input, stack, MAX_STANDARD_P2WSH_STACK_ITEM_SIZE, violations);
+    } else if script.is_v1_p2tr() {
+        let mut stack = witness;
+        if stack.len() >= 2
+            && stack.last().and_then(|item| item.first()) == Some(&TAPROOT_ANNEX_PREFIX)
+        {
+            violations.push(Violation::Annex { input });
+            stack = &stack[..stack.len() - 1];
+        }
+        // a script path spend ends with the script and the control block
+        if stack.len() >= 2 {
+            check_item_sizes(
+                input,
+                &stack[..stack.len() - 2],
+                MAX_STANDARD_TAPSCRIPT_STACK_ITEM_SIZE,
+                violations,
+            );
+        }
+    }
+}
+
+fn check_item_sizes(input: usize, stack: &[Vec<u8>], max: usize, violations: &mut Vec<Violation>) {
+    for item in stack {
+        if item.len() > max {
+            violations.push(Violation::WitnessItemSize {
+                input,
+                size: item.len(),
+                max,
+            });
+        }
+    }
+}
+
+fn check_lock_times(tx: &Transaction, violations: &mut Vec<Violation>) {
+    if tx.lock_time.to_consensus_u32() != 0 && !tx.is_lock_time_enabled() {
+        violations.push(Violation::LockTimeDisabled);
+    }
+    if tx.version < 2 {
+        for (index, input) in tx.input.iter().enumerate() {
+            if input.sequence.is_relative_lock_time() {
+                violations.push(Violation::RelativeLockTimeIgnored { input: index });
+            }
+        }
+    }
+}
+
+fn is_push_only(script: &Script) -> bool {
+    script.instructions().all(|instruction| match instruction {
+        Ok(Instruction::PushBytes(_)) => true,
+        Ok(Instruction::Op(op)) => op.to_u8() <= OP_PUSHNUM_16.to_u8(),
+        Err(_) => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        absolute::LockTime,
+        hashes::Hash,
+        opcodes::all::{OP_CHECKSIG, OP_DUP, OP_RETURN},
+        script::Builder,
+        OutPoint, ScriptBuf, Sequence, TxIn, Txid, WPubkeyHash, Witness,
+    };
+
+    use super::*;
+
+    fn p2wpkh() -> ScriptBuf {
+        ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::all_zeros())
+    }
+
+    fn op_return(size: usize) -> ScriptBuf {
+        let mut script = vec![OP_RETURN.to_u8(), 0x4c, size as u8 - 3];
+        script.resize(size, 0);
+        ScriptBuf::from(script)
+    }
+
+    fn spend(outputs: Vec<TxOut>) -> (Transaction, Vec<TxOut>) {
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), 0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::from_slice(&[&[0; 72][..], &[0; 33][..]]),
+            }],
+            output: outputs,
+        };
+        let prevouts = vec![TxOut {
+            value: 100_000,
+            script_pubkey: p2wpkh(),
+        }];
+        (tx, prevouts)
+    }
+
+    #[test]
+    fn accepts_a_standard_spend() {
+        let (tx, prevouts) = spend(vec![
+            TxOut {
+                value: 90_000,
+                script_pubkey: p2wpkh(),
+            },
+            TxOut {
+                value: 0,
+                script_pubkey: op_return(MAX_OP_RETURN_RELAY),
+            },
+        ]);
+        assert_eq!(check_standardness(&tx, &prevouts), Ok(()));
+    }
+
+    #[test]
+    fn reports_every_output_violation() {
+        let (tx, prevouts) = spend(vec![
+            TxOut {
+                value: 293,
+                script_pubkey: p2wpkh(),
+            },
+            TxOut {
+                value: 0,
+                script_pubkey: op_return(MAX_OP_RETURN_RELAY + 1),
+            },
+            TxOut {
+                value: 0,
+                script_pubkey: op_return(10),
+            },
+            TxOut {
+                value: 1_000,
+                script_pubkey: Builder::new().push_opcode(OP_CHECKSIG).into_script(),
+            },
+        ]);
+        assert_eq!(
+            check_standardness(&tx, &prevouts),
+            Err(vec![
+                Violation::Dust {
+                    vout: 0,
+                    value: 293,
+                    threshold: 294
+                },
+                Violation::OpReturnSize {
+                    vout: 1,
+                    size: 84,
+                    max: 83
+                },
+                Violation::NonStandardOutput { vout: 3 },
+                Violation::OpReturnCount(2),
+            ])
+        );
+    }
+
+    #[test]
+    fn checks_the_fee_against_the_spent_outputs() {
+        let output = |value| {
+            vec![TxOut {
+                value,
+                script_pubkey: p2wpkh(),
+            }]
+        };
+        let (tx, prevouts) = spend(output(100_001));
+        assert_eq!(
+            check_standardness(&tx, &prevouts),
+            Err(vec![Violation::NegativeFee {
+                inputs: 100_000,
+                outputs: 100_001
+            }])
+        );
+        let (tx, prevouts) = spend(output(99_950));
+        assert_eq!(
+            check_standardness(&tx, &prevouts),
+            Err(vec![Violation::FeeBelowMinRelay {
+                fee: 50,
+                required: tx.vsize() as u64
+            }])
+        );
+        assert_eq!(
+            check_standardness(&tx, &[]),
+            Err(vec![Violation::PrevoutCount {
+                expected: 1,
+                got: 0
+            }])
+        );
+    }
+
+    #[test]
+    fn checks_inputs_and_lock_times() {
+        let (mut tx, mut prevouts) = spend(vec![TxOut {
+            value: 90_000,
+            script_pubkey: p2wpkh(),
+        }]);
+        tx.version = 1;
+        tx.lock_time = LockTime::from_height(100).unwrap();
+        tx.input[0].sequence = Sequence::from_height(5);
+        tx.input.push(TxIn {
+            previous_output: OutPoint::new(Txid::all_zeros(), 1),
+            script_sig: Builder::new().push_opcode(OP_DUP).into_script(),
+            sequence: Sequence::MAX,
+            witness: Witness::from_slice(&[vec![0; 81], vec![0; 40], vec![0; 33], vec![0x50]]),
+        });
+        prevouts.push(TxOut {
+            value: 1_000,
+            script_pubkey: ScriptBuf::new_v1_p2tr_tweaked(
+                bitcoin::key::TweakedPublicKey::dangerous_assume_tweaked(
+                    bitcoin::secp256k1::SecretKey::from_slice(&[2; 32])
+                        .unwrap()
+                        .x_only_public_key(&bitcoin::secp256k1::Secp256k1::new())
+                        .0,
+                ),
+            ),
+        });
+        assert_eq!(
+            check_standardness(&tx, &prevouts),
+            Err(vec![
+                Violation::ScriptSigNotPushOnly { input: 1 },
+                Violation::Annex { input: 1 },
+                Violation::WitnessItemSize {
+                    input: 1,
+                    size: 81,
+                    max: 80
+                },
+                Violation::RelativeLockTimeIgnored { input: 0 },
+            ])
+        );
+
+        tx.input.truncate(1);
+        prevouts.truncate(1);
+        tx.input[0].sequence = Sequence::MAX;
+        tx.version = 3;
+        assert_eq!(
+            check_standardness(&tx, &prevouts),
+            Err(vec![Violation::Version(3), Violation::LockTimeDisabled])
+        );
+    }
+}
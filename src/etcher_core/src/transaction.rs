@@ -0,0 +1,619 @@
+use std::fmt;
+
+use bitcoin::{
+    absolute::LockTime,
+    hashes::{sha256, Hash},
+    script::PushBytes,
+    secp256k1::{constants::SCHNORR_SIGNATURE_SIZE, ecdsa, schnorr, Message, PublicKey, Secp256k1},
+    sighash::{EcdsaSighashType, Prevouts, SighashCache, TapSighashType},
+    taproot::{LeafVersion, Signature, TapLeafHash},
+    Address, AddressType, Amount, FeeRate, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut,
+    Witness,
+};
+use ordinals::{Artifact, Runestone};
+
+use crate::RevealPlan;
+
+pub const SIG_HASH_TYPE: EcdsaSighashType = EcdsaSighashType::All;
+
+#[derive(Debug, Clone)]
+pub struct Utxo {
+    pub outpoint: OutPoint,
+    pub value: u64,
+}
+
+/// A commit transaction whose inputs still have to be signed.
+#[derive(Debug, Clone)]
+pub struct UnsignedCommit {
+    pub transaction: Transaction,
+    /// Legacy sighash of every input, in input order, to be signed with the
+    /// key of the P2PKH address the utxos belong to.
+    pub sighashes: Vec<[u8; 32]>,
+}
+
+/// A reveal transaction whose commit input still has to be signed.
+#[derive(Debug)]
+pub struct UnsignedReveal {
+    pub transaction: Transaction,
+    /// BIP-341 signature message, prefixed with the tagged hash prefix so
+    /// that its sha256 is the sighash.
+    pub signing_data: Vec<u8>,
+    /// The commit output spent by the reveal.
+    pub commit_output: TxOut,
+    pub(crate) plan: RevealPlan,
+}
+
+impl UnsignedReveal {
+    /// The sighash to sign with the schnorr key of the plan.
+    pub fn sighash(&self) -> [u8; 32] {
+        sha256::Hash::hash(&self.signing_data).to_byte_array()
+    }
+
+    /// Gives back the plan the reveal was built from.
+    pub fn into_plan(self) -> RevealPlan {
+        self.plan
+    }
+
+    /// The reveal transaction with a placeholder signature in its witness.
+    pub fn with_placeholder_signature(&self) -> Transaction {
+        let mut reveal_tx = self.transaction.clone();
+        let witness = &mut reveal_tx.input[0].witness;
+        witness.push([0; SCHNORR_SIGNATURE_SIZE]);
+        witness.push(&self.plan.reveal_script);
+        witness.push(self.plan.control_block.serialize());
+        reveal_tx
+    }
+}
+
+/// Why signatures couldn't be put into a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SigningError {
+    SignatureCount {
+        expected: usize,
+        got: usize,
+    },
+    InvalidPublicKey,
+    /// The signature of `input` isn't a 64 byte compact signature.
+    MalformedSignature {
+        input: usize,
+    },
+    /// The signature of `input` doesn't verify against its sighash and the
+    /// public key.
+    SignatureMismatch {
+        input: usize,
+    },
+}
+
+impl fmt::Display for SigningError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SignatureCount { expected, got } => {
+                write!(f, "Expected {} signatures, got {}", expected, got)
+            }
+            Self::InvalidPublicKey => write!(f, "Invalid public key"),
+            Self::MalformedSignature { input } => {
+                write!(f, "Malformed signature for input {}", input)
+            }
+            Self::SignatureMismatch { input } => {
+                write!(f, "Signature of input {} doesn't match its sighash", input)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SigningError {}
+
+/// Builds the commit transaction for utxos of a P2PKH address, along with the
+/// sighashes to sign. See [`fund_commit_transaction`] for how it is funded.
+pub fn build_commit_transaction(
+    utxos: &[Utxo],
+    caller_address: &Address,
+    plans: &[RevealPlan],
+) -> Result<UnsignedCommit, String> {
+    if caller_address.address_type() != Some(AddressType::P2pkh) {
+        return Err("Only utxos of P2PKH addresses can be signed by sighash".into());
+    }
+    let commit_tx = fund_commit_transaction(utxos, caller_address, plans)?;
+    let cache = SighashCache::new(&commit_tx);
+    let sighashes = (0..commit_tx.input.len())
+        .map(|index| {
+            cache
+                .legacy_signature_hash(
+                    index,
+                    &caller_address.script_pubkey(),
+                    SIG_HASH_TYPE.to_u32(),
+                )
+                .unwrap()
+                .to_byte_array()
+        })
+        .collect();
+    Ok(UnsignedCommit {
+        transaction: commit_tx,
+        sighashes,
+    })
+}
+
+/// Funds every etching from a single commit transaction spending all of
+/// `utxos`, which belong to `funding_address`, with one commit output per
+/// plan in the same order.
+///
+/// Every commit output but the last carries exactly what its reveal needs,
+/// the last one receives whatever is left of the spent utxos, which the
+/// reveal then returns to the caller.
+pub fn fund_commit_transaction(
+    utxos: &[Utxo],
+    funding_address: &Address,
+    plans: &[RevealPlan],
+) -> Result<Transaction, String> {
+    if plans.is_empty() {
+        return Err("Nothing to etch".into());
+    }
+    let fee_rate = plans.iter().map(|plan| plan.fee_rate).max().unwrap();
+    let total_spent: u64 = utxos.iter().map(|utxo| utxo.value).sum();
+
+    let input = utxos
+        .iter()
+        .map(|utxo| TxIn {
+            previous_output: utxo.outpoint,
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::new(),
+            script_sig: ScriptBuf::new(),
+        })
+        .collect::<Vec<TxIn>>();
+
+    let mut commit_tx = Transaction {
+        input,
+        output: plans
+            .iter()
+            .map(|plan| TxOut {
+                script_pubkey: plan.commit_tx_address.script_pubkey(),
+                value: plan.required_commit_value(),
+            })
+            .collect(),
+        lock_time: LockTime::ZERO,
+        version: 2,
+    };
+
+    let commit_fee = estimate_commit_fee(&commit_tx, funding_address, fee_rate)?;
+    let reserved: u64 = commit_tx.output.iter().map(|output| output.value).sum();
+    if total_spent < reserved + commit_fee.to_sat() {
+        return Err("Not enough balance".into());
+    }
+    let last = commit_tx.output.len() - 1;
+    commit_tx.output[last].value += total_spent - reserved - commit_fee.to_sat();
+    Ok(commit_tx)
+}
+
+/// Picks the largest of `utxos` until they fund the commit of `plans` and
+/// its fee, so that a commit doesn't tie up more of the funds than it needs.
+pub fn select_utxos(
+    utxos: &[Utxo],
+    funding_address: &Address,
+    plans: &[RevealPlan],
+) -> Result<Vec<Utxo>, String> {
+    let reserved: u64 = plans.iter().map(|plan| plan.required_commit_value()).sum();
+    let mut by_value = utxos.to_vec();
+    by_value.sort_by_key(|utxo| std::cmp::Reverse(utxo.value));
+    let mut selected = vec![];
+    let mut total = 0;
+    for utxo in by_value {
+        total += utxo.value;
+        selected.push(utxo);
+        if total < reserved {
+            continue;
+        }
+        match fund_commit_transaction(&selected, funding_address, plans) {
+            Ok(_) => return Ok(selected),
+            Err(e) if e == "Not enough balance" => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    fund_commit_transaction(&selected, funding_address, plans).map(|_| selected)
+}
+
+/// Puts the compact ECDSA `signatures`, one per sighash, and the SEC1
+/// encoded `public_key` into the script sigs of the commit transaction.
+/// Every signature is normalized to low-S and checked against its sighash
+/// first.
+pub fn finalize_commit_transaction(
+    unsigned: UnsignedCommit,
+    signatures: &[Vec<u8>],
+    public_key: &[u8],
+) -> Result<Transaction, SigningError> {
+    let mut commit_tx = unsigned.transaction;
+    if signatures.len() != commit_tx.input.len() {
+        return Err(SigningError::SignatureCount {
+            expected: commit_tx.input.len(),
+            got: signatures.len(),
+        });
+    }
+    let public_key =
+        PublicKey::from_slice(public_key).map_err(|_| SigningError::InvalidPublicKey)?;
+    let secp = Secp256k1::verification_only();
+    for (index, (input, signature)) in commit_tx.input.iter_mut().zip(signatures).enumerate() {
+        let mut signature = ecdsa::Signature::from_compact(signature)
+            .map_err(|_| SigningError::MalformedSignature { input: index })?;
+        signature.normalize_s();
+        secp.verify_ecdsa(
+            &Message::from_slice(&unsigned.sighashes[index]).unwrap(),
+            &signature,
+            &public_key,
+        )
+        .map_err(|_| SigningError::SignatureMismatch { input: index })?;
+        let signature = bitcoin::ecdsa::Signature {
+            sig: signature,
+            hash_ty: SIG_HASH_TYPE,
+        };
+        input.script_sig = ScriptBuf::builder()
+            .push_slice::<&PushBytes>(signature.to_vec().as_slice().try_into().unwrap())
+            .push_slice(public_key.serialize())
+            .into_script();
+        input.witness.clear();
+    }
+    Ok(commit_tx)
+}
+
+/// Estimates the fee of the commit transaction once it is signed.
+fn estimate_commit_fee(
+    commit_tx: &Transaction,
+    funding_address: &Address,
+    fee_rate: FeeRate,
+) -> Result<Amount, String> {
+    Ok(fee_rate * with_placeholder_signatures(commit_tx, funding_address)?.weight())
+}
+
+/// `tx` with every input filled with a placeholder signature and public key
+/// of the size they will have once signed by the key of `funding_address`.
+pub fn with_placeholder_signatures(
+    tx: &Transaction,
+    funding_address: &Address,
+) -> Result<Transaction, String> {
+    let mut tx = tx.clone();
+    for txin in tx.input.iter_mut() {
+        match funding_address.address_type() {
+            Some(AddressType::P2pkh) => {
+                txin.script_sig = ScriptBuf::builder()
+                    .push_slice([0; 73])
+                    .push_slice([0; 33])
+                    .into_script();
+            }
+            Some(AddressType::P2wpkh) => {
+                txin.witness = Witness::from_slice(&[&[0; 73][..], &[0; 33][..]]);
+            }
+            Some(AddressType::P2tr) => {
+                txin.witness = Witness::from_slice(&[[0; SCHNORR_SIGNATURE_SIZE]]);
+            }
+            _ => return Err("Unsupported funding address".into()),
+        }
+    }
+    Ok(tx)
+}
+
+/// Builds the reveal transaction spending output `vout` of `commit_tx`,
+/// returning what is left after the fee and the reveal outputs to
+/// `caller_address`.
+pub fn build_reveal_transaction(
+    commit_tx: &Transaction,
+    vout: u32,
+    caller_address: &Address,
+    plan: RevealPlan,
+) -> Result<UnsignedReveal, String> {
+    let commit_output = commit_tx
+        .output
+        .get(vout as usize)
+        .ok_or("Commit output doesn't exist")?
+        .clone();
+    let mut reveal_output = plan.reveal_output.clone();
+    let change = commit_output
+        .value
+        .checked_sub(plan.reveal_fee.to_sat())
+        .and_then(|value| {
+            value.checked_sub(reveal_output.iter().map(|output| output.value).sum::<u64>())
+        })
+        .ok_or("Commit output can't pay for the reveal")?;
+    reveal_output.push(TxOut {
+        script_pubkey: caller_address.script_pubkey(),
+        value: change,
+    });
+    let reveal_tx = Transaction {
+        version: 2,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: commit_tx.txid(),
+                vout,
+            },
+            witness: Witness::new(),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::from_height(Runestone::COMMIT_CONFIRMATIONS - 1),
+        }],
+        output: reveal_output,
+    };
+    for output in reveal_tx.output.iter() {
+        if output.value < output.script_pubkey.dust_value().to_sat() {
+            return Err("commit txn output would be dust".into());
+        }
+    }
+    let leaf_hash = TapLeafHash::from_script(&plan.reveal_script, LeafVersion::TapScript);
+    let signing_data = taproot_signing_data(
+        &reveal_tx,
+        0,
+        std::slice::from_ref(&commit_output),
+        Some(leaf_hash),
+        TapSighashType::Default,
+    )?;
+    Ok(UnsignedReveal {
+        transaction: reveal_tx,
+        signing_data,
+        commit_output,
+        plan,
+    })
+}
+
+/// BIP-341 signature message of input `index` of `tx`, prefixed with the
+/// tagged hash prefix so that its sha256 is the sighash. This is what the
+/// schnorr canister signs. `leaf_hash` selects a script path spend.
+pub fn taproot_signing_data(
+    tx: &Transaction,
+    index: usize,
+    prevouts: &[TxOut],
+    leaf_hash: Option<TapLeafHash>,
+    sighash_type: TapSighashType,
+) -> Result<Vec<u8>, String> {
+    let mut hashed_tag = sha256::Hash::hash(b"TapSighash").to_byte_array().to_vec();
+    let mut signing_data = hashed_tag.clone();
+    signing_data.append(&mut hashed_tag);
+    SighashCache::new(tx)
+        .taproot_encode_signing_data_to(
+            &mut signing_data,
+            index,
+            &Prevouts::All(prevouts),
+            None,
+            leaf_hash.map(|leaf_hash| (leaf_hash, 0xFFFFFFFF)),
+            sighash_type,
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(signing_data)
+}
+
+/// Puts the BIP-340 `signature` of the sighash into the witness of the reveal
+/// transaction, after checking it against the schnorr key of the plan.
+pub fn finalize_reveal_transaction(
+    unsigned: UnsignedReveal,
+    signature: &[u8],
+) -> Result<Transaction, String> {
+    let signature = schnorr::Signature::from_slice(signature)
+        .map_err(|e| format!("Invalid schnorr signature: {}", e))?;
+    // Verify the signature to be sure that signing works
+    Secp256k1::verification_only()
+        .verify_schnorr(
+            &signature,
+            &Message::from_slice(&unsigned.sighash()).unwrap(),
+            &unsigned.plan.schnorr_public_key,
+        )
+        .map_err(|_| "Schnorr signature doesn't match the sighash")?;
+    let UnsignedReveal {
+        transaction: mut reveal_tx,
+        plan,
+        ..
+    } = unsigned;
+    let witness = &mut reveal_tx.input[0].witness;
+    witness.push(
+        Signature {
+            sig: signature,
+            hash_ty: TapSighashType::Default,
+        }
+        .to_vec(),
+    );
+    witness.push(plan.reveal_script);
+    witness.push(plan.control_block.serialize());
+    if Runestone::decipher(&reveal_tx) != Some(Artifact::Runestone(plan.runestone)) {
+        return Err("Runestone mismatched".into());
+    }
+    Ok(reveal_tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        secp256k1::{KeyPair, PublicKey, SecretKey},
+        Network,
+    };
+
+    use super::*;
+    use crate::{prepare_reveal, EtchingArgs};
+
+    const FUNDS: u64 = 1_000_000;
+
+    struct Keys {
+        ecdsa: SecretKey,
+        schnorr: SecretKey,
+        address: Address,
+    }
+
+    fn keys() -> Keys {
+        let ecdsa = SecretKey::from_slice(&[1; 32]).unwrap();
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &ecdsa);
+        Keys {
+            ecdsa,
+            schnorr: SecretKey::from_slice(&[2; 32]).unwrap(),
+            address: Address::p2pkh(&bitcoin::PublicKey::new(public_key), Network::Regtest),
+        }
+    }
+
+    fn plan(keys: &Keys) -> RevealPlan {
+        let args = EtchingArgs {
+            divisibility: 0,
+            symbol: 'E' as u32,
+            rune: "ETCHERTESTRUNE".to_string(),
+            amount: 1,
+            cap: 1_000,
+            turbo: false,
+            premine: 10,
+            height: None,
+            offset: Some((0, 100)),
+            fee_rate: Some(5),
+        };
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &keys.schnorr);
+        prepare_reveal(
+            &public_key.serialize(),
+            &keys.address,
+            &args,
+            Network::Regtest,
+            10_000,
+            10,
+        )
+        .unwrap()
+    }
+
+    fn utxos(value: u64) -> Vec<Utxo> {
+        vec![Utxo {
+            outpoint: OutPoint::new(bitcoin::Txid::hash(b"funding"), 0),
+            value,
+        }]
+    }
+
+    fn signed_commit(keys: &Keys, plans: &[RevealPlan]) -> Transaction {
+        let secp = Secp256k1::new();
+        let unsigned = build_commit_transaction(&utxos(FUNDS), &keys.address, plans).unwrap();
+        let signatures: Vec<_> = unsigned
+            .sighashes
+            .iter()
+            .map(|sighash| {
+                secp.sign_ecdsa(&Message::from_slice(sighash).unwrap(), &keys.ecdsa)
+                    .serialize_compact()
+                    .to_vec()
+            })
+            .collect();
+        let public_key = PublicKey::from_secret_key(&secp, &keys.ecdsa).serialize();
+        finalize_commit_transaction(unsigned, &signatures, &public_key).unwrap()
+    }
+
+    #[test]
+    fn builds_a_signed_etching() {
+        let keys = keys();
+        let commit_tx = signed_commit(&keys, &[plan(&keys)]);
+        assert_eq!(commit_tx.input.len(), 1);
+        assert!(!commit_tx.input[0].script_sig.is_empty());
+        let commit_fee = FUNDS - commit_tx.output[0].value;
+        assert!(commit_fee > 0);
+
+        let unsigned = build_reveal_transaction(&commit_tx, 0, &keys.address, plan(&keys)).unwrap();
+        let keypair = KeyPair::from_secret_key(&Secp256k1::new(), &keys.schnorr);
+        let signature = Secp256k1::new()
+            .sign_schnorr_no_aux_rand(&Message::from_slice(&unsigned.sighash()).unwrap(), &keypair);
+        let reveal_tx = finalize_reveal_transaction(unsigned, signature.as_ref()).unwrap();
+        assert_eq!(reveal_tx.input[0].previous_output.txid, commit_tx.txid());
+        assert_eq!(reveal_tx.input[0].witness.len(), 3);
+        let paid: u64 = reveal_tx.output.iter().map(|output| output.value).sum();
+        assert!(paid < commit_tx.output[0].value);
+    }
+
+    #[test]
+    fn selects_the_largest_utxos_that_fund_the_commit() {
+        let keys = keys();
+        let plans = [plan(&keys)];
+        let utxo = |vout: u32, value: u64| Utxo {
+            outpoint: OutPoint::new(bitcoin::Txid::hash(b"funding"), vout),
+            value,
+        };
+        let needed = plans[0].required_commit_value();
+        let available = [
+            utxo(0, needed / 2),
+            utxo(1, FUNDS),
+            utxo(2, needed * 2),
+            utxo(3, 1_000),
+        ];
+        let selected = select_utxos(&available, &keys.address, &plans).unwrap();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].outpoint.vout, 1);
+
+        // none covers the commit and its fee on its own
+        let available = [
+            utxo(0, needed / 2 + 5_000),
+            utxo(1, 1_000),
+            utxo(2, needed / 2),
+        ];
+        let selected = select_utxos(&available, &keys.address, &plans).unwrap();
+        let vouts: Vec<_> = selected.iter().map(|utxo| utxo.outpoint.vout).collect();
+        assert_eq!(vouts, vec![0, 2]);
+        assert!(fund_commit_transaction(&selected, &keys.address, &plans).is_ok());
+
+        assert_eq!(
+            select_utxos(&available[1..], &keys.address, &plans).unwrap_err(),
+            "Not enough balance"
+        );
+    }
+
+    #[test]
+    fn rejects_a_reveal_signed_with_another_key() {
+        let keys = keys();
+        let commit_tx = signed_commit(&keys, &[plan(&keys)]);
+        let unsigned = build_reveal_transaction(&commit_tx, 0, &keys.address, plan(&keys)).unwrap();
+        let keypair = KeyPair::from_secret_key(&Secp256k1::new(), &keys.ecdsa);
+        let signature = Secp256k1::new()
+            .sign_schnorr_no_aux_rand(&Message::from_slice(&unsigned.sighash()).unwrap(), &keypair);
+        assert!(finalize_reveal_transaction(unsigned, signature.as_ref()).is_err());
+    }
+
+    #[test]
+    fn verifies_and_normalizes_commit_signatures() {
+        let keys = keys();
+        let secp = Secp256k1::new();
+        let public_key = PublicKey::from_secret_key(&secp, &keys.ecdsa).serialize();
+        let unsigned =
+            build_commit_transaction(&utxos(FUNDS), &keys.address, &[plan(&keys)]).unwrap();
+        let sign = |secret_key: &SecretKey| {
+            secp.sign_ecdsa(
+                &Message::from_slice(&unsigned.sighashes[0]).unwrap(),
+                secret_key,
+            )
+            .serialize_compact()
+        };
+
+        // the same signature with s negated is just as valid, but not standard
+        let mut high_s = sign(&keys.ecdsa);
+        let mut s = bitcoin::secp256k1::Scalar::from_be_bytes(high_s[32..].try_into().unwrap())
+            .map(|s| SecretKey::from_slice(&s.to_be_bytes()).unwrap())
+            .unwrap();
+        s = s.negate();
+        high_s[32..].copy_from_slice(&s.secret_bytes());
+        let commit_tx =
+            finalize_commit_transaction(unsigned.clone(), &[high_s.to_vec()], &public_key).unwrap();
+        let Some(Ok(bitcoin::script::Instruction::PushBytes(signature))) =
+            commit_tx.input[0].script_sig.instructions().next()
+        else {
+            panic!("script sig doesn't start with the signature")
+        };
+        let signature = bitcoin::ecdsa::Signature::from_slice(signature.as_bytes()).unwrap();
+        assert_eq!(signature.sig.serialize_compact(), sign(&keys.ecdsa));
+
+        let other_key = SecretKey::from_slice(&[3; 32]).unwrap();
+        assert_eq!(
+            finalize_commit_transaction(
+                unsigned.clone(),
+                &[sign(&other_key).to_vec()],
+                &public_key
+            ),
+            Err(SigningError::SignatureMismatch { input: 0 })
+        );
+        assert_eq!(
+            finalize_commit_transaction(unsigned, &[], &public_key),
+            Err(SigningError::SignatureCount {
+                expected: 1,
+                got: 0
+            })
+        );
+    }
+
+    #[test]
+    fn refuses_to_underfund_the_commit() {
+        let keys = keys();
+        let plan = plan(&keys);
+        let required = plan.required_commit_value();
+        assert_eq!(
+            build_commit_transaction(&utxos(required), &keys.address, &[plan]).unwrap_err(),
+            "Not enough balance"
+        );
+    }
+}